@@ -0,0 +1,525 @@
+use crate::conf;
+use crate::events::{Event, EventTx};
+use crate::grpc::collector_grpc::ProbeType;
+use crate::histogram::Histogram;
+use crate::structures::{Aggregate, Gilbert, PingStats, ReportMode};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::mem;
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+use tracing::debug;
+
+/// A window is closed this long after its end, so probes sent near the end
+/// can still complete. Results arriving later are dropped.
+const GRACE_SECS: u64 = 10;
+/// Correlation of consecutive model states is kept below one, where both
+/// states would last forever.
+const MAX_CORRELATION: f64 = 0.999;
+
+pub type AggregateTx = mpsc::Sender<Aggregate>;
+
+/// The part of a result window aggregates are built from.
+pub struct Sample {
+    pub id: u64,
+    pub probe_type: ProbeType,
+    pub send_at: SystemTime,
+    /// `None` when the probe was lost.
+    pub rtt: Option<Duration>,
+    /// Loss run of the target at this probe, `None` when it was not sent.
+    pub loss_run: Option<u32>,
+    /// Mode of the command, `None` follows the agent config.
+    pub report: Option<ReportMode>,
+    /// IPDV against the previous probe in micros, RFC 3393.
+    pub ipdv: Option<i64>,
+    pub jitter: Duration,
+    pub reordered: u32,
+    /// Destination, `None` for host names.
+    pub ip: Option<IpAddr>,
+}
+
+/// Loss runs of the sent probes of a window.
+#[derive(Default)]
+struct Bursts {
+    lost: u32,
+    count: u32,
+    max: u32,
+}
+
+impl Bursts {
+    fn add(&mut self, lost: bool, loss_run: u32) {
+        if lost {
+            // A run open at the end of the previous window is counted again,
+            // so a window with losses never has a mean of zero.
+            self.count += u32::from(loss_run == 1 || self.lost == 0);
+            self.lost += 1;
+            self.max = self.max.max(loss_run);
+        }
+    }
+
+    fn mean(&self) -> f64 {
+        match self.count {
+            0 => 0.0,
+            n => f64::from(self.lost) / f64::from(n),
+        }
+    }
+}
+
+/// The last two sent probes of a command, newest first, `Some(true)` when
+/// lost.
+type Recent = [Option<bool>; 2];
+
+/// Joint losses of the sent probes of a window and the one or two probes sent
+/// before each, which may belong to the previous window.
+#[derive(Default)]
+struct Moments {
+    count: u32,
+    lost: u32,
+    /// Probes following a known one, and those lost along with it.
+    pairs: u32,
+    lost_pairs: u32,
+    /// Probes following two known ones, those lost along with the earlier
+    /// one, and those lost along with both.
+    triples: u32,
+    lost_gaps: u32,
+    lost_triples: u32,
+}
+
+impl Moments {
+    fn add(&mut self, lost: bool, recent: Recent) {
+        self.count += 1;
+        self.lost += u32::from(lost);
+        let Some(last) = recent[0] else { return };
+        self.pairs += 1;
+        self.lost_pairs += u32::from(lost && last);
+        let Some(before) = recent[1] else { return };
+        self.triples += 1;
+        self.lost_gaps += u32::from(lost && before);
+        self.lost_triples += u32::from(lost && last && before);
+    }
+
+    /// Fit the Gilbert-Elliott model by its moments. With stationary bad
+    /// probability `q`, state loss difference `d` and `l = 1 - p - r`, the loss
+    /// indicator has mean `m`, autocovariance `q(1-q)d² l^n` at lag `n` and a
+    /// third central moment of `q(1-q)(1-2q)d³ l²` over consecutive probes.
+    /// Matching them to the window gives `l`, `d` and `q` in closed form.
+    /// Without positive correlation losses are independent, which is the
+    /// model with `l = 0`.
+    fn gilbert_elliott(&self) -> Gilbert {
+        if self.count == 0 {
+            return Gilbert::default();
+        }
+        let ratio = |n: u32, d: u32| f64::from(n) / f64::from(d);
+        let m = ratio(self.lost, self.count);
+        if self.triples == 0 {
+            return Gilbert::independent(m);
+        }
+        let r1 = ratio(self.lost_pairs, self.pairs);
+        let r2 = ratio(self.lost_gaps, self.triples);
+        let r3 = ratio(self.lost_triples, self.triples);
+        let (c1, c2) = (r1 - m * m, r2 - m * m);
+        if c1 <= 0.0 || c2 <= 0.0 {
+            return Gilbert::independent(m);
+        }
+
+        let l = (c2 / c1).min(MAX_CORRELATION);
+        let v = c1 / l;
+        let t3 = r3 - m * (2.0 * r1 + r2) + 2.0 * m.powi(3);
+        let u = t3 / (l * l * v);
+        let d = (u * u + 4.0 * v).sqrt();
+        let q = ((1.0 - u / d) / 2.0).clamp(0.0, 1.0);
+        let good_loss = (m - q * d).clamp(0.0, 1.0);
+        let bad_loss = (good_loss + d).clamp(0.0, 1.0);
+
+        Gilbert {
+            p: q * (1.0 - l),
+            r: (1.0 - q) * (1.0 - l),
+            good_loss,
+            bad_loss,
+            loss: (1.0 - q) * good_loss + q * bad_loss,
+        }
+    }
+}
+
+struct Window {
+    probe_type: ProbeType,
+    /// Report the window to the collector, otherwise it is only published.
+    report: bool,
+    count: u32,
+    lost: u32,
+    rtt: Histogram,
+    bursts: Bursts,
+    moments: Moments,
+    variation: PingStats,
+    ip: Option<IpAddr>,
+}
+
+/// Buckets results per command into wall clock aligned windows. Every closed
+/// window is published on the event bus, windows of commands in aggregate mode
+/// are reported as well.
+pub struct Aggregator {
+    mode: ReportMode,
+    window_secs: u64,
+    /// Keyed by `(window start, command id)`, so closed windows come first.
+    windows: BTreeMap<(u64, u64), Window>,
+    /// Carried from window to window of a command.
+    recent: HashMap<u64, Recent>,
+    tx: AggregateTx,
+    event_tx: EventTx,
+}
+
+impl Aggregator {
+    pub fn new(conf: &conf::Aggregation, tx: AggregateTx, event_tx: EventTx) -> Self {
+        Self {
+            mode: conf.mode,
+            window_secs: conf.window_secs.max(1),
+            windows: BTreeMap::new(),
+            recent: HashMap::new(),
+            tx,
+            event_tx,
+        }
+    }
+
+    fn secs(t: SystemTime) -> u64 {
+        t.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+    }
+
+    /// Windows starting before this are closed at `now`.
+    fn open_from(&self, now: SystemTime) -> u64 {
+        (Self::secs(now) + 1).saturating_sub(self.window_secs + GRACE_SECS)
+    }
+
+    /// Fold `sample` into its window. Returns true when the result has to be
+    /// reported raw as well.
+    pub fn add(&mut self, sample: Sample, now: SystemTime) -> bool {
+        let raw = sample.report.unwrap_or(self.mode) == ReportMode::Raw;
+
+        let secs = Self::secs(sample.send_at);
+        let start = secs - secs % self.window_secs;
+        if start < self.open_from(now) {
+            debug!("Drop late result of closed window, id:{}", sample.id);
+            return raw;
+        }
+
+        let window = self
+            .windows
+            .entry((start, sample.id))
+            .or_insert_with(|| Window {
+                probe_type: sample.probe_type,
+                report: !raw,
+                count: 0,
+                lost: 0,
+                rtt: Histogram::default(),
+                bursts: Bursts::default(),
+                moments: Moments::default(),
+                variation: PingStats::new(sample.id),
+                ip: sample.ip,
+            });
+        window
+            .variation
+            .add(sample.jitter, sample.ipdv, sample.reordered);
+        window.count += 1;
+        match sample.rtt {
+            Some(rtt) => window.rtt.record(rtt.as_micros() as u64),
+            None => window.lost += 1,
+        }
+        if let Some(loss_run) = sample.loss_run {
+            let lost = sample.rtt.is_none();
+            window.bursts.add(lost, loss_run);
+            let recent = self.recent.entry(sample.id).or_default();
+            window.moments.add(lost, *recent);
+            *recent = [Some(lost), recent[0]];
+        }
+        raw
+    }
+
+    /// Publish and report every window closed at `now`.
+    pub async fn flush(&mut self, now: SystemTime) {
+        let open = self.windows.split_off(&(self.open_from(now), 0));
+        let closed = mem::replace(&mut self.windows, open);
+        let ids: HashSet<_> = self.windows.keys().map(|&(_, id)| id).collect();
+        self.recent.retain(|id, _| ids.contains(id));
+
+        for ((start, id), window) in closed {
+            let quantile = |q| Duration::from_micros(window.rtt.quantile(q));
+            let aggregate = Aggregate {
+                id,
+                probe_type: window.probe_type,
+                window_start: UNIX_EPOCH + Duration::from_secs(start),
+                window: Duration::from_secs(self.window_secs),
+                count: window.count,
+                lost: window.lost,
+                rtt_min: Duration::from_micros(window.rtt.min()),
+                rtt_max: Duration::from_micros(window.rtt.max()),
+                rtt_p50: quantile(0.5),
+                rtt_p90: quantile(0.9),
+                rtt_p99: quantile(0.99),
+                rtt_p999: quantile(0.999),
+                bursts: window.bursts.count,
+                burst_mean: window.bursts.mean(),
+                burst_max: window.bursts.max,
+                gilbert: window.moments.gilbert_elliott(),
+                variation: window.variation,
+                ip: window.ip,
+                origin: None,
+            };
+            // Nobody listening is fine, events are best effort.
+            let _ = self.event_tx.send(Event::Window(aggregate.clone()));
+            if window.report {
+                self.tx.send(aggregate).await.expect("Send aggregate fail");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events;
+    use rand::rngs::SmallRng;
+    use rand::{Rng, SeedableRng};
+
+    const WINDOW_SECS: u64 = 60;
+
+    fn aggregator() -> (Aggregator, mpsc::Receiver<Aggregate>) {
+        let conf = conf::Aggregation {
+            mode: ReportMode::Aggregate,
+            window_secs: WINDOW_SECS,
+        };
+        let (tx, rx) = mpsc::channel(16);
+        (Aggregator::new(&conf, tx, events::channel()), rx)
+    }
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    fn sample(send_at: SystemTime, rtt: Option<Duration>, loss_run: Option<u32>) -> Sample {
+        Sample {
+            id: 1,
+            probe_type: ProbeType::Ping,
+            send_at,
+            rtt,
+            loss_run,
+            ipdv: None,
+            jitter: Duration::ZERO,
+            reordered: 0,
+            report: None,
+            ip: None,
+        }
+    }
+
+    /// Probes sent a second apart from `start`, `true` when lost. `run` is the
+    /// loss run of the target, as the scheduler keeps it.
+    fn feed(aggregator: &mut Aggregator, run: &mut u32, start: u64, losses: &[bool]) {
+        for (i, &lost) in losses.iter().enumerate() {
+            let loss_run = if lost {
+                *run += 1;
+                *run
+            } else {
+                mem::take(run)
+            };
+            let rtt = (!lost).then_some(Duration::from_millis(5));
+            let send_at = at(start + i as u64);
+            aggregator.add(sample(send_at, rtt, Some(loss_run)), send_at);
+        }
+    }
+
+    async fn closed(
+        aggregator: &mut Aggregator,
+        rx: &mut mpsc::Receiver<Aggregate>,
+        now: u64,
+    ) -> Vec<Aggregate> {
+        aggregator.flush(at(now)).await;
+        let mut aggregates = Vec::new();
+        while let Ok(aggregate) = rx.try_recv() {
+            aggregates.push(aggregate);
+        }
+        aggregates
+    }
+
+    /// Losses of a Gilbert-Elliott chain starting in the good state.
+    fn chain(model: Gilbert, len: usize, seed: u64) -> Vec<bool> {
+        let mut rng = SmallRng::seed_from_u64(seed);
+        let mut bad = false;
+        (0..len)
+            .map(|_| {
+                bad = if bad {
+                    !rng.gen_bool(model.r)
+                } else {
+                    rng.gen_bool(model.p)
+                };
+                rng.gen_bool(if bad { model.bad_loss } else { model.good_loss })
+            })
+            .collect()
+    }
+
+    fn fit(losses: &[bool]) -> Gilbert {
+        let mut moments = Moments::default();
+        let mut recent = Recent::default();
+        for &lost in losses {
+            moments.add(lost, recent);
+            recent = [Some(lost), recent[0]];
+        }
+        moments.gilbert_elliott()
+    }
+
+    #[tokio::test]
+    async fn count_loss_runs() {
+        let (mut aggregator, mut rx) = aggregator();
+        let mut run = 0;
+        let losses = [
+            true, true, false, true, false, false, true, true, true, false,
+        ];
+        feed(&mut aggregator, &mut run, 0, &losses);
+
+        let aggregates = closed(&mut aggregator, &mut rx, WINDOW_SECS + GRACE_SECS).await;
+        assert_eq!(aggregates.len(), 1);
+        let aggregate = &aggregates[0];
+        assert_eq!((aggregate.count, aggregate.lost), (10, 6));
+        assert_eq!(aggregate.bursts, 3);
+        assert_eq!(aggregate.burst_mean, 2.0);
+        assert_eq!(aggregate.burst_max, 3);
+    }
+
+    #[tokio::test]
+    async fn carry_loss_run_into_next_window() {
+        let (mut aggregator, mut rx) = aggregator();
+        let mut run = 0;
+        feed(&mut aggregator, &mut run, 56, &[false, false, true, true]);
+        feed(&mut aggregator, &mut run, 60, &[true, false, true, false]);
+        // The probes before the window are known to the lagged counts.
+        let moments = &aggregator.windows[&(60, 1)].moments;
+        assert_eq!((moments.pairs, moments.triples), (4, 4));
+        assert_eq!((moments.lost_pairs, moments.lost_triples), (1, 1));
+
+        let aggregates = closed(&mut aggregator, &mut rx, 2 * WINDOW_SECS + GRACE_SECS).await;
+        assert_eq!(aggregates.len(), 2);
+        let (first, second) = (&aggregates[0], &aggregates[1]);
+        assert_eq!((first.bursts, first.burst_max), (1, 2));
+        assert_eq!(first.burst_mean, 2.0);
+        // The run carried over counts again, its max covers the whole run.
+        assert_eq!((second.lost, second.bursts, second.burst_max), (2, 2, 3));
+        assert_eq!(second.burst_mean, 1.0);
+    }
+
+    #[tokio::test]
+    async fn align_windows_to_wall_clock() {
+        let (mut aggregator, mut rx) = aggregator();
+        let rtt = Some(Duration::from_millis(5));
+        for secs in [59, 60, 119, 121] {
+            aggregator.add(sample(at(secs), rtt, Some(0)), at(secs));
+        }
+
+        let aggregates = closed(&mut aggregator, &mut rx, 3 * WINDOW_SECS + GRACE_SECS).await;
+        let windows: Vec<_> = aggregates
+            .iter()
+            .map(|a| (a.window_start, a.window, a.count))
+            .collect();
+        let window = Duration::from_secs(WINDOW_SECS);
+        assert_eq!(
+            windows,
+            [
+                (at(0), window, 1),
+                (at(60), window, 2),
+                (at(120), window, 1)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn close_windows_after_grace() {
+        let (mut aggregator, mut rx) = aggregator();
+        let rtt = Some(Duration::from_millis(5));
+        aggregator.add(sample(at(10), rtt, Some(0)), at(10));
+
+        // Results of the window still count during the grace period.
+        let end = WINDOW_SECS + GRACE_SECS - 1;
+        assert!(closed(&mut aggregator, &mut rx, end).await.is_empty());
+        aggregator.add(sample(at(59), None, Some(1)), at(end));
+
+        let aggregates = closed(&mut aggregator, &mut rx, end + 1).await;
+        assert_eq!(aggregates.len(), 1);
+        assert_eq!((aggregates[0].count, aggregates[0].lost), (2, 1));
+
+        // Later ones are dropped rather than opening the window again.
+        aggregator.add(sample(at(59), rtt, Some(0)), at(end + 1));
+        assert!(aggregator.windows.is_empty());
+        assert!(closed(&mut aggregator, &mut rx, end + 100).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn carry_delay_variation() {
+        let (mut aggregator, mut rx) = aggregator();
+        let rtt = Some(Duration::from_millis(5));
+        for (secs, ipdv, reordered) in [(1, None, 0), (2, Some(-300), 1), (3, Some(500), 0)] {
+            let sample = Sample {
+                ipdv,
+                jitter: Duration::from_micros(secs * 100),
+                reordered,
+                ..sample(at(secs), rtt, Some(0))
+            };
+            aggregator.add(sample, at(secs));
+        }
+
+        let aggregates = closed(&mut aggregator, &mut rx, WINDOW_SECS + GRACE_SECS).await;
+        let variation = &aggregates[0].variation;
+        assert_eq!(variation.jitter, Duration::from_micros(300));
+        assert_eq!((variation.ipdv_min, variation.ipdv_max), (-300, 500));
+        assert_eq!((variation.ipdv_abs_sum, variation.ipdv_samples), (800, 2));
+        assert_eq!(variation.reordered, 1);
+    }
+
+    #[test]
+    fn fit_gilbert_elliott() {
+        let model = Gilbert {
+            p: 0.05,
+            r: 0.3,
+            good_loss: 0.01,
+            bad_loss: 0.7,
+            loss: 0.0,
+        };
+        let fitted = fit(&chain(model, 200_000, 1));
+        assert!((fitted.p - model.p).abs() < 0.01, "{:?}", fitted);
+        assert!((fitted.r - model.r).abs() < 0.05, "{:?}", fitted);
+        assert!(
+            (fitted.good_loss - model.good_loss).abs() < 0.01,
+            "{:?}",
+            fitted
+        );
+        assert!(
+            (fitted.bad_loss - model.bad_loss).abs() < 0.05,
+            "{:?}",
+            fitted
+        );
+        let loss = (model.r * model.good_loss + model.p * model.bad_loss) / (model.p + model.r);
+        assert!((fitted.loss - loss).abs() < 0.01, "{:?}", fitted);
+    }
+
+    #[test]
+    fn fit_simple_gilbert() {
+        let model = Gilbert {
+            p: 0.02,
+            r: 0.5,
+            good_loss: 0.0,
+            bad_loss: 1.0,
+            loss: 0.0,
+        };
+        let fitted = fit(&chain(model, 200_000, 2));
+        assert!(fitted.good_loss < 0.005, "{:?}", fitted);
+        assert!(fitted.bad_loss > 0.95, "{:?}", fitted);
+        assert!((fitted.r - model.r).abs() < 0.05, "{:?}", fitted);
+    }
+
+    #[test]
+    fn independent_without_correlation() {
+        let none = fit(&[false; 100]);
+        assert_eq!((none.p, none.r, none.loss), (0.0, 1.0, 0.0));
+        let all = fit(&[true; 100]);
+        assert_eq!((all.p, all.r, all.loss), (1.0, 0.0, 1.0));
+        // Alternating losses are negatively correlated.
+        let alternating: Vec<_> = (0..100).map(|i| i % 2 == 0).collect();
+        assert_eq!(fit(&alternating).loss, 0.5);
+        assert_eq!(Moments::default().gilbert_elliott().loss, 0.0);
+    }
+}
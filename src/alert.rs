@@ -0,0 +1,508 @@
+use super::backoff;
+use crate::conf::{self, AlertCondition, AlertRule};
+use crate::events::{Event, EventRx};
+use crate::grpc::collector_grpc::ProbeType;
+use crate::structures::{Aggregate, StateChange, TargetState};
+use anyhow::{anyhow, bail, Result};
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Method, Request, Uri};
+use std::collections::HashSet;
+use std::fmt::Write;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::task;
+use tokio::time::{self, Duration};
+use tracing::{info, warn};
+
+const QUEUE_SIZE: usize = 256;
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+const SEND_TIMES: u32 = 5;
+#[cfg(not(test))]
+const RETRY_INTERVAL_MIN: u64 = 1;
+#[cfg(not(test))]
+const RETRY_INTERVAL_MAX: u64 = 5;
+// Tests retry against a local webhook right away.
+#[cfg(test)]
+const RETRY_INTERVAL_MIN: u64 = 0;
+#[cfg(test)]
+const RETRY_INTERVAL_MAX: u64 = 0;
+
+type AlertKey = (usize, ProbeType, u64);
+
+#[derive(Debug, Clone, Copy)]
+enum Status {
+    Firing,
+    Resolved,
+}
+
+struct Notification {
+    rule: String,
+    status: Status,
+    probe_type: ProbeType,
+    id: u64,
+    target: Option<String>,
+    value: f64,
+    threshold: Option<f64>,
+    at: SystemTime,
+}
+
+impl Notification {
+    /// Same for the firing and the resolved notification of one alert, so the
+    /// receiver can dedup retried deliveries.
+    fn fingerprint(&self) -> String {
+        format!(
+            "{}/{}/{}",
+            self.rule,
+            self.probe_type.as_str_name(),
+            self.id
+        )
+    }
+
+    fn to_json(&self) -> String {
+        let status = match self.status {
+            Status::Firing => "firing",
+            Status::Resolved => "resolved",
+        };
+        let at = self.at.duration_since(UNIX_EPOCH).unwrap().as_millis();
+
+        let mut json = String::from("{");
+        let _ = write!(
+            json,
+            r#""fingerprint":{},"rule":{},"status":"{}","type":"{}","id":{},"value":{},"at":{}"#,
+            escape(&self.fingerprint()),
+            escape(&self.rule),
+            status,
+            self.probe_type.as_str_name(),
+            self.id,
+            self.value,
+            at
+        );
+        if let Some(threshold) = self.threshold {
+            let _ = write!(json, r#","threshold":{}"#, threshold);
+        }
+        if let Some(target) = &self.target {
+            let _ = write!(json, r#","target":{}"#, escape(target));
+        }
+        json.push('}');
+        json
+    }
+}
+
+/// Quote `s` as a JSON string.
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if c.is_control() => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Record whether the alert `key` fires now, returns the notification status
+/// when it changed. Repeated firing windows are not notified again.
+fn transition(firing: &mut HashSet<AlertKey>, key: AlertKey, fire: bool) -> Option<Status> {
+    match (fire, firing.contains(&key)) {
+        (true, false) => {
+            firing.insert(key);
+            Some(Status::Firing)
+        }
+        (false, true) => {
+            firing.remove(&key);
+            Some(Status::Resolved)
+        }
+        _ => None,
+    }
+}
+
+/// Evaluates the alert rules against the agent's own events, so sites which
+/// lost the collector still get alerted.
+pub struct Alerter {
+    url: Uri,
+    rules: Vec<AlertRule>,
+    firing: HashSet<AlertKey>,
+}
+
+impl Alerter {
+    /// `None` when alerting is disabled.
+    /// The client speaks plain http only, other schemes are rejected.
+    pub fn new(conf: &conf::Alert) -> Result<Option<Self>> {
+        if conf.webhook.is_empty() {
+            return Ok(None);
+        }
+
+        let url = Uri::from_str(&conf.webhook)?;
+        if url.scheme_str() != Some("http") {
+            bail!("webhook {} is not an http url", conf.webhook);
+        }
+        Ok(Some(Self {
+            url,
+            rules: conf.rules.clone(),
+            firing: HashSet::new(),
+        }))
+    }
+
+    pub async fn run(mut self, mut events: EventRx) {
+        let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+        task::spawn(Self::deliver(self.url.clone(), rx));
+
+        loop {
+            let notifications = match events.recv().await {
+                Ok(Event::Window(aggregate)) => self.on_window(&aggregate),
+                Ok(Event::State(change)) => self.on_state(&change),
+                Ok(Event::Anomaly(_) | Event::PathChange(_) | Event::Budget(_)) => continue,
+                Err(RecvError::Lagged(n)) => {
+                    warn!("Alerter lagged, drop {} events", n);
+                    continue;
+                }
+                Err(RecvError::Closed) => return,
+            };
+
+            for notification in notifications {
+                info!(
+                    "Alert {} {:?}, value {}",
+                    notification.fingerprint(),
+                    notification.status,
+                    notification.value
+                );
+                match tx.try_send(notification) {
+                    Ok(()) => {}
+                    Err(TrySendError::Full(n)) => {
+                        warn!("Alert queue full, drop {}", n.fingerprint())
+                    }
+                    Err(TrySendError::Closed(_)) => panic!("Send alert fail"),
+                }
+            }
+        }
+    }
+
+    fn matches(rule: &AlertRule, id: u64) -> bool {
+        rule.ids.is_empty() || rule.ids.contains(&id)
+    }
+
+    fn on_window(&mut self, aggregate: &Aggregate) -> Vec<Notification> {
+        let mut notifications = Vec::new();
+        for (index, rule) in self.rules.iter().enumerate() {
+            if !Self::matches(rule, aggregate.id) {
+                continue;
+            }
+
+            let (value, threshold) = match rule.condition {
+                AlertCondition::Loss { percent } if aggregate.count > 0 => {
+                    let loss = 100.0 * f64::from(aggregate.lost) / f64::from(aggregate.count);
+                    (loss, percent)
+                }
+                AlertCondition::P99 { rtt_ms } if aggregate.count > aggregate.lost => {
+                    (aggregate.rtt_p99.as_secs_f64() * 1000.0, rtt_ms as f64)
+                }
+                _ => continue,
+            };
+
+            let key = (index, aggregate.probe_type, aggregate.id);
+            let Some(status) = transition(&mut self.firing, key, value > threshold) else {
+                continue;
+            };
+            notifications.push(Notification {
+                rule: rule.name.clone(),
+                status,
+                probe_type: aggregate.probe_type,
+                id: aggregate.id,
+                target: None,
+                value,
+                threshold: Some(threshold),
+                at: aggregate.window_start + aggregate.window,
+            });
+        }
+
+        notifications
+    }
+
+    fn on_state(&mut self, change: &StateChange) -> Vec<Notification> {
+        let mut notifications = Vec::new();
+        for (index, rule) in self.rules.iter().enumerate() {
+            if !matches!(rule.condition, AlertCondition::Down) || !Self::matches(rule, change.id) {
+                continue;
+            }
+
+            let key = (index, change.probe_type, change.id);
+            let fire = change.to == TargetState::Down;
+            let Some(status) = transition(&mut self.firing, key, fire) else {
+                continue;
+            };
+            notifications.push(Notification {
+                rule: rule.name.clone(),
+                status,
+                probe_type: change.probe_type,
+                id: change.id,
+                target: Some(change.target.clone()),
+                value: f64::from(change.losses),
+                threshold: None,
+                at: change.at,
+            });
+        }
+
+        notifications
+    }
+
+    async fn deliver(url: Uri, mut rx: mpsc::Receiver<Notification>) {
+        let client = Client::new();
+        while let Some(notification) = rx.recv().await {
+            let body = notification.to_json();
+            for times in 1..=SEND_TIMES {
+                let Err(e) = Self::post(&client, &url, body.clone()).await else {
+                    break;
+                };
+
+                warn!(
+                    "Send alert {} fail {}/{}, err:{}",
+                    notification.fingerprint(),
+                    times,
+                    SEND_TIMES,
+                    e
+                );
+                if times < SEND_TIMES {
+                    backoff!(RETRY_INTERVAL_MIN, RETRY_INTERVAL_MAX);
+                }
+            }
+        }
+    }
+
+    async fn post(client: &Client<HttpConnector>, url: &Uri, body: String) -> Result<()> {
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri(url)
+            .header("content-type", "application/json")
+            .body(Body::from(body))?;
+
+        let resp = time::timeout(SEND_TIMEOUT, client.request(req))
+            .await
+            .map_err(|_| anyhow!("timeout"))??;
+        if !resp.status().is_success() {
+            return Err(anyhow!("status {}", resp.status()));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events;
+    use crate::structures::{Gilbert, PingStats};
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Response, Server, StatusCode};
+    use std::collections::HashMap;
+    use std::convert::Infallible;
+    use std::iter::Peekable;
+    use std::net::SocketAddr;
+    use std::str::Chars;
+
+    /// Local webhook answering every post with `status`, the bodies it got
+    /// come out of the receiver.
+    fn webhook(status: StatusCode) -> (SocketAddr, mpsc::UnboundedReceiver<String>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let make = make_service_fn(move |_| {
+            let tx = tx.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let tx = tx.clone();
+                    async move {
+                        let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                        tx.send(String::from_utf8(body.to_vec()).unwrap()).unwrap();
+                        Ok::<_, Infallible>(
+                            Response::builder()
+                                .status(status)
+                                .body(Body::empty())
+                                .unwrap(),
+                        )
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make);
+        let addr = server.local_addr();
+        task::spawn(server);
+        (addr, rx)
+    }
+
+    fn start(addr: SocketAddr, rule: &str) -> events::EventTx {
+        let conf = conf::Alert {
+            webhook: format!("http://{}/alert", addr),
+            rules: vec![AlertRule {
+                name: rule.to_string(),
+                ids: Vec::new(),
+                condition: AlertCondition::Loss { percent: 10.0 },
+            }],
+        };
+        let alerter = Alerter::new(&conf).unwrap().unwrap();
+        let event_tx = events::channel();
+        task::spawn(alerter.run(event_tx.subscribe()));
+        event_tx
+    }
+
+    fn window(lost: u32) -> Event {
+        Event::Window(Aggregate {
+            id: 7,
+            probe_type: ProbeType::Ping,
+            window_start: UNIX_EPOCH + Duration::from_secs(60),
+            window: Duration::from_secs(60),
+            count: 100,
+            lost,
+            rtt_min: Duration::from_millis(1),
+            rtt_max: Duration::from_millis(9),
+            rtt_p50: Duration::from_millis(2),
+            rtt_p90: Duration::from_millis(5),
+            rtt_p99: Duration::from_millis(8),
+            rtt_p999: Duration::from_millis(9),
+            bursts: 0,
+            burst_mean: 0.0,
+            burst_max: 0,
+            gilbert: Gilbert::default(),
+            variation: PingStats::new(7),
+            ip: None,
+            origin: None,
+        })
+    }
+
+    async fn next(rx: &mut mpsc::UnboundedReceiver<String>) -> HashMap<String, String> {
+        let body = time::timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .expect("no alert posted")
+            .unwrap();
+        parse_object(&body)
+    }
+
+    async fn assert_idle(rx: &mut mpsc::UnboundedReceiver<String>) {
+        let extra = time::timeout(Duration::from_millis(300), rx.recv()).await;
+        assert!(extra.is_err(), "unexpected alert {:?}", extra);
+    }
+
+    /// Reads a flat JSON object of strings and numbers, panics on anything
+    /// which is not valid JSON.
+    fn parse_object(json: &str) -> HashMap<String, String> {
+        let mut chars = json.chars().peekable();
+        let mut object = HashMap::new();
+        assert_eq!(chars.next(), Some('{'), "{}", json);
+        loop {
+            let key = parse_string(&mut chars);
+            assert_eq!(chars.next(), Some(':'), "{}", json);
+            let value = match chars.peek() {
+                Some('"') => parse_string(&mut chars),
+                _ => {
+                    let mut number = String::new();
+                    while let Some(c) = chars.next_if(|c| "+-.eE0123456789".contains(*c)) {
+                        number.push(c);
+                    }
+                    number.parse::<f64>().expect(json);
+                    number
+                }
+            };
+            object.insert(key, value);
+            match chars.next() {
+                Some(',') => continue,
+                Some('}') => break,
+                c => panic!("unexpected {:?} in {}", c, json),
+            }
+        }
+        assert_eq!(chars.next(), None, "{}", json);
+        object
+    }
+
+    fn parse_string(chars: &mut Peekable<Chars>) -> String {
+        assert_eq!(chars.next(), Some('"'));
+        let mut out = String::new();
+        loop {
+            match chars.next().expect("unterminated string") {
+                '"' => return out,
+                '\\' => match chars.next().unwrap() {
+                    'u' => {
+                        let hex: String = chars.by_ref().take(4).collect();
+                        let code = u32::from_str_radix(&hex, 16).unwrap();
+                        out.push(char::from_u32(code).unwrap());
+                    }
+                    c @ ('"' | '\\' | '/') => out.push(c),
+                    'n' => out.push('\n'),
+                    't' => out.push('\t'),
+                    'r' => out.push('\r'),
+                    c => panic!("bad escape {:?}", c),
+                },
+                c if u32::from(c) < 0x20 => panic!("raw control character {:?}", c),
+                c => out.push(c),
+            }
+        }
+    }
+
+    #[test]
+    fn escape_quotes_and_control_characters() {
+        assert_eq!(escape("plain"), r#""plain""#);
+        assert_eq!(escape(r#"a"b\c"#), r#""a\"b\\c""#);
+        assert_eq!(escape("a\nb\u{1}"), r#""a\u000ab\u0001""#);
+    }
+
+    #[test]
+    fn json_body_is_valid() {
+        let rule = "loss \"high\"\t\\ \n\u{7f}";
+        let notification = Notification {
+            rule: rule.to_string(),
+            status: Status::Firing,
+            probe_type: ProbeType::TcpPing,
+            id: 3,
+            target: Some("10.0.0.1:80\r".to_string()),
+            value: 12.5,
+            threshold: Some(10.0),
+            at: UNIX_EPOCH + Duration::from_millis(1_500),
+        };
+        let json = parse_object(&notification.to_json());
+        assert_eq!(json["rule"], rule);
+        assert_eq!(json["status"], "firing");
+        assert_eq!(json["type"], "TcpPing");
+        assert_eq!(json["target"], "10.0.0.1:80\r");
+        assert_eq!(json["fingerprint"], format!("{}/TcpPing/3", rule));
+        assert_eq!(json["value"], "12.5");
+        assert_eq!(json["threshold"], "10");
+        assert_eq!(json["at"], "1500");
+    }
+
+    #[tokio::test]
+    async fn fire_once_and_resolve() {
+        let (addr, mut rx) = webhook(StatusCode::OK);
+        let rule = "loss \"high\"\n";
+        let event_tx = start(addr, rule);
+
+        event_tx.send(window(50)).unwrap();
+        event_tx.send(window(40)).unwrap();
+        event_tx.send(window(0)).unwrap();
+
+        let firing = next(&mut rx).await;
+        assert_eq!(firing["status"], "firing");
+        assert_eq!(firing["rule"], rule);
+        assert_eq!(firing["value"], "50");
+        let resolved = next(&mut rx).await;
+        assert_eq!(resolved["status"], "resolved");
+        assert_eq!(resolved["fingerprint"], firing["fingerprint"]);
+        assert_idle(&mut rx).await;
+    }
+
+    #[tokio::test]
+    async fn retry_server_errors() {
+        let (addr, mut rx) = webhook(StatusCode::SERVICE_UNAVAILABLE);
+        let event_tx = start(addr, "loss");
+
+        event_tx.send(window(50)).unwrap();
+        for _ in 0..SEND_TIMES {
+            assert_eq!(next(&mut rx).await["status"], "firing");
+        }
+        assert_idle(&mut rx).await;
+    }
+}
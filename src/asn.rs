@@ -0,0 +1,496 @@
+use crate::conf;
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+use tokio::fs;
+use tokio::task;
+use tokio::time::{self, Duration, MissedTickBehavior};
+use tracing::{info, warn};
+
+const MRT_HEADER_LEN: usize = 12;
+const MRT_TABLE_DUMP_V2: u16 = 13;
+const RIB_IPV4_UNICAST: u16 = 2;
+const RIB_IPV6_UNICAST: u16 = 4;
+const ATTR_EXTENDED_LEN: u8 = 0x10;
+const ATTR_AS_PATH: u8 = 2;
+const AS_SET: u8 = 1;
+
+#[derive(Deserialize, Clone, Copy, Default, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// `prefix,asn` lines, the ASN may carry an `AS` prefix.
+    #[default]
+    Csv,
+    /// Uncompressed MRT TABLE_DUMP_V2 RIB dump, RFC 6396. The origin is the
+    /// last AS of the path of the first entry of every prefix.
+    Mrt,
+}
+
+/// The announced prefix covering an address and the AS originating it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Origin {
+    pub asn: u32,
+    pub prefix: IpAddr,
+    pub prefix_len: u8,
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.prefix, self.prefix_len)
+    }
+}
+
+/// Prefixes by length, longest first, each a map from the masked network to
+/// its origin AS.
+#[derive(Default)]
+struct Table {
+    v4: Vec<(u8, HashMap<u32, u32>)>,
+    v6: Vec<(u8, HashMap<u128, u32>)>,
+}
+
+fn mask_v4(ip: u32, len: u8) -> u32 {
+    match len {
+        0 => 0,
+        len => ip & (u32::MAX << (32 - len)),
+    }
+}
+
+fn mask_v6(ip: u128, len: u8) -> u128 {
+    match len {
+        0 => 0,
+        len => ip & (u128::MAX << (128 - len)),
+    }
+}
+
+impl Table {
+    fn insert(&mut self, prefix: IpAddr, len: u8, asn: u32) {
+        fn insert<K: std::hash::Hash + Eq>(
+            by_len: &mut Vec<(u8, HashMap<K, u32>)>,
+            key: K,
+            len: u8,
+            asn: u32,
+        ) {
+            let at = match by_len.binary_search_by(|(l, _)| len.cmp(l)) {
+                Ok(at) => at,
+                Err(at) => {
+                    by_len.insert(at, (len, HashMap::new()));
+                    at
+                }
+            };
+            by_len[at].1.entry(key).or_insert(asn);
+        }
+
+        match prefix {
+            IpAddr::V4(ip) if len <= 32 => insert(&mut self.v4, mask_v4(ip.into(), len), len, asn),
+            IpAddr::V6(ip) if len <= 128 => insert(&mut self.v6, mask_v6(ip.into(), len), len, asn),
+            _ => {}
+        }
+    }
+
+    fn lookup(&self, ip: IpAddr) -> Option<Origin> {
+        match ip {
+            IpAddr::V4(ip) => self.v4.iter().find_map(|(len, prefixes)| {
+                let net = mask_v4(ip.into(), *len);
+                prefixes.get(&net).map(|&asn| Origin {
+                    asn,
+                    prefix: IpAddr::V4(Ipv4Addr::from(net)),
+                    prefix_len: *len,
+                })
+            }),
+            IpAddr::V6(ip) => self.v6.iter().find_map(|(len, prefixes)| {
+                let net = mask_v6(ip.into(), *len);
+                prefixes.get(&net).map(|&asn| Origin {
+                    asn,
+                    prefix: IpAddr::V6(Ipv6Addr::from(net)),
+                    prefix_len: *len,
+                })
+            }),
+        }
+    }
+
+    fn prefixes(&self) -> usize {
+        let v4: usize = self.v4.iter().map(|(_, p)| p.len()).sum();
+        let v6: usize = self.v6.iter().map(|(_, p)| p.len()).sum();
+        v4 + v6
+    }
+
+    fn parse_csv(data: &[u8]) -> Result<Self> {
+        let data = std::str::from_utf8(data).context("table is not utf-8")?;
+        let mut table = Self::default();
+        for (n, line) in data.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let parse = || -> Option<(IpAddr, u8, u32)> {
+                let (prefix, asn) = line.split_once(',')?;
+                let (ip, len) = prefix.trim().split_once('/')?;
+                let asn = asn.trim();
+                let asn = asn
+                    .strip_prefix("AS")
+                    .or_else(|| asn.strip_prefix("as"))
+                    .unwrap_or(asn);
+                Some((ip.parse().ok()?, len.parse().ok()?, asn.parse().ok()?))
+            };
+            let (ip, len, asn) = parse().ok_or_else(|| anyhow!("bad line {}: {}", n + 1, line))?;
+            table.insert(ip, len, asn);
+        }
+        Ok(table)
+    }
+
+    fn parse_mrt(mut data: &[u8]) -> Result<Self> {
+        let mut table = Self::default();
+        while !data.is_empty() {
+            if data.len() < MRT_HEADER_LEN {
+                bail!("truncated mrt header");
+            }
+            let kind = u16::from_be_bytes([data[4], data[5]]);
+            let subtype = u16::from_be_bytes([data[6], data[7]]);
+            let len = u32::from_be_bytes(data[8..12].try_into().unwrap()) as usize;
+            let record = data
+                .get(MRT_HEADER_LEN..MRT_HEADER_LEN + len)
+                .ok_or_else(|| anyhow!("truncated mrt record"))?;
+            data = &data[MRT_HEADER_LEN + len..];
+
+            let v6 = match (kind, subtype) {
+                (MRT_TABLE_DUMP_V2, RIB_IPV4_UNICAST) => false,
+                (MRT_TABLE_DUMP_V2, RIB_IPV6_UNICAST) => true,
+                _ => continue,
+            };
+            if let Some((prefix, len, asn)) = Self::rib_entry(record, v6) {
+                table.insert(prefix, len, asn);
+            }
+        }
+        Ok(table)
+    }
+
+    /// Prefix and origin AS of a RIB_IPV4_UNICAST or RIB_IPV6_UNICAST record.
+    fn rib_entry(record: &[u8], v6: bool) -> Option<(IpAddr, u8, u32)> {
+        let len = *record.get(4)?;
+        let bytes = usize::from(len).div_ceil(8);
+        if bytes > if v6 { 16 } else { 4 } {
+            return None;
+        }
+        let mut octets = [0; 16];
+        octets[..bytes].copy_from_slice(record.get(5..5 + bytes)?);
+        let prefix = if v6 {
+            IpAddr::V6(Ipv6Addr::from(octets))
+        } else {
+            IpAddr::V4(Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3]))
+        };
+
+        let mut entries = record.get(5 + bytes + 2..)?;
+        while entries.len() >= 8 {
+            let attr_len = usize::from(u16::from_be_bytes([entries[6], entries[7]]));
+            let attrs = entries.get(8..8 + attr_len)?;
+            if let Some(asn) = Self::origin_as(attrs) {
+                return Some((prefix, len, asn));
+            }
+            entries = &entries[8 + attr_len..];
+        }
+        None
+    }
+
+    /// Last AS of the AS_PATH attribute, ASNs are 4 bytes in TABLE_DUMP_V2.
+    fn origin_as(mut attrs: &[u8]) -> Option<u32> {
+        while attrs.len() >= 3 {
+            let (flags, kind) = (attrs[0], attrs[1]);
+            let (len, at) = if flags & ATTR_EXTENDED_LEN != 0 {
+                (
+                    usize::from(u16::from_be_bytes([attrs[2], *attrs.get(3)?])),
+                    4,
+                )
+            } else {
+                (usize::from(attrs[2]), 3)
+            };
+            let value = attrs.get(at..at + len)?;
+            attrs = &attrs[at + len..];
+            if kind != ATTR_AS_PATH {
+                continue;
+            }
+
+            let mut origin = None;
+            let mut segments = value;
+            while segments.len() >= 2 {
+                let (segment, count) = (segments[0], usize::from(segments[1]));
+                let asns = segments.get(2..2 + count * 4)?;
+                let pick = if segment == AS_SET {
+                    0
+                } else {
+                    count.checked_sub(1)?
+                };
+                origin = Some(u32::from_be_bytes(
+                    asns.get(pick * 4..pick * 4 + 4)?.try_into().ok()?,
+                ));
+                segments = &segments[2 + count * 4..];
+            }
+            return origin;
+        }
+        None
+    }
+}
+
+/// IP to origin AS table loaded from a local file, so enrichment never goes
+/// online. Cheap to clone, every clone sees reloads.
+#[derive(Clone)]
+pub struct AsnDb {
+    path: String,
+    format: Format,
+    table: Arc<RwLock<Arc<Table>>>,
+}
+
+impl AsnDb {
+    /// `None` when no table is configured.
+    pub async fn new(conf: &conf::Asn) -> Result<Option<Self>> {
+        if conf.path.is_empty() {
+            return Ok(None);
+        }
+
+        let db = Self {
+            path: conf.path.clone(),
+            format: conf.format,
+            table: Arc::new(RwLock::new(Arc::new(Table::default()))),
+        };
+        db.load().await?;
+        Ok(Some(db))
+    }
+
+    pub fn lookup(&self, ip: IpAddr) -> Option<Origin> {
+        let table = self.table.read().unwrap().clone();
+        table.lookup(ip)
+    }
+
+    async fn load(&self) -> Result<()> {
+        let data = fs::read(&self.path)
+            .await
+            .with_context(|| format!("read asn table {}", self.path))?;
+        let format = self.format;
+        let table = task::spawn_blocking(move || match format {
+            Format::Csv => Table::parse_csv(&data),
+            Format::Mrt => Table::parse_mrt(&data),
+        })
+        .await?
+        .with_context(|| format!("parse asn table {}", self.path))?;
+
+        info!(
+            "Load asn table {}, {} prefixes",
+            self.path,
+            table.prefixes()
+        );
+        *self.table.write().unwrap() = Arc::new(table);
+        Ok(())
+    }
+
+    async fn modified(&self) -> Option<SystemTime> {
+        fs::metadata(&self.path).await.ok()?.modified().ok()
+    }
+
+    /// Reload the table whenever the file changes, checked every `interval`.
+    /// A table which fails to load keeps the previous one in use.
+    pub async fn watch(self, interval: Duration) {
+        let mut modified = self.modified().await;
+        let mut timer = time::interval(interval);
+        timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        timer.tick().await;
+        loop {
+            timer.tick().await;
+            let now = self.modified().await;
+            if now.is_none() || now == modified {
+                continue;
+            }
+            modified = now;
+            if let Err(e) = self.load().await {
+                warn!("Reload asn table fail, err:{:#}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn origin(table: &Table, ip: &str) -> Option<(u32, String)> {
+        let origin = table.lookup(ip.parse().unwrap())?;
+        Some((origin.asn, origin.to_string()))
+    }
+
+    /// A TABLE_DUMP_V2 record of `subtype` holding `body`.
+    fn record(subtype: u16, body: &[u8]) -> Vec<u8> {
+        let mut record = vec![0; 4];
+        record.extend(MRT_TABLE_DUMP_V2.to_be_bytes());
+        record.extend(subtype.to_be_bytes());
+        record.extend((body.len() as u32).to_be_bytes());
+        record.extend(body);
+        record
+    }
+
+    /// A RIB entry of `prefix` whose only peer saw `segments` as the AS path.
+    fn rib(prefix: &[u8], len: u8, segments: &[(u8, &[u32])]) -> Vec<u8> {
+        let mut path = Vec::new();
+        for (segment, asns) in segments {
+            path.extend([*segment, asns.len() as u8]);
+            path.extend(asns.iter().flat_map(|asn| asn.to_be_bytes()));
+        }
+        // An origin attribute first, then the path with an extended length.
+        let mut attrs = vec![0x40, 1, 1, 0, 0x40 | ATTR_EXTENDED_LEN, ATTR_AS_PATH];
+        attrs.extend((path.len() as u16).to_be_bytes());
+        attrs.extend(path);
+
+        let mut body = vec![0, 0, 0, 1, len];
+        body.extend(&prefix[..usize::from(len).div_ceil(8)]);
+        body.extend(1_u16.to_be_bytes());
+        body.extend([0; 6]);
+        body.extend((attrs.len() as u16).to_be_bytes());
+        body.extend(attrs);
+        body
+    }
+
+    fn dump() -> Vec<u8> {
+        const SEQUENCE: u8 = 2;
+        let v6: Ipv6Addr = "2001:db8:100::".parse().unwrap();
+        [
+            // The peer index table is skipped.
+            record(1, &[0; 8]),
+            record(
+                RIB_IPV4_UNICAST,
+                &rib(&[192, 0, 2, 0], 24, &[(SEQUENCE, &[64_496, 64_500])]),
+            ),
+            record(
+                RIB_IPV4_UNICAST,
+                &rib(&[192, 0, 0, 0], 16, &[(SEQUENCE, &[64_496, 64_501])]),
+            ),
+            record(
+                RIB_IPV6_UNICAST,
+                &rib(
+                    &v6.octets(),
+                    40,
+                    &[(SEQUENCE, &[64_496]), (AS_SET, &[64_502, 64_503])],
+                ),
+            ),
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn longest_prefix_wins() {
+        let table = Table::parse_csv(
+            b"# prefix,asn\n\
+              10.0.0.0/8,1\n\
+              10.1.2.77/24, AS3\n\
+              \n\
+              10.1.0.0/16,as2\n\
+              0.0.0.0/0,4\n\
+              2001:db8:1::/48,64501\n\
+              2001:db8::/32,64500\n",
+        )
+        .unwrap();
+        assert_eq!(table.prefixes(), 6);
+
+        assert_eq!(origin(&table, "10.1.2.3"), Some((3, "10.1.2.0/24".into())));
+        assert_eq!(origin(&table, "10.1.9.9"), Some((2, "10.1.0.0/16".into())));
+        assert_eq!(origin(&table, "10.9.9.9"), Some((1, "10.0.0.0/8".into())));
+        assert_eq!(origin(&table, "11.0.0.1"), Some((4, "0.0.0.0/0".into())));
+        assert_eq!(
+            origin(&table, "2001:db8:1::1"),
+            Some((64_501, "2001:db8:1::/48".into()))
+        );
+        assert_eq!(
+            origin(&table, "2001:db8:2::1"),
+            Some((64_500, "2001:db8::/32".into()))
+        );
+        assert_eq!(origin(&table, "2001:db9::1"), None);
+    }
+
+    #[test]
+    fn first_entry_of_a_prefix_wins() {
+        let table = Table::parse_csv(b"10.0.0.0/8,1\n10.0.0.0/8,2\n").unwrap();
+        assert_eq!(origin(&table, "10.0.0.1"), Some((1, "10.0.0.0/8".into())));
+    }
+
+    #[test]
+    fn malformed_csv_lines() {
+        for line in [
+            "10.0.0.0/8",
+            "10.0.0.0,1",
+            "10.0.0.0/x,1",
+            "10.0.0.0/300,1",
+            "10.0.0/8,1",
+            "10.0.0.0/8,ASx",
+            "10.0.0.0/8,",
+            "10.0.0.0/8,1,2",
+        ] {
+            let data = format!("10.0.0.0/8,1\n{}\n", line);
+            let err = Table::parse_csv(data.as_bytes()).err().unwrap();
+            assert_eq!(err.to_string(), format!("bad line 2: {}", line));
+        }
+        assert!(Table::parse_csv(b"10.0.0.0/8,\xff\n").is_err());
+        // Lengths beyond the address are ignored rather than masked.
+        let table = Table::parse_csv(b"10.0.0.0/33,1\n::/129,1\n").unwrap();
+        assert_eq!(table.prefixes(), 0);
+    }
+
+    #[test]
+    fn mrt_origins() {
+        let table = Table::parse_mrt(&dump()).unwrap();
+        assert_eq!(table.prefixes(), 3);
+        assert_eq!(
+            origin(&table, "192.0.2.1"),
+            Some((64_500, "192.0.2.0/24".into()))
+        );
+        assert_eq!(
+            origin(&table, "192.0.3.1"),
+            Some((64_501, "192.0.0.0/16".into()))
+        );
+        // The first AS of a trailing set stands for the origin.
+        assert_eq!(
+            origin(&table, "2001:db8:1ff::1"),
+            Some((64_502, "2001:db8:100::/40".into()))
+        );
+    }
+
+    #[test]
+    fn truncated_mrt() {
+        let dump = dump();
+        let err = Table::parse_mrt(&dump[..dump.len() - 1]).err().unwrap();
+        assert_eq!(err.to_string(), "truncated mrt record");
+        let err = Table::parse_mrt(&dump[..MRT_HEADER_LEN - 1]).err().unwrap();
+        assert_eq!(err.to_string(), "truncated mrt header");
+
+        // Cut anywhere, the dump is refused or read up to a record boundary.
+        for len in 0..dump.len() {
+            if let Ok(table) = Table::parse_mrt(&dump[..len]) {
+                assert!(table.prefixes() <= 3);
+            }
+        }
+    }
+
+    #[test]
+    fn garbage_mrt_records() {
+        // Records too short for their entries are skipped, not fatal.
+        let dump = [
+            record(RIB_IPV4_UNICAST, &[]),
+            record(RIB_IPV4_UNICAST, &[0, 0, 0, 1, 33, 1, 2, 3, 4, 5]),
+            record(RIB_IPV6_UNICAST, &[0, 0, 0, 1, 200]),
+            record(RIB_IPV4_UNICAST, &rib(&[10, 0, 0, 0], 8, &[(2, &[])])),
+            record(RIB_IPV4_UNICAST, &[0xff; 64]),
+        ]
+        .concat();
+        assert_eq!(Table::parse_mrt(&dump).unwrap().prefixes(), 0);
+
+        // Whatever the bytes, parsing never panics.
+        let mut dump = self::dump();
+        for i in MRT_HEADER_LEN..dump.len() {
+            let saved = dump[i];
+            for byte in [0, 1, 0x7f, 0xff] {
+                dump[i] = byte;
+                let _ = Table::parse_mrt(&dump);
+            }
+            dump[i] = saved;
+        }
+    }
+}
@@ -0,0 +1,263 @@
+use crate::conf;
+use crate::events::{Event, EventTx};
+use crate::structures::BudgetStats;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::SystemTime;
+use tokio::time::{self, Duration, Instant};
+use tracing::info;
+
+/// A bucket holds this much of its rate, bursts above it are delayed.
+const BURST_WINDOW: Duration = Duration::from_millis(100);
+const STATS_INTERVAL: Duration = Duration::from_secs(60);
+
+struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    fn new(pps: u32) -> Self {
+        let rate = f64::from(pps);
+        let capacity = (rate * BURST_WINDOW.as_secs_f64()).max(1.0);
+        Self {
+            rate,
+            capacity,
+            state: Mutex::new((capacity, Instant::now())),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, (f64, Instant)> {
+        self.state.lock().expect("Lock token bucket fail")
+    }
+
+    /// Take a token from every bucket if each of them has one, otherwise from
+    /// none. Returns how long to wait before trying again, zero when taken.
+    /// Buckets are locked in order, the global one first.
+    fn take(buckets: &[&Self]) -> Duration {
+        let mut states: Vec<_> = buckets.iter().map(|bucket| bucket.lock()).collect();
+        let now = Instant::now();
+        let mut wait = Duration::ZERO;
+        for (bucket, state) in buckets.iter().zip(&mut states) {
+            let (tokens, last) = &mut **state;
+            *tokens = (*tokens + now.duration_since(*last).as_secs_f64() * bucket.rate)
+                .min(bucket.capacity);
+            *last = now;
+            if *tokens < 1.0 {
+                wait = wait.max(Duration::from_secs_f64((1.0 - *tokens) / bucket.rate));
+            }
+        }
+
+        if wait.is_zero() {
+            for state in &mut states {
+                state.0 -= 1.0;
+            }
+        }
+        wait
+    }
+}
+
+#[derive(Default)]
+struct Stats {
+    acquired: AtomicU64,
+    delayed: AtomicU64,
+    delayed_micros: AtomicU64,
+}
+
+/// Packet budget of one protocol, every probe packet must be acquired from it.
+#[derive(Clone)]
+pub struct Budget {
+    global: Option<Arc<TokenBucket>>,
+    protocol: Option<Arc<TokenBucket>>,
+    stats: Arc<Stats>,
+}
+
+impl Budget {
+    pub async fn acquire(&self) {
+        self.stats.acquired.fetch_add(1, Ordering::Relaxed);
+
+        let buckets: Vec<_> = [&self.global, &self.protocol]
+            .into_iter()
+            .flatten()
+            .map(|bucket| &**bucket)
+            .collect();
+        let start = Instant::now();
+        loop {
+            let wait = TokenBucket::take(&buckets);
+            if wait.is_zero() {
+                break;
+            }
+            time::sleep(wait).await;
+        }
+
+        let waited = start.elapsed();
+        if waited.is_zero() {
+            return;
+        }
+        self.stats.delayed.fetch_add(1, Ordering::Relaxed);
+        self.stats
+            .delayed_micros
+            .fetch_add(waited.as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
+/// Budgets of all protocols, sharing the agent wide bucket.
+pub struct Budgets {
+    conf: conf::Budget,
+    global: Option<Arc<TokenBucket>>,
+    protocols: Vec<(&'static str, Arc<Stats>)>,
+}
+
+impl Budgets {
+    pub fn new(conf: &conf::Budget) -> Self {
+        let global = (conf.pps > 0).then(|| Arc::new(TokenBucket::new(conf.pps)));
+        Self {
+            conf: conf.clone(),
+            global,
+            protocols: Vec::new(),
+        }
+    }
+
+    /// Budget for `name`, limited by `[budget.protocols]` if the protocol is listed.
+    pub fn protocol(&mut self, name: &'static str) -> Budget {
+        let pps = self.conf.protocols.get(name).copied().unwrap_or(0);
+        let protocol = (pps > 0).then(|| Arc::new(TokenBucket::new(pps)));
+        let stats = Arc::new(Stats::default());
+        self.protocols.push((name, stats.clone()));
+
+        Budget {
+            global: self.global.clone(),
+            protocol,
+            stats,
+        }
+    }
+
+    /// Periodically publish how many probes each budget handed out and
+    /// delayed, the reporter sends them along with the events.
+    pub async fn publish_stats(self, event_tx: EventTx) {
+        let mut interval = time::interval_at(Instant::now() + STATS_INTERVAL, STATS_INTERVAL);
+        loop {
+            interval.tick().await;
+
+            for (name, stats) in &self.protocols {
+                let acquired = stats.acquired.swap(0, Ordering::Relaxed);
+                let delayed = stats.delayed.swap(0, Ordering::Relaxed);
+                let delayed_micros = stats.delayed_micros.swap(0, Ordering::Relaxed);
+                if acquired == 0 {
+                    continue;
+                }
+                if delayed > 0 {
+                    info!(
+                        "Budget {} delayed {}/{} probes, total wait {}ms",
+                        name,
+                        delayed,
+                        acquired,
+                        delayed_micros / 1000
+                    );
+                }
+
+                // Nobody listening is fine, events are best effort.
+                let _ = event_tx.send(Event::Budget(BudgetStats {
+                    protocol: name,
+                    at: SystemTime::now(),
+                    interval: STATS_INTERVAL,
+                    acquired,
+                    delayed,
+                    delayed_total: Duration::from_micros(delayed_micros),
+                }));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events;
+    use futures::future;
+    use std::collections::HashMap;
+    use tokio::task;
+
+    fn budgets(pps: u32, protocols: &[(&str, u32)]) -> Budgets {
+        Budgets::new(&conf::Budget {
+            pps,
+            protocols: protocols
+                .iter()
+                .map(|&(name, pps)| (name.to_string(), pps))
+                .collect::<HashMap<_, _>>(),
+        })
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn take_from_both_or_neither() {
+        let global = TokenBucket::new(10);
+        let protocol = TokenBucket::new(100);
+        assert_eq!(TokenBucket::take(&[&global, &protocol]), Duration::ZERO);
+        assert_eq!((global.lock().0, protocol.lock().0), (0.0, 9.0));
+
+        // The global bucket is empty, so the protocol keeps its token.
+        let wait = TokenBucket::take(&[&global, &protocol]);
+        assert_eq!(wait, Duration::from_millis(100));
+        assert_eq!((global.lock().0, protocol.lock().0), (0.0, 9.0));
+
+        time::advance(wait).await;
+        assert_eq!(TokenBucket::take(&[&global, &protocol]), Duration::ZERO);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pace_to_the_slower_bucket() {
+        let mut budgets = budgets(100, &[("ping", 10)]);
+        let ping = budgets.protocol("ping");
+        let other = budgets.protocol("other");
+
+        let start = Instant::now();
+        let waiting = {
+            let ping = ping.clone();
+            tokio::spawn(async move {
+                future::join_all((0..21).map(|_| ping.acquire())).await;
+            })
+        };
+        task::yield_now().await;
+
+        // Probes waiting for ping tokens hold no global ones meanwhile.
+        for _ in 0..9 {
+            other.acquire().await;
+        }
+        assert_eq!(start.elapsed(), Duration::ZERO);
+
+        // One token is there from the start, then one every 100ms.
+        waiting.await.unwrap();
+        assert_eq!(start.elapsed(), Duration::from_secs(2));
+        assert_eq!(ping.stats.acquired.load(Ordering::Relaxed), 21);
+        assert_eq!(ping.stats.delayed.load(Ordering::Relaxed), 20);
+        assert_eq!(other.stats.delayed.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn publish_counters() {
+        let mut budgets = budgets(0, &[("ping", 10)]);
+        let ping = budgets.protocol("ping");
+        let _idle = budgets.protocol("idle");
+        let event_tx = events::channel();
+        let mut events = event_tx.subscribe();
+        tokio::spawn(budgets.publish_stats(event_tx));
+
+        for _ in 0..3 {
+            ping.acquire().await;
+        }
+        let Event::Budget(stats) = events.recv().await.unwrap() else {
+            panic!("not budget stats");
+        };
+        assert_eq!(stats.protocol, "ping");
+        assert_eq!((stats.acquired, stats.delayed), (3, 2));
+        assert_eq!(stats.delayed_total, Duration::from_millis(200));
+        assert_eq!(stats.interval, STATS_INTERVAL);
+        // Budgets without probes are left out, counters start over.
+        ping.acquire().await;
+        let Event::Budget(stats) = events.recv().await.unwrap() else {
+            panic!("not budget stats");
+        };
+        assert_eq!((stats.protocol, stats.acquired), ("ping", 1));
+    }
+}
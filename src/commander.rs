@@ -1,9 +1,11 @@
 use crate::grpc::controller_grpc::controller_client::ControllerClient;
-use crate::grpc::controller_grpc::{
-    CommandReq, CommandType, PingCommandsResp, RegisterReq, TcpPingCommandResp, UpdateCommandResp,
+use crate::grpc::controller_grpc::{CommandReq, CommandType, RegisterReq, UpdateCommandResp};
+use crate::structures::{
+    FPingCommand, MtrCommand, PingCommand, PmtuCommand, TcpPingCommand, TimestampCommand,
+    TwampCommand,
 };
-use crate::structures::{FPingCommand, PingCommand, TcpPingCommand};
 use std::convert::TryFrom;
+use std::future::Future;
 use std::result::Result::Err;
 use std::str::FromStr;
 use tokio::sync::broadcast;
@@ -18,7 +20,7 @@ use tracing::{info, warn};
 const RETRY_INTERVAL_MIN: u64 = 5;
 const RETRY_INTERVAL_MAX: u64 = 15;
 
-type Client = ControllerClient<Channel>;
+pub type Client = ControllerClient<Channel>;
 
 type UpdateTx = broadcast::Sender<UpdateCommandResp>;
 type UpdateRx = broadcast::Receiver<UpdateCommandResp>;
@@ -114,40 +116,58 @@ impl Commander {
         }
     }
 
-    pub async fn forward_ping_command(mut self, tx: Sender<Vec<PingCommand>>) {
+    pub async fn forward_command<C: Command>(mut self, tx: Sender<Vec<C>>) {
         let mut client = Client::new(self.channel.clone());
         loop {
             let comm = match self.rx.recv().await {
                 Ok(c) => c,
                 Err(RecvError::Lagged(v)) => {
-                    warn!("Recv ping command lagged skipped:{}", v);
+                    warn!("Recv {} command lagged skipped:{}", C::NAME, v);
                     continue;
                 }
-                Err(RecvError::Closed) => panic!("Recv ping command on closed channel"),
+                Err(RecvError::Closed) => panic!("Recv {} command on closed channel", C::NAME),
             };
 
-            if comm.command_type != CommandType::Ping as i32 {
+            if comm.command_type != C::COMMAND_TYPE as i32 {
                 continue;
             }
-            info!("Recv ping command update");
+            info!("Recv {} command update", C::NAME);
 
             let req = self.build_command_req(comm.version);
 
-            info!("Send get ping command req version:{}", req.version.clone());
-            let resp = client.get_ping_command(req).await;
-            match resp {
-                Ok(resp) => {
-                    let resp = resp.into_inner();
-                    info!("Recv ping commands len:{}", resp.ping_commands.len());
-                    let commands = Self::build_ping_commands(resp);
-                    tx.send(commands).await.expect("Send ping commands fail");
+            info!("Send get {} command req version:{}", C::NAME, req.version);
+            match C::fetch(&mut client, req).await {
+                Ok(commands) => {
+                    info!("Recv {} commands len:{}", C::NAME, commands.len());
+                    tx.send(commands)
+                        .await
+                        .unwrap_or_else(|_| panic!("Send {} commands fail", C::NAME));
                 }
-                Err(e) => warn!("Get ping command fail, err:{}", e.message()),
+                Err(e) => warn!("Get {} command fail, err:{}", C::NAME, e.message()),
             }
         }
     }
+}
+
+/// A command type the controller can push to the agent.
+pub trait Command: Sized + Send + 'static {
+    /// Update notifications with this type trigger a fetch.
+    const COMMAND_TYPE: CommandType;
+    /// Human readable name used in logs.
+    const NAME: &'static str;
+
+    fn fetch(
+        client: &mut Client,
+        req: CommandReq,
+    ) -> impl Future<Output = Result<Vec<Self>, Status>> + Send;
+}
 
-    fn build_ping_commands(resp: PingCommandsResp) -> Vec<PingCommand> {
+impl Command for PingCommand {
+    const COMMAND_TYPE: CommandType = CommandType::Ping;
+    const NAME: &'static str = "ping";
+
+    async fn fetch(client: &mut Client, req: CommandReq) -> Result<Vec<Self>, Status> {
+        let resp = client.get_ping_command(req).await?.into_inner();
         let mut v = Vec::with_capacity(resp.ping_commands.len());
         for comm in resp.ping_commands {
             let ip = comm.ip.clone();
@@ -159,95 +179,117 @@ impl Commander {
             }
         }
 
-        v
+        Ok(v)
     }
+}
 
-    pub async fn forward_tcp_ping_command(mut self, tx: Sender<Vec<TcpPingCommand>>) {
-        let mut client = Client::new(self.channel.clone());
-        loop {
-            let comm = match self.rx.recv().await {
-                Ok(c) => c,
-                Err(RecvError::Lagged(v)) => {
-                    warn!("Recv tcp ping command lagged skipped:{}", v);
+impl Command for TcpPingCommand {
+    const COMMAND_TYPE: CommandType = CommandType::TcpPing;
+    const NAME: &'static str = "tcp ping";
+
+    async fn fetch(client: &mut Client, req: CommandReq) -> Result<Vec<Self>, Status> {
+        let resp = client.get_tcp_ping_command(req).await?.into_inner();
+        let commands = resp
+            .tcp_ping_commands
+            .into_iter()
+            .map(TcpPingCommand::from)
+            .collect();
+
+        Ok(commands)
+    }
+}
+
+impl Command for FPingCommand {
+    const COMMAND_TYPE: CommandType = CommandType::Fping;
+    const NAME: &'static str = "fping";
+
+    async fn fetch(client: &mut Client, req: CommandReq) -> Result<Vec<Self>, Status> {
+        let resp = client.get_fping_command(req).await?.into_inner();
+        let mut commands = Vec::with_capacity(resp.fping_commands.len());
+        for command in resp.fping_commands {
+            let command = match command.try_into() {
+                Ok(command) => command,
+                Err(e) => {
+                    warn!("Parse ip addr fail, err:{}", e);
                     continue;
                 }
-                Err(RecvError::Closed) => panic!("Recv tcp ping command on closed channel"),
             };
+            commands.push(command);
+        }
 
-            if comm.command_type != CommandType::TcpPing as i32 {
-                continue;
-            }
-            info!("Recv tcp ping command update");
+        Ok(commands)
+    }
+}
 
-            let req = self.build_command_req(comm.version);
+/// The controller hands out one trace per update.
+impl Command for MtrCommand {
+    const COMMAND_TYPE: CommandType = CommandType::Mtr;
+    const NAME: &'static str = "mtr";
 
-            info!("Send get tcp ping command req version:{}", req.version);
-            let resp = client.get_tcp_ping_command(req).await;
-            match resp {
-                Ok(resp) => {
-                    let resp = resp.into_inner();
-                    let len = resp.tcp_ping_commands.len();
-                    info!("Recv tcp ping commands len:{}", len);
-                    let commands = Self::build_tcp_ping_commands(resp);
-                    tx.send(commands)
-                        .await
-                        .expect("Send tcp ping commands fail");
-                }
-                Err(e) => warn!("Get ping command fail, err:{}", e.message()),
+    async fn fetch(client: &mut Client, req: CommandReq) -> Result<Vec<Self>, Status> {
+        let resp = client.get_mtr_command(req).await?.into_inner();
+        match MtrCommand::try_from(resp) {
+            Ok(command) => Ok(vec![command]),
+            Err(e) => {
+                warn!("Parse ip addr fail, err:{}", e);
+                Ok(Vec::new())
             }
         }
     }
+}
+
+impl Command for PmtuCommand {
+    const COMMAND_TYPE: CommandType = CommandType::Pmtu;
+    const NAME: &'static str = "pmtu";
 
-    fn build_tcp_ping_commands(resp: TcpPingCommandResp) -> Vec<TcpPingCommand> {
-        let mut v = Vec::with_capacity(resp.tcp_ping_commands.len());
-        for comm in resp.tcp_ping_commands {
-            let command = TcpPingCommand::from(comm);
-            v.push(command);
+    async fn fetch(client: &mut Client, req: CommandReq) -> Result<Vec<Self>, Status> {
+        let resp = client.get_pmtu_command(req).await?.into_inner();
+        let mut commands = Vec::with_capacity(resp.pmtu_commands.len());
+        for command in resp.pmtu_commands {
+            match command.try_into() {
+                Ok(command) => commands.push(command),
+                Err(e) => warn!("Parse ip addr fail, err:{}", e),
+            }
         }
 
-        v
+        Ok(commands)
     }
+}
 
-    pub async fn forward_fping_command(mut self, tx: Sender<Vec<FPingCommand>>) {
-        let mut client = Client::new(self.channel.clone());
-        loop {
-            let comm = match self.rx.recv().await {
-                Ok(c) => c,
-                Err(RecvError::Lagged(v)) => {
-                    warn!("Recv fping command lagged skipped:{}", v);
-                    continue;
-                }
-                Err(RecvError::Closed) => panic!("Recv fping command on closed channel"),
-            };
+/// Timestamp messages are IPv4 only, other addresses fail to parse.
+impl Command for TimestampCommand {
+    const COMMAND_TYPE: CommandType = CommandType::Timestamp;
+    const NAME: &'static str = "timestamp";
 
-            if comm.command_type != CommandType::Fping as i32 {
-                continue;
+    async fn fetch(client: &mut Client, req: CommandReq) -> Result<Vec<Self>, Status> {
+        let resp = client.get_timestamp_command(req).await?.into_inner();
+        let mut commands = Vec::with_capacity(resp.timestamp_commands.len());
+        for command in resp.timestamp_commands {
+            let ip = command.ip.clone();
+            match command.try_into() {
+                Ok(command) => commands.push(command),
+                Err(e) => warn!("Parse ipv4 addr:{} fail, err:{}", ip, e),
             }
-            info!("Recv fping command update");
+        }
 
-            let req = self.build_command_req(comm.version);
+        Ok(commands)
+    }
+}
 
-            info!("Send get fping command req version:{}", req.version);
-            let resp = client.get_fping_command(req).await;
-            match resp {
-                Ok(resp) => {
-                    let resp = resp.into_inner();
-                    info!("Recv fping commands len:{}", resp.fping_commands.len());
-                    let mut commands = Vec::with_capacity(resp.fping_commands.len());
-                    for command in resp.fping_commands {
-                        let command = match command.try_into() {
-                            Ok(command) => command,
-                            Err(e) => {
-                                warn!("Parse ip addr fail, err:{}", e);
-                                continue;
-                            }
-                        };
-                        commands.push(command);
-                    }
-                    tx.send(commands).await.unwrap();
-                }
-                Err(e) => warn!("Get fping command fail, err:{}", e.message()),
+impl Command for TwampCommand {
+    const COMMAND_TYPE: CommandType = CommandType::Twamp;
+    const NAME: &'static str = "twamp";
+
+    async fn fetch(client: &mut Client, req: CommandReq) -> Result<Vec<Self>, Status> {
+        let resp = client.get_twamp_command(req).await?.into_inner();
+        let mut commands = Vec::with_capacity(resp.twamp_commands.len());
+        for command in resp.twamp_commands {
+            match command.try_into() {
+                Ok(command) => commands.push(command),
+                Err(e) => warn!("Parse ip addr fail, err:{}", e),
             }
         }
+
+        Ok(commands)
     }
 }
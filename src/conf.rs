@@ -1,6 +1,9 @@
+use crate::asn;
+use crate::structures::{ReportMode, TraceMode, TraceProtocol};
 use anyhow::Result;
 use clap::Parser;
 use serde::Deserialize;
+use std::collections::HashMap;
 use tracing::info;
 
 /// Simple program helps you detect network quality.
@@ -17,6 +20,22 @@ pub struct Conf {
     pub agent: Agent,
     pub controller: Controller,
     pub collector: Collector,
+    #[serde(default)]
+    pub budget: Budget,
+    #[serde(default)]
+    pub detector: Detector,
+    #[serde(default)]
+    pub aggregation: Aggregation,
+    #[serde(default)]
+    pub alert: Alert,
+    #[serde(default)]
+    pub traceroute: Traceroute,
+    #[serde(default)]
+    pub asn: Asn,
+    #[serde(default)]
+    pub rdns: Rdns,
+    #[serde(default)]
+    pub twamp: Twamp,
 }
 
 #[derive(Deserialize)]
@@ -34,6 +53,219 @@ pub struct Collector {
     pub url: String,
 }
 
+/// Probe packet rate limits, 0 or missing means unlimited.
+#[derive(Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct Budget {
+    /// Packets per second across all detectors.
+    pub pps: u32,
+    /// Packets per second of a single detector, keyed by protocol name.
+    pub protocols: HashMap<String, u32>,
+}
+
+#[derive(Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct Detector {
+    pub smoothing: Smoothing,
+    pub reachability: Reachability,
+}
+
+/// How the first probes of a new command set are spread out.
+#[derive(Deserialize, Clone, Copy, Default, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Smoothing {
+    /// Every target starts at once.
+    None,
+    /// Targets start evenly spread over one interval.
+    #[default]
+    Uniform,
+    /// Every target starts at an offset derived from its destination, so it
+    /// keeps the same phase across command updates.
+    Hash,
+}
+
+/// Reporting of ping and tcp ping results, commands may override the mode.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct Aggregation {
+    pub mode: ReportMode,
+    pub window_secs: u64,
+}
+
+impl Default for Aggregation {
+    fn default() -> Self {
+        Self {
+            mode: ReportMode::Raw,
+            window_secs: 60,
+        }
+    }
+}
+
+/// Thresholds of the up / degraded / down state of ping and tcp ping targets.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct Reachability {
+    /// Consecutive losses before a target is degraded, 0 disables.
+    pub degraded_after: u32,
+    /// Consecutive losses before a target is down.
+    pub down_after: u32,
+    /// A reply slower than this degrades the target, 0 disables.
+    pub degraded_rtt_ms: u64,
+    /// Consecutive good replies before a target is up again.
+    pub recover_after: u32,
+}
+
+impl Default for Reachability {
+    fn default() -> Self {
+        Self {
+            degraded_after: 2,
+            down_after: 5,
+            degraded_rtt_ms: 0,
+            recover_after: 3,
+        }
+    }
+}
+
+/// Alerts evaluated on the agent, delivered to `webhook`. No webhook disables
+/// alerting.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct Alert {
+    pub webhook: String,
+    pub rules: Vec<AlertRule>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct AlertRule {
+    pub name: String,
+    /// Command ids the rule applies to, empty means every command.
+    #[serde(default)]
+    pub ids: Vec<u64>,
+    #[serde(flatten)]
+    pub condition: AlertCondition,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum AlertCondition {
+    /// Loss of an aggregation window above `percent`.
+    Loss { percent: f64 },
+    /// p99 RTT of an aggregation window above `rtt_ms`.
+    P99 { rtt_ms: u64 },
+    /// The target is down.
+    Down,
+}
+
+/// Traces toward ping and tcp ping targets which become degraded or down, and
+/// toward watched destinations.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct Traceroute {
+    pub trigger: bool,
+    /// At most one triggered trace per command in this many seconds.
+    pub trigger_interval_secs: u64,
+    /// Commanded and triggered traces running at once, others wait.
+    pub max_traces: usize,
+    /// Destinations traced every `watch_interval_secs`, reported only when
+    /// their path changes.
+    pub watch: Vec<String>,
+    pub watch_interval_secs: u64,
+    pub times: u32,
+    pub hop_limit: u32,
+    pub timeout_ms: u64,
+    pub mode: TraceMode,
+    /// Flows traced in Paris mode to enumerate equal-cost paths.
+    pub flows: u32,
+    pub protocol: TraceProtocol,
+    /// Destination port of UDP and TCP probes, 0 is the protocol default.
+    pub port: u16,
+    pub fixed_port: bool,
+}
+
+impl Default for Traceroute {
+    fn default() -> Self {
+        Self {
+            trigger: false,
+            trigger_interval_secs: 300,
+            max_traces: 16,
+            watch: Vec::new(),
+            watch_interval_secs: 300,
+            times: 3,
+            hop_limit: 30,
+            timeout_ms: 1000,
+            mode: TraceMode::Classic,
+            flows: 1,
+            protocol: TraceProtocol::Icmp,
+            port: 0,
+            fixed_port: false,
+        }
+    }
+}
+
+/// Local IP to ASN table trace hops and targets are annotated from, no path
+/// disables enrichment.
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct Asn {
+    pub path: String,
+    pub format: asn::Format,
+    /// Check the file for changes this often.
+    pub reload_secs: u64,
+}
+
+impl Default for Asn {
+    fn default() -> Self {
+        Self {
+            path: String::new(),
+            format: asn::Format::Csv,
+            reload_secs: 60,
+        }
+    }
+}
+
+/// Reverse lookups of trace hops.
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct Rdns {
+    pub enable: bool,
+    /// `ip` or `ip:port` of the DNS server, empty uses resolv.conf.
+    pub server: String,
+    pub timeout_ms: u64,
+    /// How long a missing name is cached when the server sends no SOA.
+    pub negative_ttl_secs: u64,
+    pub max_entries: usize,
+}
+
+impl Default for Rdns {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            server: String::new(),
+            timeout_ms: 1000,
+            negative_ttl_secs: 300,
+            max_entries: 4096,
+        }
+    }
+}
+
+/// TWAMP-Light reflector answering test packets of other agents and routers.
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct Twamp {
+    pub reflector: bool,
+    /// `ip:port` the reflector listens on, `[::]` takes IPv4 too.
+    pub listen: String,
+}
+
+impl Default for Twamp {
+    fn default() -> Self {
+        Self {
+            reflector: false,
+            listen: "[::]:862".to_string(),
+        }
+    }
+}
+
 pub async fn read_conf() -> Result<Conf> {
     use tokio::fs;
 
@@ -0,0 +1,95 @@
+use crate::structures::AnomalyKind;
+use std::time::Duration;
+
+/// Gain of the fast EWMA, follows the RTT within a few probes.
+const FAST_GAIN: f64 = 1.0 / 4.0;
+/// Gain of the baseline and its deviation.
+const SLOW_GAIN: f64 = 1.0 / 64.0;
+/// Replies before anomalies are flagged.
+const WARM_UP: u32 = 32;
+/// Replies off the baseline before they are a step, single spikes are not.
+const STEP_AFTER: u32 = 3;
+/// Replies off the baseline before it moves to the new level.
+const LEVEL_SHIFT_AFTER: u32 = 64;
+/// Lower bound of the deviation, keeps a very stable target from flagging
+/// scheduling noise.
+const MIN_DEVIATION_MICROS: f64 = 500.0;
+
+pub(super) struct Flag {
+    pub(super) kind: AnomalyKind,
+    pub(super) baseline: Duration,
+    pub(super) deviation: Duration,
+    pub(super) rtt: Duration,
+}
+
+/// EWMA baseline and mean deviation of the RTT of one subscriber. The fast
+/// EWMA is compared against it, the baseline only learns from normal replies
+/// so a step does not drag it along.
+pub(super) struct Baseline {
+    sensitivity: f64,
+    replies: u32,
+    fast_micros: f64,
+    baseline_micros: f64,
+    deviation_micros: f64,
+    /// Consecutive replies off the baseline.
+    off: u32,
+}
+
+impl Baseline {
+    pub(super) fn new(sensitivity: f64) -> Self {
+        Self {
+            sensitivity,
+            replies: 0,
+            fast_micros: 0.0,
+            baseline_micros: 0.0,
+            deviation_micros: 0.0,
+            off: 0,
+        }
+    }
+
+    pub(super) fn sensitivity(&self) -> f64 {
+        self.sensitivity
+    }
+
+    fn flag(&self, kind: AnomalyKind, baseline_micros: f64) -> Flag {
+        Flag {
+            kind,
+            baseline: Duration::from_micros(baseline_micros as u64),
+            deviation: Duration::from_micros(self.deviation_micros as u64),
+            rtt: Duration::from_micros(self.fast_micros as u64),
+        }
+    }
+
+    /// Feed a reply, lost probes are the reachability state's business.
+    pub(super) fn on_reply(&mut self, rtt: Duration) -> Option<Flag> {
+        let rtt = rtt.as_micros() as f64;
+        if self.replies == 0 {
+            self.fast_micros = rtt;
+            self.baseline_micros = rtt;
+        }
+        self.replies = self.replies.saturating_add(1);
+        self.fast_micros += (rtt - self.fast_micros) * FAST_GAIN;
+
+        let deviation = self.deviation_micros.max(MIN_DEVIATION_MICROS);
+        let off = (self.fast_micros - self.baseline_micros).abs() > self.sensitivity * deviation;
+        if !off || self.replies <= WARM_UP {
+            self.off = 0;
+            let d = (rtt - self.baseline_micros).abs();
+            self.deviation_micros += (d - self.deviation_micros) * SLOW_GAIN;
+            self.baseline_micros += (rtt - self.baseline_micros) * SLOW_GAIN;
+            return None;
+        }
+
+        self.off += 1;
+        if self.off == STEP_AFTER {
+            return Some(self.flag(AnomalyKind::Step, self.baseline_micros));
+        }
+        if self.off == LEVEL_SHIFT_AFTER {
+            let old = self.baseline_micros;
+            self.baseline_micros = self.fast_micros;
+            self.off = 0;
+            return Some(self.flag(AnomalyKind::LevelShift, old));
+        }
+        None
+    }
+}
@@ -1,31 +1,34 @@
-use crate::detectors::pinger::Pinger;
+use super::pinger::Pinger;
+use super::{Detector, ResultTx};
+use crate::budget::Budget;
 use crate::structures::{FPingCommand, FPingResult};
 use futures::future::join_all;
 use std::sync::Arc;
-use tokio::sync::mpsc::{Receiver, Sender};
 use tokio::sync::Mutex;
 
-type CommandRx = Receiver<Vec<FPingCommand>>;
-type ResultTx = Sender<Vec<FPingResult>>;
-
-pub struct FpingDetector {}
+pub struct FpingDetector {
+    budget: Budget,
+}
 
 impl FpingDetector {
-    pub async fn detect(mut command_rx: CommandRx, result_tx: ResultTx) {
-        loop {
-            let comm = command_rx.recv().await.unwrap();
-            tokio::spawn(Self::detect_once(comm, result_tx.clone()));
-        }
+    pub fn new(budget: Budget) -> Self {
+        Self { budget }
     }
 
-    async fn detect_once(commands: Vec<FPingCommand>, result_tx: ResultTx) {
+    async fn detect_once(
+        commands: Vec<FPingCommand>,
+        budget: Budget,
+        result_tx: ResultTx<Vec<FPingResult>>,
+    ) {
         let results = Vec::with_capacity(commands.len());
         let results = Arc::new(Mutex::new(results));
         let mut handlers = Vec::with_capacity(commands.len());
 
         for comm in commands {
             let results = results.clone();
+            let budget = budget.clone();
             let h = tokio::spawn(async move {
+                budget.acquire().await;
                 let pinger = Pinger::from_fping_command(&comm);
                 let result = pinger.ping(1).await.unwrap();
                 let mut results = results.lock().await;
@@ -41,3 +44,17 @@ impl FpingDetector {
         result_tx.send(results).await.unwrap();
     }
 }
+
+impl Detector for FpingDetector {
+    type Command = FPingCommand;
+    type Result = Vec<FPingResult>;
+
+    /// Every command set is a single round, rounds never cancel each other.
+    async fn apply(&mut self, commands: Vec<FPingCommand>, result_tx: &ResultTx<Vec<FPingResult>>) {
+        tokio::spawn(Self::detect_once(
+            commands,
+            self.budget.clone(),
+            result_tx.clone(),
+        ));
+    }
+}
@@ -0,0 +1,538 @@
+use crate::structures::MplsLabel;
+use bytes::{BufMut, Bytes, BytesMut};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMP_UNREACHABLE: u8 = 3;
+const ICMP_ECHO_REQUEST: u8 = 8;
+const ICMP_TIME_EXCEEDED: u8 = 11;
+const ICMP_TIMESTAMP_REQUEST: u8 = 13;
+const ICMP_TIMESTAMP_REPLY: u8 = 14;
+const ICMP_FRAG_NEEDED: u8 = 4;
+const ICMPV6_UNREACHABLE: u8 = 1;
+const ICMPV6_PACKET_TOO_BIG: u8 = 2;
+const ICMPV6_TIME_EXCEEDED: u8 = 3;
+const ICMPV6_ECHO_REQUEST: u8 = 128;
+const ICMPV6_ECHO_REPLY: u8 = 129;
+const PROTO_ICMP: u8 = 1;
+const PROTO_TCP: u8 = 6;
+const PROTO_UDP: u8 = 17;
+const PROTO_ICMPV6: u8 = 58;
+const ICMP_HEADER_LEN: usize = 8;
+const UDP_HEADER_LEN: usize = 8;
+const IPV6_HEADER_LEN: usize = 40;
+/// Quoted datagram length of errors from before RFC 4884, which put the
+/// extensions behind a fixed 128 bytes.
+const COMPAT_QUOTE_LEN: usize = 128;
+const EXTENSION_VERSION: u8 = 2;
+const EXTENSION_HEADER_LEN: usize = 4;
+const OBJECT_HEADER_LEN: usize = 4;
+const CLASS_MPLS: u8 = 1;
+const CTYPE_LABEL_STACK: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Kind {
+    EchoReply,
+    TimeExceeded,
+    Unreachable {
+        code: u8,
+    },
+    /// Fragmentation needed or packet too big, `mtu` is that of the next hop,
+    /// 0 from routers older than RFC 1191.
+    TooBig {
+        mtu: u32,
+    },
+}
+
+/// The probe a message answers, read from the reply itself or from the
+/// datagram quoted in an error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Quoted {
+    Echo { id: u16, seq: u16 },
+    Udp { src_port: u16, checksum: u16 },
+    Tcp { src_port: u16 },
+}
+
+#[derive(Debug)]
+pub(super) struct Message {
+    pub(super) from: IpAddr,
+    pub(super) kind: Kind,
+    pub(super) probe: Quoted,
+    /// Label stack of the packet when the error was raised, see RFC 4950.
+    pub(super) mpls: Vec<MplsLabel>,
+}
+
+fn u16_at(buf: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(buf.get(at..at + 2)?.try_into().ok()?))
+}
+
+fn u32_at(buf: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(buf.get(at..at + 4)?.try_into().ok()?))
+}
+
+/// RFC 1071 checksum, the kernel only fills it for ICMPv6 on raw sockets.
+pub(super) fn checksum(buf: &[u8]) -> u16 {
+    let mut sum: u32 = buf
+        .chunks(2)
+        .map(|c| u32::from(u16::from_be_bytes([c[0], *c.get(1).unwrap_or(&0)])))
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+pub(super) fn echo_request(v6: bool, id: u16, seq: u16, len: usize) -> Bytes {
+    let mut buf = BytesMut::with_capacity(len);
+    buf.put_u8(if v6 {
+        ICMPV6_ECHO_REQUEST
+    } else {
+        ICMP_ECHO_REQUEST
+    });
+    buf.put_u8(0);
+    buf.put_u16(0);
+    buf.put_u16(id);
+    buf.put_u16(seq);
+    buf.resize(len.max(ICMP_HEADER_LEN), 1);
+    if !v6 {
+        let sum = checksum(&buf);
+        buf[2..4].copy_from_slice(&sum.to_be_bytes());
+    }
+    buf.freeze()
+}
+
+/// Timestamp request, RFC 792, receive and transmit are left for the target.
+pub(super) fn timestamp_request(id: u16, seq: u16, originate: u32) -> Bytes {
+    let mut buf = BytesMut::with_capacity(ICMP_HEADER_LEN + 12);
+    buf.put_u8(ICMP_TIMESTAMP_REQUEST);
+    buf.put_u8(0);
+    buf.put_u16(0);
+    buf.put_u16(id);
+    buf.put_u16(seq);
+    buf.put_u32(originate);
+    buf.put_u32(0);
+    buf.put_u32(0);
+    let sum = checksum(&buf);
+    buf[2..4].copy_from_slice(&sum.to_be_bytes());
+    buf.freeze()
+}
+
+/// Set the two bytes at `at`, which must be zero, so the checksum of `buf`
+/// becomes `target`.
+fn compensate(buf: &mut [u8], at: usize, target: u16) {
+    let sum = u32::from(!target) + u32::from(checksum(buf));
+    let pad = (sum & 0xffff) + (sum >> 16);
+    buf[at..at + 2].copy_from_slice(&(pad as u16).to_be_bytes());
+}
+
+/// Echo request whose checksum is `flow` whatever `seq` is, the first two
+/// payload bytes make up for it. Load balancers hashing the ICMP header keep
+/// every probe of a flow on one path, see Paris traceroute. The ICMPv6
+/// pseudo header is the same for every probe, so its checksum is constant too.
+pub(super) fn flow_echo_request(v6: bool, id: u16, seq: u16, len: usize, flow: u16) -> Bytes {
+    let mut buf = BytesMut::from(&echo_request(v6, id, seq, len.max(ICMP_HEADER_LEN + 2))[..]);
+    buf[2..4].fill(0);
+    buf[ICMP_HEADER_LEN..ICMP_HEADER_LEN + 2].fill(0);
+    compensate(&mut buf, ICMP_HEADER_LEN, flow);
+    if !v6 {
+        buf[2..4].copy_from_slice(&flow.to_be_bytes());
+    }
+    buf.freeze()
+}
+
+/// Payload of a UDP datagram of `len` bytes from `src` to `dst` whose checksum,
+/// filled in by the kernel, is `seq`. Errors quote the UDP header, so the
+/// checksum tells probes apart while the ports stay those of the flow.
+pub(super) fn udp_payload(src: SocketAddr, dst: SocketAddr, len: usize, seq: u16) -> Bytes {
+    let len = len.max(UDP_HEADER_LEN + 2);
+    let mut buf = BytesMut::new();
+    match (src.ip(), dst.ip()) {
+        (IpAddr::V4(s), IpAddr::V4(d)) => {
+            buf.put_slice(&s.octets());
+            buf.put_slice(&d.octets());
+            buf.put_u16(u16::from(PROTO_UDP));
+            buf.put_u16(len as u16);
+        }
+        (s, d) => {
+            buf.put_slice(&to_v6(s).octets());
+            buf.put_slice(&to_v6(d).octets());
+            buf.put_u32(len as u32);
+            buf.put_u32(u32::from(PROTO_UDP));
+        }
+    }
+    let at = buf.len() + UDP_HEADER_LEN;
+    buf.put_u16(src.port());
+    buf.put_u16(dst.port());
+    buf.put_u16(len as u16);
+    buf.put_u16(0);
+    buf.put_u16(0);
+    buf.resize(at + len - UDP_HEADER_LEN, 1);
+    compensate(&mut buf, at, seq);
+    buf.split_off(at).freeze()
+}
+
+fn to_v6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+/// Parse an IPv4 packet read from a raw ICMP socket.
+pub(super) fn parse_v4(packet: &[u8]) -> Option<Message> {
+    let ihl = usize::from(packet.first()? & 0x0f) * 4;
+    let from: [u8; 4] = packet.get(12..16)?.try_into().ok()?;
+    let icmp = packet.get(ihl..)?;
+
+    let kind = match *icmp.first()? {
+        ICMP_ECHO_REPLY => Kind::EchoReply,
+        ICMP_TIME_EXCEEDED => Kind::TimeExceeded,
+        ICMP_UNREACHABLE if icmp.get(1) == Some(&ICMP_FRAG_NEEDED) => Kind::TooBig {
+            mtu: u32::from(u16_at(icmp, 6)?),
+        },
+        ICMP_UNREACHABLE => Kind::Unreachable {
+            code: *icmp.get(1)?,
+        },
+        _ => return None,
+    };
+    let probe = match kind {
+        Kind::EchoReply => Quoted::Echo {
+            id: u16_at(icmp, 4)?,
+            seq: u16_at(icmp, 6)?,
+        },
+        _ => quoted_v4(icmp.get(ICMP_HEADER_LEN..)?)?,
+    };
+    let mpls = match kind {
+        Kind::EchoReply => Vec::new(),
+        // The length is in 32 bit words.
+        _ => mpls(icmp, usize::from(icmp[5]) * 4),
+    };
+
+    Some(Message {
+        from: IpAddr::V4(Ipv4Addr::from(from)),
+        kind,
+        probe,
+        mpls,
+    })
+}
+
+/// Timestamps a target wrote into its timestamp reply, as on the wire.
+#[derive(Debug)]
+pub(super) struct TimestampReply {
+    pub(super) id: u16,
+    pub(super) seq: u16,
+    pub(super) receive: u32,
+    pub(super) transmit: u32,
+}
+
+/// Parse an IPv4 packet read from a raw ICMP socket, `None` unless it is a
+/// timestamp reply.
+pub(super) fn parse_timestamp_reply(packet: &[u8]) -> Option<TimestampReply> {
+    let ihl = usize::from(packet.first()? & 0x0f) * 4;
+    let icmp = packet.get(ihl..)?;
+    if *icmp.first()? != ICMP_TIMESTAMP_REPLY {
+        return None;
+    }
+
+    Some(TimestampReply {
+        id: u16_at(icmp, 4)?,
+        seq: u16_at(icmp, 6)?,
+        receive: u32_at(icmp, 12)?,
+        transmit: u32_at(icmp, 16)?,
+    })
+}
+
+fn quoted_v4(packet: &[u8]) -> Option<Quoted> {
+    let ihl = usize::from(packet.first()? & 0x0f) * 4;
+    let transport = packet.get(ihl..)?;
+    match *packet.get(9)? {
+        PROTO_ICMP if transport.first() == Some(&ICMP_ECHO_REQUEST) => Some(Quoted::Echo {
+            id: u16_at(transport, 4)?,
+            seq: u16_at(transport, 6)?,
+        }),
+        proto => quoted_transport(proto, transport),
+    }
+}
+
+/// Parse an ICMPv6 message read from a raw socket, which strips the IPv6
+/// header, so the sender is passed in.
+pub(super) fn parse_v6(icmp: &[u8], from: Ipv6Addr) -> Option<Message> {
+    let kind = match *icmp.first()? {
+        ICMPV6_ECHO_REPLY => Kind::EchoReply,
+        ICMPV6_TIME_EXCEEDED => Kind::TimeExceeded,
+        ICMPV6_PACKET_TOO_BIG => Kind::TooBig {
+            mtu: u32_at(icmp, 4)?,
+        },
+        ICMPV6_UNREACHABLE => Kind::Unreachable {
+            code: *icmp.get(1)?,
+        },
+        _ => return None,
+    };
+    let probe = match kind {
+        Kind::EchoReply => Quoted::Echo {
+            id: u16_at(icmp, 4)?,
+            seq: u16_at(icmp, 6)?,
+        },
+        _ => quoted_v6(icmp.get(ICMP_HEADER_LEN..)?)?,
+    };
+    let mpls = match kind {
+        // Packet too big has no length field, RFC 4884.
+        Kind::EchoReply | Kind::TooBig { .. } => Vec::new(),
+        // The length is in 64 bit words.
+        _ => mpls(icmp, usize::from(icmp[4]) * 8),
+    };
+
+    Some(Message {
+        from: IpAddr::V6(from),
+        kind,
+        probe,
+        mpls,
+    })
+}
+
+fn quoted_v6(packet: &[u8]) -> Option<Quoted> {
+    let transport = packet.get(IPV6_HEADER_LEN..)?;
+    match *packet.get(6)? {
+        PROTO_ICMPV6 if transport.first() == Some(&ICMPV6_ECHO_REQUEST) => Some(Quoted::Echo {
+            id: u16_at(transport, 4)?,
+            seq: u16_at(transport, 6)?,
+        }),
+        proto => quoted_transport(proto, transport),
+    }
+}
+
+/// UDP and TCP headers quoted by an error, only the first 8 bytes are
+/// guaranteed to be there.
+fn quoted_transport(proto: u8, transport: &[u8]) -> Option<Quoted> {
+    match proto {
+        PROTO_UDP => Some(Quoted::Udp {
+            src_port: u16_at(transport, 0)?,
+            checksum: u16_at(transport, 6)?,
+        }),
+        PROTO_TCP => Some(Quoted::Tcp {
+            src_port: u16_at(transport, 0)?,
+        }),
+        _ => None,
+    }
+}
+
+/// MPLS label stack of the RFC 4884 extension structure of an error quoting
+/// `quote_len` bytes, empty if there is none. Errors without a length may
+/// still carry extensions behind a 128 byte quote.
+fn mpls(icmp: &[u8], quote_len: usize) -> Vec<MplsLabel> {
+    let quote_len = match quote_len {
+        0 if icmp.len() >= ICMP_HEADER_LEN + COMPAT_QUOTE_LEN + EXTENSION_HEADER_LEN => {
+            COMPAT_QUOTE_LEN
+        }
+        0 => return Vec::new(),
+        len => len,
+    };
+    let Some(ext) = icmp.get(ICMP_HEADER_LEN + quote_len..) else {
+        return Vec::new();
+    };
+    let valid = ext.len() >= EXTENSION_HEADER_LEN
+        && ext[0] >> 4 == EXTENSION_VERSION
+        && (u16_at(ext, 2) == Some(0) || checksum(ext) == 0);
+    if !valid {
+        return Vec::new();
+    }
+
+    let mut labels = Vec::new();
+    let mut objects = &ext[EXTENSION_HEADER_LEN..];
+    while let Some(len) = u16_at(objects, 0) {
+        let len = usize::from(len);
+        if len < OBJECT_HEADER_LEN || len > objects.len() {
+            break;
+        }
+        if objects[2] == CLASS_MPLS && objects[3] == CTYPE_LABEL_STACK {
+            labels.extend(
+                objects[OBJECT_HEADER_LEN..len]
+                    .chunks_exact(4)
+                    .map(|entry| {
+                        MplsLabel::from_entry(u32::from_be_bytes(entry.try_into().unwrap()))
+                    }),
+            );
+        }
+        objects = &objects[len..];
+    }
+    labels
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ID: u16 = 0x1234;
+    const SEQ: u16 = 7;
+
+    fn entry(label: u32, s: bool, ttl: u8) -> u32 {
+        label << 12 | u32::from(s) << 8 | u32::from(ttl)
+    }
+
+    fn object(class: u8, ctype: u8, entries: &[u32]) -> Vec<u8> {
+        let len = OBJECT_HEADER_LEN + entries.len() * 4;
+        let mut object = vec![0, 0, class, ctype];
+        object[..2].copy_from_slice(&(len as u16).to_be_bytes());
+        object.extend(entries.iter().flat_map(|e| e.to_be_bytes()));
+        object
+    }
+
+    fn extension(objects: &[Vec<u8>]) -> Vec<u8> {
+        let mut ext = vec![EXTENSION_VERSION << 4, 0, 0, 0];
+        ext.extend(objects.concat());
+        resum(&mut ext);
+        ext
+    }
+
+    fn resum(ext: &mut [u8]) {
+        ext[2..4].fill(0);
+        let sum = checksum(ext);
+        ext[2..4].copy_from_slice(&sum.to_be_bytes());
+    }
+
+    /// Time exceeded quoting our echo request padded to `quote_len`, with
+    /// `words` in the length field.
+    fn time_exceeded_v4(words: u8, quote_len: usize, ext: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0; 20];
+        packet[0] = 0x45;
+        packet[9] = PROTO_ICMP;
+        packet[12..16].copy_from_slice(&[192, 0, 2, 1]);
+        packet.extend([ICMP_TIME_EXCEEDED, 0, 0, 0, 0, words, 0, 0]);
+
+        let mut quote = vec![0; 20];
+        quote[0] = 0x45;
+        quote[9] = PROTO_ICMP;
+        quote.extend([ICMP_ECHO_REQUEST, 0, 0, 0]);
+        quote.extend(ID.to_be_bytes());
+        quote.extend(SEQ.to_be_bytes());
+        quote.resize(quote_len, 0);
+        packet.extend(quote);
+        packet.extend(ext);
+        packet
+    }
+
+    fn icmpv6(kind: u8, words: u8, quote_len: usize, ext: &[u8]) -> Vec<u8> {
+        let mut icmp = vec![kind, 0, 0, 0, words, 0, 0, 0];
+        let mut quote = vec![0; IPV6_HEADER_LEN];
+        quote[6] = PROTO_ICMPV6;
+        quote.extend([ICMPV6_ECHO_REQUEST, 0, 0, 0]);
+        quote.extend(ID.to_be_bytes());
+        quote.extend(SEQ.to_be_bytes());
+        quote.resize(quote_len, 0);
+        icmp.extend(quote);
+        icmp.extend(ext);
+        icmp
+    }
+
+    fn labels(packet: &[u8]) -> Vec<MplsLabel> {
+        let message = parse_v4(packet).unwrap();
+        assert_eq!(message.kind, Kind::TimeExceeded);
+        assert_eq!(message.probe, Quoted::Echo { id: ID, seq: SEQ });
+        message.mpls
+    }
+
+    #[test]
+    fn single_label() {
+        let ext = extension(&[object(
+            CLASS_MPLS,
+            CTYPE_LABEL_STACK,
+            &[entry(16_004, true, 1)],
+        )]);
+        let mpls = labels(&time_exceeded_v4(32, 128, &ext));
+        assert_eq!(
+            mpls,
+            [MplsLabel {
+                label: 16_004,
+                tc: 0,
+                s: true,
+                ttl: 1
+            }]
+        );
+    }
+
+    #[test]
+    fn label_stack_after_other_objects() {
+        let stack = [
+            entry(100, false, 3),
+            entry(200, false, 2),
+            entry(300, true, 1),
+        ];
+        let ext = extension(&[
+            object(2, 1, &[0xdead_beef]),
+            object(CLASS_MPLS, CTYPE_LABEL_STACK, &stack),
+        ]);
+        let expected: Vec<_> = stack.into_iter().map(MplsLabel::from_entry).collect();
+        assert_eq!(labels(&time_exceeded_v4(32, 128, &ext)), expected);
+
+        // ICMPv6 counts the quote in 64 bit words.
+        let icmp = icmpv6(ICMPV6_TIME_EXCEEDED, 16, 128, &ext);
+        let message = parse_v6(&icmp, Ipv6Addr::LOCALHOST).unwrap();
+        assert_eq!(message.probe, Quoted::Echo { id: ID, seq: SEQ });
+        assert_eq!(message.mpls, expected);
+    }
+
+    #[test]
+    fn bad_checksum() {
+        let mut ext = extension(&[object(CLASS_MPLS, CTYPE_LABEL_STACK, &[entry(1, true, 1)])]);
+        ext[3] ^= 0xff;
+        assert!(labels(&time_exceeded_v4(32, 128, &ext)).is_empty());
+
+        // A zero checksum means none was computed.
+        ext[2..4].fill(0);
+        assert_eq!(labels(&time_exceeded_v4(32, 128, &ext)).len(), 1);
+    }
+
+    #[test]
+    fn length_past_the_end() {
+        let ext = extension(&[object(CLASS_MPLS, CTYPE_LABEL_STACK, &[entry(1, true, 1)])]);
+        let packet = time_exceeded_v4(32, 128, &ext);
+        // The quote would end behind the packet.
+        let mut long = packet.clone();
+        long[20 + 5] = 64;
+        assert!(labels(&long).is_empty());
+
+        // An object claiming more than is left ends the stack.
+        let mut ext = extension(&[
+            object(CLASS_MPLS, CTYPE_LABEL_STACK, &[entry(1, false, 1)]),
+            object(CLASS_MPLS, CTYPE_LABEL_STACK, &[entry(2, true, 1)]),
+        ]);
+        ext[12..14].copy_from_slice(&64_u16.to_be_bytes());
+        resum(&mut ext);
+        let mpls = labels(&time_exceeded_v4(32, 128, &ext));
+        assert_eq!(mpls, [MplsLabel::from_entry(entry(1, false, 1))]);
+
+        // Cut anywhere, the packet is dropped or read without extensions.
+        for len in 0..packet.len() {
+            if let Some(message) = parse_v4(&packet[..len]) {
+                assert!(len >= 20 + ICMP_HEADER_LEN + 28 && message.mpls.is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn zero_length_object() {
+        let mut ext = extension(&[]);
+        ext.extend(object(CLASS_MPLS, CTYPE_LABEL_STACK, &[entry(5, true, 9)]));
+        ext.extend([0, 0, CLASS_MPLS, CTYPE_LABEL_STACK]);
+        ext.extend(entry(6, true, 9).to_be_bytes());
+        resum(&mut ext);
+        assert_eq!(
+            labels(&time_exceeded_v4(32, 128, &ext)),
+            [MplsLabel::from_entry(entry(5, true, 9))]
+        );
+    }
+
+    #[test]
+    fn no_length_field() {
+        // Routers before RFC 4884 put the extensions behind 128 bytes.
+        let ext = extension(&[object(CLASS_MPLS, CTYPE_LABEL_STACK, &[entry(7, true, 1)])]);
+        assert_eq!(labels(&time_exceeded_v4(0, 128, &ext)).len(), 1);
+        // Shorter quotes carry no extensions.
+        assert!(labels(&time_exceeded_v4(0, 28, &[])).is_empty());
+        assert!(labels(&time_exceeded_v4(0, 128, &[])).is_empty());
+        // Nor does packet too big, which has the MTU where the length would be.
+        let icmp = icmpv6(ICMPV6_PACKET_TOO_BIG, 16, 128, &ext);
+        let message = parse_v6(&icmp, Ipv6Addr::LOCALHOST).unwrap();
+        assert!(message.mpls.is_empty());
+    }
+}
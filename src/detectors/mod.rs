@@ -1,8 +1,63 @@
+mod baseline;
 mod fping_detector;
+mod icmp;
+mod mtr_detector;
+mod path;
 mod ping_detector;
 mod pinger;
+mod pmtu_detector;
+mod reachability;
+mod rtt;
+mod scheduler;
 mod tcp_ping_detector;
+mod timestamp_detector;
+mod tracer;
+mod twamp_detector;
+mod variation;
+mod wheel;
 
 pub use fping_detector::FpingDetector;
+pub use mtr_detector::MtrDetector;
 pub use ping_detector::PingDetector;
+pub use pmtu_detector::PmtuDetector;
 pub use tcp_ping_detector::TcpPingDetector;
+pub use timestamp_detector::TimestampDetector;
+pub use twamp_detector::TwampDetector;
+
+use crate::commander::Command;
+use crate::reporter::Report;
+use std::future::Future;
+use tokio::sync::mpsc;
+use tracing::info;
+
+pub type CommandRx<C> = mpsc::Receiver<Vec<C>>;
+pub type ResultTx<R> = mpsc::Sender<R>;
+
+/// A probe type. The controller pushes `Command` sets, the detector turns them
+/// into probes and emits a `Result` for every probe it runs.
+pub trait Detector: Sized + Send + 'static {
+    type Command: Command;
+    type Result: Report;
+
+    /// Replace everything started by previous commands with probes for `commands`.
+    fn apply(
+        &mut self,
+        commands: Vec<Self::Command>,
+        result_tx: &ResultTx<Self::Result>,
+    ) -> impl Future<Output = ()> + Send;
+
+    fn run(
+        mut self,
+        mut command_rx: CommandRx<Self::Command>,
+        result_tx: ResultTx<Self::Result>,
+    ) -> impl Future<Output = ()> + Send {
+        async move {
+            loop {
+                let commands = command_rx.recv().await.expect("Command rx fail");
+                info!("Recv {} commands", Self::Command::NAME);
+
+                self.apply(commands, &result_tx).await;
+            }
+        }
+    }
+}
@@ -0,0 +1,345 @@
+use super::path;
+use super::tracer::{Hop, Tracer};
+use super::{CommandRx, Detector, ResultTx};
+use crate::budget::Budget;
+use crate::commander::Command;
+use crate::conf;
+use crate::events::{Event, EventRx, EventTx};
+use crate::grpc::collector_grpc::ProbeType;
+use crate::rdns::Resolver;
+use crate::structures::{
+    MtrCommand, MtrResult, PathChange, StateChange, TargetState, Trace, TraceMode, TraceProtocol,
+};
+use futures::future::join_all;
+use std::collections::{HashMap, HashSet};
+use std::future;
+use std::io;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::net;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::Semaphore;
+use tokio::time::{self, Duration, Instant, MissedTickBehavior};
+use tracing::{info, warn};
+
+type TriggerKey = (ProbeType, u64);
+
+/// Runs traces commanded by the controller, and when enabled traces toward
+/// ping and tcp ping targets as soon as they degrade. Watched destinations are
+/// traced periodically and only reported when their path changes.
+pub struct MtrDetector {
+    budget: Budget,
+    conf: conf::Traceroute,
+    event_tx: EventTx,
+    events: Option<EventRx>,
+    triggered: HashMap<TriggerKey, Instant>,
+    rdns: Option<Resolver>,
+    /// Bounds the commanded and triggered traces running at once.
+    traces: Arc<Semaphore>,
+}
+
+impl MtrDetector {
+    pub fn new(
+        budget: Budget,
+        conf: &conf::Traceroute,
+        event_tx: &EventTx,
+        rdns: Option<Resolver>,
+    ) -> Self {
+        Self {
+            budget,
+            conf: conf.clone(),
+            event_tx: event_tx.clone(),
+            events: conf.trigger.then(|| event_tx.subscribe()),
+            triggered: HashMap::new(),
+            rdns,
+            traces: Arc::new(Semaphore::new(conf.max_traces.max(1))),
+        }
+    }
+
+    fn command(conf: &conf::Traceroute, ip: IpAddr) -> MtrCommand {
+        MtrCommand {
+            version: String::new(),
+            ip,
+            times: conf.times,
+            hop_limit: conf.hop_limit,
+            timeout: Duration::from_millis(conf.timeout_ms),
+            mode: conf.mode,
+            flows: conf.flows,
+            protocol: conf.protocol,
+            port: conf.port,
+            fixed_port: conf.fixed_port,
+        }
+    }
+
+    /// Every hop of every round of `command`. Rounds of all flows are told
+    /// apart by their number, so there are at most 255 of them.
+    async fn hops(command: &MtrCommand, budget: &Budget) -> io::Result<Vec<Hop>> {
+        let mut tracer = Tracer::new(command)?;
+        let hop_limit = command.hop_limit.clamp(1, u32::from(u8::MAX)) as u8;
+        let times = command.times.clamp(1, u32::from(u8::MAX));
+        let paris = Self::is_paris(command);
+        let flows = if paris {
+            command.flows.clamp(1, u32::from(u8::MAX) / times)
+        } else {
+            1
+        };
+        let mut hops = Vec::new();
+        for flow in 0..flows {
+            let flow_id = paris.then_some(flow as u16);
+            for round in 0..times {
+                let round = (flow * times + round) as u8;
+                hops.extend(
+                    tracer
+                        .round(round, hop_limit, command.timeout, flow_id, budget)
+                        .await?,
+                );
+            }
+        }
+        Ok(hops)
+    }
+
+    /// Every TCP probe has a source port of its own, so it never keeps a flow.
+    fn is_paris(command: &MtrCommand) -> bool {
+        command.mode == TraceMode::Paris && command.protocol != TraceProtocol::Tcp
+    }
+
+    /// Host names of every address answering in `hops`, looked up at once.
+    async fn hostnames(rdns: &Resolver, hops: &[Hop]) -> HashMap<IpAddr, String> {
+        let ips: HashSet<_> = hops.iter().filter_map(|hop| hop.from).collect();
+        let names = join_all(
+            ips.into_iter()
+                .map(|ip| async move { rdns.lookup(ip).await.map(|name| (ip, name)) }),
+        )
+        .await;
+        names.into_iter().flatten().collect()
+    }
+
+    async fn trace(
+        command: MtrCommand,
+        trigger: Option<TriggerKey>,
+        budget: Budget,
+        rdns: Option<Resolver>,
+        traces: Arc<Semaphore>,
+        result_tx: ResultTx<Trace>,
+    ) {
+        let permit = traces.acquire().await.expect("Acquire trace permit fail");
+        let start_at = SystemTime::now();
+        let hops = Self::hops(&command, &budget).await;
+        drop(permit);
+        let hops = match hops {
+            Ok(hops) => hops,
+            Err(e) => {
+                warn!("Trace to {} fail, err:{}", command.ip, e);
+                return;
+            }
+        };
+        let paths = if Self::is_paris(&command) {
+            path::set(&hops)
+        } else {
+            Vec::new()
+        };
+        let hostnames = match &rdns {
+            Some(rdns) => Self::hostnames(rdns, &hops).await,
+            None => HashMap::new(),
+        };
+        let results = hops
+            .into_iter()
+            .map(|hop| MtrResult {
+                hop: u32::from(hop.ttl),
+                ip: hop.from.map(|ip| ip.to_string()).unwrap_or_default(),
+                is_timeout: hop.from.is_none(),
+                rtt: hop.rtt,
+                flow: u32::from(hop.flow),
+                mpls: hop.mpls,
+                origin: None,
+                hostname: hop.from.and_then(|ip| hostnames.get(&ip).cloned()),
+            })
+            .collect();
+
+        let trace = Trace {
+            trigger,
+            version: command.version,
+            ip: command.ip,
+            start_at,
+            results,
+            paths,
+            origin: None,
+        };
+        result_tx.send(trace).await.expect("Send mtr result fail");
+    }
+
+    /// Re-trace `target` forever and publish a path change whenever its hops
+    /// differ from the previous trace. The first trace only sets the path, as
+    /// does one toward a newly resolved address. Of a Paris trace only the
+    /// first flow is compared.
+    async fn watch(target: String, conf: conf::Traceroute, budget: Budget, event_tx: EventTx) {
+        let mut timer = time::interval(Duration::from_secs(conf.watch_interval_secs.max(1)));
+        timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut last: Option<(IpAddr, Vec<Option<IpAddr>>)> = None;
+        loop {
+            timer.tick().await;
+            let Some(ip) = Self::resolve(&target).await else {
+                warn!("Resolve watched target:{} fail", target);
+                continue;
+            };
+            let hops = match Self::hops(&Self::command(&conf, ip), &budget).await {
+                Ok(hops) => hops,
+                Err(e) => {
+                    warn!("Trace to watched target:{} fail, err:{}", target, e);
+                    continue;
+                }
+            };
+
+            let new = path::from_hops(hops.iter().filter(|hop| hop.flow == 0));
+            let Some((old_ip, old)) = last.replace((ip, new.clone())) else {
+                info!("Watch path to target:{}, {} hops", target, new.len());
+                continue;
+            };
+            // Paths toward another address are not comparable.
+            if old_ip != ip {
+                info!(
+                    "Watched target:{} resolved to {} instead of {}, {} hops",
+                    target,
+                    ip,
+                    old_ip,
+                    new.len()
+                );
+                continue;
+            }
+            let diff = path::diff(&old, &new);
+            if diff.is_empty() {
+                continue;
+            }
+
+            info!(
+                "Path to target:{} changed, {} hops differ",
+                target,
+                diff.len()
+            );
+            let change = PathChange {
+                target: target.clone(),
+                ip,
+                at: SystemTime::now(),
+                old,
+                new,
+                diff,
+            };
+            // Nobody listening is fine, events are best effort.
+            let _ = event_tx.send(Event::PathChange(change));
+        }
+    }
+
+    /// Tcp ping targets are `host:port`, ping targets plain addresses.
+    async fn resolve(target: &str) -> Option<IpAddr> {
+        if let Ok(ip) = target.parse() {
+            return Some(ip);
+        }
+        net::lookup_host(target)
+            .await
+            .ok()?
+            .next()
+            .map(|addr| addr.ip())
+    }
+
+    fn on_event(&mut self, event: Event, result_tx: &ResultTx<Trace>) {
+        let Event::State(change) = event else {
+            return;
+        };
+        if !matches!(change.to, TargetState::Degraded | TargetState::Down) {
+            return;
+        }
+
+        let interval = Duration::from_secs(self.conf.trigger_interval_secs);
+        self.triggered.retain(|_, at| at.elapsed() < interval);
+        let key = (change.probe_type, change.id);
+        if self.triggered.contains_key(&key) {
+            return;
+        }
+        self.triggered.insert(key, Instant::now());
+
+        info!(
+            "Trace to target:{} of id:{}, {:?} -> {:?}",
+            change.target, change.id, change.from, change.to
+        );
+        tokio::spawn(Self::trigger(
+            change,
+            self.conf.clone(),
+            self.budget.clone(),
+            self.rdns.clone(),
+            self.traces.clone(),
+            result_tx.clone(),
+        ));
+    }
+
+    async fn trigger(
+        change: StateChange,
+        conf: conf::Traceroute,
+        budget: Budget,
+        rdns: Option<Resolver>,
+        traces: Arc<Semaphore>,
+        result_tx: ResultTx<Trace>,
+    ) {
+        let Some(ip) = Self::resolve(&change.target).await else {
+            warn!("Resolve trace target:{} fail", change.target);
+            return;
+        };
+
+        let command = Self::command(&conf, ip);
+        let trigger = Some((change.probe_type, change.id));
+        Self::trace(command, trigger, budget, rdns, traces, result_tx).await;
+    }
+}
+
+impl Detector for MtrDetector {
+    type Command = MtrCommand;
+    type Result = Trace;
+
+    async fn apply(&mut self, commands: Vec<MtrCommand>, result_tx: &ResultTx<Trace>) {
+        for command in commands {
+            info!("Start trace to {}, version:{}", command.ip, command.version);
+            tokio::spawn(Self::trace(
+                command,
+                None,
+                self.budget.clone(),
+                self.rdns.clone(),
+                self.traces.clone(),
+                result_tx.clone(),
+            ));
+        }
+    }
+
+    async fn run(mut self, mut command_rx: CommandRx<MtrCommand>, result_tx: ResultTx<Trace>) {
+        for target in &self.conf.watch {
+            tokio::spawn(Self::watch(
+                target.clone(),
+                self.conf.clone(),
+                self.budget.clone(),
+                self.event_tx.clone(),
+            ));
+        }
+
+        let mut events = self.events.take();
+        loop {
+            let event = async {
+                match &mut events {
+                    Some(events) => events.recv().await,
+                    None => future::pending().await,
+                }
+            };
+
+            tokio::select! {
+                commands = command_rx.recv() => {
+                    let commands = commands.expect("Command rx fail");
+                    info!("Recv {} commands", MtrCommand::NAME);
+                    self.apply(commands, &result_tx).await;
+                }
+                event = event => match event {
+                    Ok(event) => self.on_event(event, &result_tx),
+                    Err(RecvError::Lagged(n)) => warn!("Trace trigger lagged, drop {} events", n),
+                    Err(RecvError::Closed) => return,
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,78 @@
+use super::tracer::Hop;
+use crate::structures::{FlowPath, HopChange, HopDiff};
+use std::net::IpAddr;
+
+/// Hop sequence of the rounds of one trace, indexed by TTL - 1. Every hop is
+/// the address answering most often for its TTL, the first one on a tie, and
+/// `None` if nobody answered. Unanswered hops at the end are cut off.
+pub(super) fn from_hops<'a>(hops: impl IntoIterator<Item = &'a Hop>) -> Vec<Option<IpAddr>> {
+    let mut seen: Vec<Vec<(IpAddr, u32)>> = Vec::new();
+    for hop in hops {
+        let Some(from) = hop.from else { continue };
+        let ttl = usize::from(hop.ttl);
+        if seen.len() < ttl {
+            seen.resize(ttl, Vec::new());
+        }
+        let seen = &mut seen[ttl - 1];
+        match seen.iter_mut().find(|(ip, _)| *ip == from) {
+            Some((_, n)) => *n += 1,
+            None => seen.push((from, 1)),
+        }
+    }
+
+    let mut path: Vec<_> = seen
+        .into_iter()
+        .map(|seen| {
+            seen.into_iter()
+                .rev()
+                .max_by_key(|(_, n)| *n)
+                .map(|(ip, _)| ip)
+        })
+        .collect();
+    while path.last() == Some(&None) {
+        path.pop();
+    }
+    path
+}
+
+/// Distinct paths of the flows of a Paris trace, ordered by their first flow.
+pub(super) fn set(hops: &[Hop]) -> Vec<FlowPath> {
+    let mut flows: Vec<_> = hops.iter().map(|hop| hop.flow).collect();
+    flows.sort_unstable();
+    flows.dedup();
+
+    let mut paths: Vec<FlowPath> = Vec::new();
+    for flow in flows {
+        let path = from_hops(hops.iter().filter(|hop| hop.flow == flow));
+        match paths.iter_mut().find(|p| p.hops == path) {
+            Some(p) => p.flows.push(u32::from(flow)),
+            None => paths.push(FlowPath {
+                flows: vec![u32::from(flow)],
+                hops: path,
+            }),
+        }
+    }
+    paths
+}
+
+/// Hops that differ between `old` and `new`. A hop which did not answer on
+/// either side is unknown rather than changed, so a silent router does not
+/// flap the path.
+pub(super) fn diff(old: &[Option<IpAddr>], new: &[Option<IpAddr>]) -> Vec<HopDiff> {
+    (0..old.len().max(new.len()))
+        .filter_map(|i| {
+            let change = match (old.get(i), new.get(i)) {
+                (Some(Some(from)), Some(Some(to))) if from != to => HopChange::Changed,
+                (None, Some(Some(_))) => HopChange::Added,
+                (Some(Some(_)), None) => HopChange::Removed,
+                _ => return None,
+            };
+            Some(HopDiff {
+                hop: i as u32 + 1,
+                change,
+                from: old.get(i).copied().flatten(),
+                to: new.get(i).copied().flatten(),
+            })
+        })
+        .collect()
+}
@@ -1,84 +1,110 @@
 use super::pinger::Pinger;
+use super::scheduler::{FromOutcome, Outcome, Scheduler, SchedulerHandle, Subscriber, Target};
+use super::{Detector, ResultTx};
+use crate::budget::Budget;
+use crate::conf;
+use crate::events::EventTx;
+use crate::grpc::collector_grpc::ProbeType;
 use crate::structures::{PingCommand, PingResult};
-use tokio::sync::broadcast;
-use tokio::sync::mpsc;
-use tokio::task;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::sync::Arc;
 use tracing::info;
 
-type CommandRx = mpsc::Receiver<Vec<PingCommand>>;
-type ResultTx = mpsc::Sender<PingResult>;
-type ExitSignalTx = broadcast::Sender<()>;
-type ExitedTx = mpsc::Sender<()>;
-type ExitedRx = mpsc::Receiver<()>;
+impl FromOutcome for PingResult {
+    fn from_outcome(outcome: &Outcome) -> Self {
+        Self {
+            id: outcome.id,
+            is_timeout: outcome.sent && outcome.rtt.is_none(),
+            send_at: outcome.send_at,
+            rtt: outcome.rtt,
+            not_sent: !outcome.sent,
+            timeout: outcome.timeout,
+            interval: outcome.interval,
+            ipdv: outcome.ipdv,
+            jitter: outcome.jitter,
+            reordered: outcome.reordered,
+            loss_run: outcome.loss_run,
+            report: outcome.report,
+            ip: outcome.ip,
+            origin: None,
+        }
+    }
+}
 
 pub struct PingDetector {
-    exited_tx: ExitedTx,
-    exited_rx: ExitedRx,
-    exit_signal_tx: ExitSignalTx,
+    budget: Budget,
+    conf: conf::Detector,
+    event_tx: EventTx,
+    scheduler: Option<SchedulerHandle<Pinger>>,
 }
 
 impl PingDetector {
-    pub fn new() -> Self {
-        let (exited_tx, exited_rx) = mpsc::channel(10);
-        let (exit_signal_tx, _) = broadcast::channel(1);
+    pub fn new(budget: Budget, conf: &conf::Detector, event_tx: EventTx) -> Self {
         Self {
-            exited_tx,
-            exited_rx,
-            exit_signal_tx,
-        }
-    }
-
-    async fn stop_all_ping_task(&mut self) {
-        let total = self.exit_signal_tx.receiver_count();
-        if total == 0 {
-            info!("No ping task need to be stop");
-            return;
-        }
-
-        info!("Stop ping tasks. total {}", total);
-        self.exit_signal_tx
-            .send(())
-            .expect("Broadcast stop ping task signal fail");
-        let mut completed_num = 0;
-        loop {
-            self.exited_rx.recv().await.expect("Recv ping exited fail");
-            completed_num += 1;
-            if completed_num == total {
-                info!("All ping tasks have been stopped");
-                return;
-            }
+            budget,
+            conf: conf.clone(),
+            event_tx,
+            scheduler: None,
         }
     }
+}
 
-    pub async fn detect(mut self, mut command_rx: CommandRx, result_tx: ResultTx) {
-        loop {
-            let commands = command_rx.recv().await.expect("Command rx fail");
-            info!("Recv ping commands");
+impl Detector for PingDetector {
+    type Command = PingCommand;
+    type Result = PingResult;
 
-            self.stop_all_ping_task().await;
+    async fn apply(&mut self, commands: Vec<PingCommand>, result_tx: &ResultTx<PingResult>) {
+        let total = commands.len();
+        info!("Start ping targets, total {}", total);
 
-            if commands.is_empty() {
-                info!("Commands is empty, noting to do");
-                continue;
+        // Commands probing the same destination the same way share one stream.
+        let mut streams: HashMap<_, usize> = HashMap::new();
+        let mut targets: Vec<Target<Pinger>> = Vec::new();
+        for command in &commands {
+            let key = (
+                command.ip,
+                command.dscp,
+                command.size,
+                command.interval,
+                command.sampling,
+                command.escalation,
+            );
+            let subscriber = Subscriber {
+                id: command.id,
+                timeout: command.timeout,
+                adaptive: command.adaptive_timeout,
+                report: command.report,
+                anomaly_sensitivity: command.anomaly_sensitivity,
+            };
+            match streams.entry(key) {
+                Entry::Occupied(e) => targets[*e.get()].subscribers.push(subscriber),
+                Entry::Vacant(e) => {
+                    e.insert(targets.len());
+                    targets.push(Target {
+                        subscribers: vec![subscriber],
+                        probe: Arc::new(Pinger::from_ping_command(command)),
+                        interval: command.interval,
+                        sampling: command.sampling,
+                        escalation: command.escalation,
+                    });
+                }
             }
+        }
+        info!("Ping targets share {} probe streams", targets.len());
 
-            let total = commands.len();
-
-            info!("Start ping tasks, total {}", commands.len());
-
-            for command in commands {
-                let result_tx = result_tx.clone();
-                let exit_signal_rx = self.exit_signal_tx.subscribe();
-                let exited_tx = self.exited_tx.clone();
-                task::spawn(async move {
-                    let pinger = Pinger::from_ping_command(&command);
-                    pinger
-                        .loop_ping(command.interval, result_tx, exit_signal_rx, exited_tx)
-                        .await;
-                });
-            }
+        let scheduler = self.scheduler.get_or_insert_with(|| {
+            Scheduler::spawn(
+                "ping",
+                ProbeType::Ping,
+                self.budget.clone(),
+                &self.conf,
+                result_tx.clone(),
+                self.event_tx.clone(),
+            )
+        });
+        scheduler.replace(targets).await;
 
-            info!("All ping tasks was started, total {}", total)
-        }
+        info!("All ping targets was scheduled, total {}", total)
     }
 }
@@ -1,25 +1,19 @@
+use super::scheduler::{FromOutcome, Outcome, Probe, Sample};
 use crate::structures::{FPingCommand, PingCommand, PingResult};
 use bytes::{Buf, BufMut, Bytes, BytesMut};
 use socket2::{Protocol, SockAddr, Socket, Type};
 use std::{
     io::{Read, Result},
     net::{IpAddr, SocketAddrV4, SocketAddrV6},
-    num::Wrapping,
-};
-use tokio::{
-    io::unix::AsyncFd,
-    sync::broadcast,
-    sync::broadcast::error::TryRecvError,
-    time,
-    time::{Duration, MissedTickBehavior},
+    sync::atomic::{AtomicU32, Ordering},
 };
+use tokio::{io::unix::AsyncFd, time, time::Duration};
 use tracing::info;
 
 const PING_PACKET_LEN: usize = 64;
-
-type ResultTx = tokio::sync::mpsc::Sender<PingResult>;
-type ExitSignalRx = broadcast::Receiver<()>;
-type ExitedTx = tokio::sync::mpsc::Sender<()>;
+const NONE_RECEIVED: u32 = u32::MAX;
+/// Echo header, the sequence number sits at bytes 6..8.
+const ICMP_HEADER_LEN: usize = 8;
 
 pub(super) enum Domain {
     V4,
@@ -32,28 +26,30 @@ pub(super) struct Pinger {
     timeout: Duration,
     dst: (SockAddr, String),
     len: usize,
+    /// Newest reply sequence seen, `NONE_RECEIVED` before the first one.
+    newest_received: AtomicU32,
 }
 
 impl Pinger {
     pub(super) fn from_ping_command(comm: &PingCommand) -> Self {
-        Self::new(comm.id, comm.ip, comm.timeout, PING_PACKET_LEN)
+        Self::new(comm.id, comm.ip, comm.timeout, comm.size, comm.dscp)
     }
 
     pub(super) fn from_fping_command(comm: &FPingCommand) -> Self {
-        Self::new(comm.id, comm.ip, comm.timeout, PING_PACKET_LEN)
+        Self::new(comm.id, comm.ip, comm.timeout, PING_PACKET_LEN, comm.dscp)
     }
 
-    pub(super) fn new(id: u64, ip: IpAddr, timeout: Duration, len: usize) -> Self {
+    pub(super) fn new(id: u64, ip: IpAddr, timeout: Duration, len: usize, dscp: u32) -> Self {
         let (dst, sock) = match ip {
             IpAddr::V4(ip) => {
                 let dst = SocketAddrV4::new(ip, 0);
-                let sock = PingSocket::new(Domain::V4).expect("Get socket fail");
+                let sock = PingSocket::new(Domain::V4, dscp).expect("Get socket fail");
 
                 (SockAddr::from(dst), sock)
             }
             IpAddr::V6(ip) => {
                 let dst = SocketAddrV6::new(ip, 0, 0, 0);
-                let sock = PingSocket::new(Domain::V6).expect("Get socket fail");
+                let sock = PingSocket::new(Domain::V6, dscp).expect("Get socket fail");
 
                 (SockAddr::from(dst), sock)
             }
@@ -67,68 +63,88 @@ impl Pinger {
             timeout,
             dst,
             len,
+            newest_received: AtomicU32::new(NONE_RECEIVED),
         }
     }
 
-    pub(super) async fn loop_ping(
-        &self,
-        interval: Duration,
-        result_tx: ResultTx,
-        mut rx: ExitSignalRx,
-        tx: ExitedTx,
-    ) {
-        let mut seq = Wrapping(0_u16);
+    pub(super) async fn ping(&self, seq: u16) -> Result<PingResult> {
+        let sample = self.sample(seq, self.timeout).await?;
+        let outcome = Outcome {
+            id: self.id,
+            send_at: sample.send_at,
+            rtt: sample.rtt,
+            sent: true,
+            timeout: self.timeout,
+            interval: Duration::ZERO,
+            ipdv: None,
+            jitter: Duration::ZERO,
+            reordered: sample.reordered,
+            loss_run: u32::from(sample.rtt.is_none()),
+            report: None,
+            ip: self.ip(),
+        };
+        Ok(PingResult::from_outcome(&outcome))
+    }
 
-        let mut interval = time::interval(interval);
-        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    async fn sample(&self, seq: u16, timeout: Duration) -> Result<Sample> {
+        self.sock.send_request(seq, self.len, &self.dst.0).await?;
 
-        loop {
-            interval.tick().await;
+        let send_at_sys = std::time::SystemTime::now();
+        let send_at = std::time::Instant::now();
+        let mut stray = Vec::new();
+        let result = time::timeout(timeout, self.sock.recv_reply(seq, self.len, &mut stray)).await;
 
-            seq += Wrapping(1);
+        let rtt = match result {
+            Ok(Ok(())) => Some(send_at.elapsed()),
+            Ok(Err(e)) => return Err(e),
+            Err(_) => None,
+        };
 
-            let result = self.ping(seq.0).await.expect("Send/Recv ping fail");
+        Ok(Sample {
+            send_at: send_at_sys,
+            rtt,
+            reordered: self.track_received(&stray, rtt.map(|_| seq)),
+        })
+    }
 
-            result_tx.send(result).await.expect("Send result fail");
+    /// Count the replies read for this probe which are reordered, and
+    /// remember the newest received sequence. Only this probe is in flight,
+    /// so earlier ones can only show up reordered once they timed out.
+    fn track_received(&self, stray: &[u16], replied: Option<u16>) -> u32 {
+        let newest = self.newest_received.load(Ordering::Relaxed);
+        let (newest, reordered) = reordered(newest, stray.iter().chain(replied.iter()).copied());
+        self.newest_received.store(newest, Ordering::Relaxed);
 
-            match rx.try_recv() {
-                Ok(_) => {
-                    tx.send(()).await.expect("Send exited signal fail");
-                    return;
-                }
-                Err(TryRecvError::Closed) | Err(TryRecvError::Lagged(_)) => {
-                    panic!("Recv exit signal fail");
-                }
-                Err(broadcast::error::TryRecvError::Empty) => (),
-            }
+        reordered
+    }
+}
+
+/// Count arrivals older than one already received, as RFC 4737 defines
+/// reordering. Returns the newest sequence received and the count.
+fn reordered(mut newest: u32, arrivals: impl Iterator<Item = u16>) -> (u32, u32) {
+    let mut reordered = 0;
+    for seq in arrivals {
+        let seq = u32::from(seq);
+        if newest == NONE_RECEIVED || (seq.wrapping_sub(newest) as u16 as i16) > 0 {
+            newest = seq;
+        } else {
+            reordered += 1;
         }
     }
+    (newest, reordered)
+}
 
-    pub(super) async fn ping(&self, seq: u16) -> Result<PingResult> {
-        self.sock.send_request(seq, self.len, &self.dst.0).await?;
+impl Probe for Pinger {
+    fn target(&self) -> &str {
+        &self.dst.1
+    }
 
-        let send_at_sys = std::time::SystemTime::now();
-        let send_at = std::time::Instant::now();
-        let result = time::timeout(self.timeout, self.sock.recv_reply(seq, self.len)).await;
-
-        match result {
-            Ok(Ok(())) => {
-                let rtt = send_at.elapsed();
-                Ok(PingResult {
-                    id: self.id,
-                    is_timeout: false,
-                    send_at: send_at_sys,
-                    rtt: Some(rtt),
-                })
-            }
-            Ok(Err(e)) => Err(e),
-            Err(_) => Ok(PingResult {
-                id: self.id,
-                is_timeout: true,
-                send_at: send_at_sys,
-                rtt: None,
-            }),
-        }
+    fn ip(&self) -> Option<IpAddr> {
+        self.dst.0.as_socket().map(|addr| addr.ip())
+    }
+
+    async fn probe(&self, seq: u16, timeout: Duration) -> Result<Sample> {
+        self.sample(seq, timeout).await
     }
 }
 
@@ -137,13 +153,23 @@ pub(crate) struct PingSocket {
 }
 
 impl PingSocket {
-    pub(super) fn new(domain: Domain) -> Result<Self> {
-        let (domain, protocol) = match domain {
-            Domain::V4 => (socket2::Domain::IPV4, Some(Protocol::ICMPV4)),
-            Domain::V6 => (socket2::Domain::IPV6, Some(Protocol::ICMPV6)),
+    /// Requests are marked with `dscp`, the ECN bits are left clear.
+    pub(super) fn new(domain: Domain, dscp: u32) -> Result<Self> {
+        let tos = (dscp & 0x3f) << 2;
+        let inner = match domain {
+            Domain::V4 => {
+                let inner =
+                    Socket::new(socket2::Domain::IPV4, Type::DGRAM, Some(Protocol::ICMPV4))?;
+                inner.set_tos(tos)?;
+                inner
+            }
+            Domain::V6 => {
+                let inner =
+                    Socket::new(socket2::Domain::IPV6, Type::DGRAM, Some(Protocol::ICMPV6))?;
+                inner.set_tclass_v6(tos)?;
+                inner
+            }
         };
-        let dgram = Type::DGRAM;
-        let inner = Socket::new(domain, dgram, protocol)?;
         inner.set_nonblocking(true)?;
         let inner = AsyncFd::new(inner)?;
         Ok(Self { inner })
@@ -194,23 +220,63 @@ impl PingSocket {
         Ok(())
     }
 
-    pub(super) async fn recv_reply(&self, expect_seq: u16, len: usize) -> Result<()> {
+    /// Wait for the reply of `expect_seq`, sequences of other replies read in
+    /// the meantime are pushed to `stray`.
+    pub(super) async fn recv_reply(
+        &self,
+        expect_seq: u16,
+        len: usize,
+        stray: &mut Vec<u16>,
+    ) -> Result<()> {
         loop {
             let mut buf = BytesMut::with_capacity(len);
             buf.resize(len, 0);
             let reply_len = self.read(&mut buf).await?;
-            if reply_len != len {
+            if reply_len != len || reply_len < ICMP_HEADER_LEN {
                 info!("Recv packet len:{} less than expect len:{}", reply_len, len);
                 continue;
             }
             let buf = buf.freeze();
-            let seq = buf.slice(6..9).get_u16();
+            let seq = buf.slice(6..8).get_u16();
             if seq == expect_seq {
                 return Ok(());
             } else {
                 info!("Recv packet seq:{} != expect seq:{}", seq, expect_seq);
+                stray.push(seq);
                 continue;
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn count_reordered_arrivals() {
+        let (newest, n) = reordered(NONE_RECEIVED, [1, 2, 4, 3, 5].into_iter());
+        assert_eq!((newest, n), (5, 1));
+        // Each arrival older than the newest counts, not only the first.
+        assert_eq!(reordered(NONE_RECEIVED, [5, 1, 2, 3].into_iter()), (5, 3));
+        // Gaps of lost probes are not reordering.
+        assert_eq!(reordered(NONE_RECEIVED, [1, 4, 9].into_iter()), (9, 0));
+        // Duplicates of the newest count as well.
+        assert_eq!(reordered(7, [7].into_iter()), (7, 1));
+    }
+
+    #[test]
+    fn reordered_across_wrap() {
+        assert_eq!(reordered(65_534, [65_535, 0, 1].into_iter()), (1, 0));
+        assert_eq!(reordered(1, [65_535, 2].into_iter()), (2, 1));
+    }
+
+    #[test]
+    fn late_reply_read_with_next_probe() {
+        // Probe 2 timed out, its reply is read while waiting for probe 3,
+        // then the reply of 1 turns up even later.
+        let (newest, n) = reordered(1, [2, 3].into_iter());
+        assert_eq!((newest, n), (3, 0));
+        assert_eq!(reordered(newest, [1, 4].into_iter()), (4, 1));
+    }
+}
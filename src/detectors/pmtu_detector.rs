@@ -0,0 +1,254 @@
+use super::icmp::{self, Kind, Quoted};
+use super::{Detector, ResultTx};
+use crate::budget::Budget;
+use crate::structures::{PmtuCommand, PmtuDrop, PmtuResult};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io::{self, Result};
+use std::mem;
+use std::net::{IpAddr, SocketAddr};
+use std::os::fd::AsRawFd;
+use std::time::SystemTime;
+use tokio::net::UdpSocket;
+use tokio::time::{self, Duration, Instant};
+use tracing::{info, warn};
+
+const IPV4_HEADER_LEN: u16 = 20;
+const IPV6_HEADER_LEN: u16 = 40;
+/// Probes of one size lost in a row before it counts as dropped, so a lost
+/// packet is not taken for a blackhole.
+const PROBE_TRIES: u32 = 2;
+/// Replies are only read for their headers, longer ones are truncated.
+const RECV_BUFFER: usize = 2048;
+
+/// What became of the probes of one size.
+enum Answer {
+    Passed,
+    Dropped(PmtuDrop),
+}
+
+/// Set DF on everything sent and ignore the path MTU the kernel learned, so
+/// every size reaches the wire as is and only the path decides.
+fn set_pmtudisc_probe(sock: &Socket, dst: IpAddr) -> Result<()> {
+    let (level, name, value) = match dst {
+        IpAddr::V4(_) => (
+            libc::IPPROTO_IP,
+            libc::IP_MTU_DISCOVER,
+            libc::IP_PMTUDISC_PROBE,
+        ),
+        IpAddr::V6(_) => (
+            libc::IPPROTO_IPV6,
+            libc::IPV6_MTU_DISCOVER,
+            libc::IPV6_PMTUDISC_PROBE,
+        ),
+    };
+    // SAFETY: the option value is a c_int living across the call.
+    let ret = unsafe {
+        libc::setsockopt(
+            sock.as_raw_fd(),
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Sends echo requests of a given IP packet size with DF set toward one
+/// destination. Replies are read from a raw socket along with every other
+/// ICMP message of the host and told apart by a random echo id.
+struct Prober {
+    sock: UdpSocket,
+    dst: IpAddr,
+    timeout: Duration,
+    ident: u16,
+    seq: u16,
+    sent: u32,
+}
+
+impl Prober {
+    fn new(dst: IpAddr, timeout: Duration) -> Result<Self> {
+        let (domain, protocol) = match dst {
+            IpAddr::V4(_) => (Domain::IPV4, Protocol::ICMPV4),
+            IpAddr::V6(_) => (Domain::IPV6, Protocol::ICMPV6),
+        };
+        let sock = Socket::new(domain, Type::RAW, Some(protocol))?;
+        set_pmtudisc_probe(&sock, dst)?;
+        sock.set_nonblocking(true)?;
+
+        Ok(Self {
+            sock: UdpSocket::from_std(sock.into())?,
+            dst,
+            timeout,
+            ident: rand::random(),
+            seq: 0,
+            sent: 0,
+        })
+    }
+
+    /// Whether `size` gets through, each try waits `timeout` for an answer.
+    async fn try_size(&mut self, size: u16, budget: &Budget) -> Result<Answer> {
+        for _ in 0..PROBE_TRIES {
+            budget.acquire().await;
+            if let Some(answer) = self.probe(size).await? {
+                return Ok(answer);
+            }
+        }
+        Ok(Answer::Dropped(PmtuDrop::Blackhole))
+    }
+
+    /// `None` when nothing answered the probe.
+    async fn probe(&mut self, size: u16) -> Result<Option<Answer>> {
+        let header = match self.dst {
+            IpAddr::V4(_) => IPV4_HEADER_LEN,
+            IpAddr::V6(_) => IPV6_HEADER_LEN,
+        };
+        let seq = self.seq;
+        self.seq = self.seq.wrapping_add(1);
+        self.sent += 1;
+        let len = usize::from(size - header);
+        let probe = icmp::echo_request(self.dst.is_ipv6(), self.ident, seq, len);
+        let dst = SocketAddr::new(self.dst, 0);
+        match self.sock.send_to(&probe, dst).await {
+            Ok(_) => {}
+            Err(e) if e.raw_os_error() == Some(libc::EMSGSIZE) => {
+                return Ok(Some(Answer::Dropped(PmtuDrop::Local)))
+            }
+            Err(e) => return Err(e),
+        }
+
+        let echo = Quoted::Echo {
+            id: self.ident,
+            seq,
+        };
+        let deadline = Instant::now() + self.timeout;
+        let mut buf = vec![0; RECV_BUFFER];
+        loop {
+            let Ok(recv) = time::timeout_at(deadline, self.sock.recv_from(&mut buf)).await else {
+                return Ok(None);
+            };
+            let (len, from) = recv?;
+            let msg = match from {
+                SocketAddr::V4(_) => icmp::parse_v4(&buf[..len]),
+                SocketAddr::V6(from) => icmp::parse_v6(&buf[..len], *from.ip()),
+            };
+            let Some(msg) = msg.filter(|msg| msg.probe == echo) else {
+                continue;
+            };
+            match msg.kind {
+                Kind::EchoReply => return Ok(Some(Answer::Passed)),
+                Kind::TooBig { mtu } => {
+                    let drop = PmtuDrop::TooBig {
+                        from: msg.from,
+                        mtu,
+                    };
+                    return Ok(Some(Answer::Dropped(drop)));
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Finds the path MTU toward commanded targets. Every command is a single
+/// discovery, the controller pushes it again to repeat it.
+pub struct PmtuDetector {
+    budget: Budget,
+}
+
+impl PmtuDetector {
+    pub fn new(budget: Budget) -> Self {
+        Self { budget }
+    }
+
+    /// Binary search for the largest size between the bounds of `command` that
+    /// gets through, starting with the maximum. A next-hop MTU reported by a
+    /// router is tried next when it is in the range left, and one byte more
+    /// once it got through, so an honest router settles the search in three
+    /// sizes while a wrong report only costs the binary search.
+    async fn discover(command: &PmtuCommand, budget: &Budget) -> Result<PmtuResult> {
+        let start_at = SystemTime::now();
+        let mut prober = Prober::new(command.ip, command.timeout)?;
+        // Largest size known to get through, one below the minimum before any
+        // did, and the largest size not known to fail.
+        let mut passed = u32::from(command.min_size) - 1;
+        let mut upper = u32::from(command.max_size);
+        let mut failed = None;
+        let mut size = upper;
+        let mut reported = None;
+        while passed < upper {
+            let mut hint = None;
+            match prober.try_size(size as u16, budget).await? {
+                Answer::Passed => {
+                    passed = size;
+                    if reported.take() == Some(size) {
+                        hint = Some(size + 1);
+                    }
+                }
+                Answer::Dropped(drop) => {
+                    if let PmtuDrop::TooBig { mtu, .. } = drop {
+                        hint = Some(mtu);
+                        reported = Some(mtu);
+                    }
+                    // Later sizes are all smaller, so the last failure is the
+                    // smallest one.
+                    failed = Some((size as u16, drop));
+                    upper = size - 1;
+                }
+            }
+
+            size = match hint {
+                Some(mtu) if mtu > passed && mtu <= upper => mtu,
+                _ => passed + (upper - passed).div_ceil(2),
+            };
+        }
+
+        Ok(PmtuResult {
+            id: command.id,
+            ip: command.ip,
+            start_at,
+            mtu: if passed < u32::from(command.min_size) {
+                0
+            } else {
+                passed as u16
+            },
+            failed,
+            probes: prober.sent,
+        })
+    }
+
+    async fn detect(command: PmtuCommand, budget: Budget, result_tx: ResultTx<PmtuResult>) {
+        let result = match Self::discover(&command, &budget).await {
+            Ok(result) => result,
+            Err(e) => {
+                warn!("Discover path mtu to {} fail, err:{}", command.ip, e);
+                return;
+            }
+        };
+
+        info!(
+            "Path mtu to {} is {}, {} probes",
+            command.ip, result.mtu, result.probes
+        );
+        result_tx.send(result).await.expect("Send pmtu result fail");
+    }
+}
+
+impl Detector for PmtuDetector {
+    type Command = PmtuCommand;
+    type Result = PmtuResult;
+
+    /// Discoveries never cancel each other.
+    async fn apply(&mut self, commands: Vec<PmtuCommand>, result_tx: &ResultTx<PmtuResult>) {
+        for command in commands {
+            tokio::spawn(Self::detect(
+                command,
+                self.budget.clone(),
+                result_tx.clone(),
+            ));
+        }
+    }
+}
@@ -0,0 +1,86 @@
+use crate::conf;
+use crate::structures::TargetState;
+use std::time::{Duration, SystemTime};
+
+pub(super) struct Transition {
+    pub(super) from: TargetState,
+    pub(super) to: TargetState,
+    /// When `from` was entered.
+    pub(super) since: SystemTime,
+    pub(super) losses: u32,
+}
+
+/// Up / degraded / down state of one subscriber. A target only goes back up
+/// after `recover_after` good replies, so a flapping target stays degraded.
+pub(super) struct Reachability {
+    state: TargetState,
+    since: SystemTime,
+    losses: u32,
+    replies: u32,
+}
+
+impl Reachability {
+    pub(super) fn new() -> Self {
+        Self {
+            state: TargetState::Up,
+            since: SystemTime::now(),
+            losses: 0,
+            replies: 0,
+        }
+    }
+
+    pub(super) fn rtt_threshold(conf: &conf::Reachability) -> Option<Duration> {
+        (conf.degraded_rtt_ms > 0).then(|| Duration::from_millis(conf.degraded_rtt_ms))
+    }
+
+    /// Feed a probe sent at `at`, `rtt` is `None` when it was lost.
+    pub(super) fn on_probe(
+        &mut self,
+        rtt: Option<Duration>,
+        at: SystemTime,
+        conf: &conf::Reachability,
+    ) -> Option<Transition> {
+        let slow = matches!(
+            (rtt, Self::rtt_threshold(conf)),
+            (Some(rtt), Some(threshold)) if rtt > threshold
+        );
+        let losses = self.losses;
+        match rtt {
+            None => {
+                self.losses += 1;
+                self.replies = 0;
+            }
+            Some(_) => {
+                self.losses = 0;
+                self.replies = if slow { 0 } else { self.replies + 1 };
+            }
+        }
+
+        let degraded = conf.degraded_after > 0 && self.losses >= conf.degraded_after;
+        let next = if self.losses >= conf.down_after.max(1) {
+            TargetState::Down
+        } else if degraded || slow {
+            TargetState::Degraded
+        } else if self.replies >= conf.recover_after {
+            TargetState::Up
+        } else if rtt.is_some() && self.state == TargetState::Down {
+            TargetState::Degraded
+        } else {
+            self.state
+        };
+        if next == self.state {
+            return None;
+        }
+
+        let transition = Transition {
+            from: self.state,
+            to: next,
+            since: self.since,
+            // A reply ending a loss run is evidenced by the run it ended.
+            losses: self.losses.max(losses),
+        };
+        self.state = next;
+        self.since = at;
+        Some(transition)
+    }
+}
@@ -0,0 +1,52 @@
+use std::time::Duration;
+
+/// RFC 6298 gains.
+const ALPHA: f64 = 1.0 / 8.0;
+const BETA: f64 = 1.0 / 4.0;
+const K: f64 = 4.0;
+/// Lower bound of `K * rttvar`, keeps a very stable target from timing out on
+/// scheduling noise.
+const MIN_VARIANCE: Duration = Duration::from_millis(5);
+/// Timeouts grow at most 2^6 times before the next reply.
+const MAX_BACKOFF_SHIFT: u32 = 6;
+
+/// Smoothed RTT and variance of one target, used for adaptive timeouts.
+#[derive(Default)]
+pub(super) struct RttEstimator {
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    backoff: u32,
+}
+
+impl RttEstimator {
+    pub(super) fn on_reply(&mut self, rtt: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                let delta = srtt.abs_diff(rtt);
+                self.rttvar = self.rttvar.mul_f64(1.0 - BETA) + delta.mul_f64(BETA);
+                self.srtt = Some(srtt.mul_f64(1.0 - ALPHA) + rtt.mul_f64(ALPHA));
+            }
+        }
+        self.backoff = 0;
+    }
+
+    /// Back off like a TCP retransmission timer, so a target that got slower
+    /// is not reported lost forever.
+    pub(super) fn on_timeout(&mut self) {
+        self.backoff = (self.backoff + 1).min(MAX_BACKOFF_SHIFT);
+    }
+
+    /// `srtt + K * rttvar`, clamped by `max`. Until the first reply it is `max`.
+    pub(super) fn timeout(&self, max: Duration) -> Duration {
+        let Some(srtt) = self.srtt else {
+            return max;
+        };
+
+        let rto = srtt + self.rttvar.mul_f64(K).max(MIN_VARIANCE);
+        rto.saturating_mul(1 << self.backoff).min(max)
+    }
+}
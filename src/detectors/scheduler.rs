@@ -0,0 +1,666 @@
+use super::baseline::Baseline;
+use super::reachability::Reachability;
+use super::rtt::RttEstimator;
+use super::variation::DelayVariation;
+use super::wheel::TimerWheel;
+use super::ResultTx;
+use crate::budget::Budget;
+use crate::conf::{self, Smoothing};
+use crate::events::{Event, EventTx};
+use crate::grpc::collector_grpc::ProbeType;
+use crate::structures::{Anomaly, Escalation, ReportMode, Sampling, StateChange};
+use futures::stream::{FuturesUnordered, StreamExt};
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+use std::future::{self, Future};
+use std::io;
+use std::mem;
+use std::net::IpAddr;
+use std::num::Wrapping;
+use std::sync::Arc;
+use std::thread;
+use std::time::SystemTime;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::task;
+use tokio::time::{self, Duration, Instant};
+use tracing::{info, warn};
+
+const TICK_MILLIS: u64 = 1;
+const DEFAULT_WORKERS: usize = 4;
+const JOB_BUFFER: usize = 1024;
+/// Poisson gaps are truncated at this many intervals, as RFC 2330 allows.
+const POISSON_MAX_GAPS: f64 = 10.0;
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0100_0000_01b3;
+
+/// 64 bit FNV-1a, stable across builds so hash offsets survive upgrades.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(FNV_OFFSET, |hash, &b| {
+        (hash ^ u64::from(b)).wrapping_mul(FNV_PRIME)
+    })
+}
+
+/// Outcome of a single probe, `rtt` is `None` when it timed out.
+pub(super) struct Sample {
+    pub(super) send_at: SystemTime,
+    pub(super) rtt: Option<Duration>,
+    /// Replies of earlier probes that arrived out of order while waiting.
+    pub(super) reordered: u32,
+}
+
+pub(super) trait Probe: Send + Sync + 'static {
+    /// Destination, only used in logs.
+    fn target(&self) -> &str;
+
+    /// Address of the destination, `None` for host names.
+    fn ip(&self) -> Option<IpAddr>;
+
+    fn probe(&self, seq: u16, timeout: Duration)
+        -> impl Future<Output = io::Result<Sample>> + Send;
+}
+
+/// A probe slot as seen by one subscriber.
+pub(super) struct Outcome {
+    pub(super) id: u64,
+    pub(super) send_at: SystemTime,
+    /// `None` when the probe timed out or was not sent.
+    pub(super) rtt: Option<Duration>,
+    /// False when the slot was due but could not be probed.
+    pub(super) sent: bool,
+    /// Timeout the probe was judged by.
+    pub(super) timeout: Duration,
+    /// Active interval of the target.
+    pub(super) interval: Duration,
+    /// Delay variation against the previous probe, in micros.
+    pub(super) ipdv: Option<i64>,
+    /// Smoothed interarrival jitter of the target.
+    pub(super) jitter: Duration,
+    pub(super) reordered: u32,
+    /// Consecutive losses up to this probe, for a reply the length of the
+    /// loss run it ended.
+    pub(super) loss_run: u32,
+    pub(super) report: Option<ReportMode>,
+    pub(super) ip: Option<IpAddr>,
+}
+
+pub(super) trait FromOutcome {
+    fn from_outcome(outcome: &Outcome) -> Self;
+}
+
+/// A command reading the results of a target.
+pub(super) struct Subscriber {
+    pub(super) id: u64,
+    pub(super) timeout: Duration,
+    /// Judge probes by the smoothed RTT instead of the command timeout.
+    pub(super) adaptive: bool,
+    pub(super) report: Option<ReportMode>,
+    pub(super) anomaly_sensitivity: Option<f64>,
+}
+
+impl Subscriber {
+    fn timeout(&self, rtt: &RttEstimator) -> Duration {
+        if self.adaptive {
+            rtt.timeout(self.timeout)
+        } else {
+            self.timeout
+        }
+    }
+}
+
+/// One probe stream. Every result is fanned out to all subscribers, each one
+/// judging timeouts by its own command.
+pub(super) struct Target<P> {
+    pub(super) subscribers: Vec<Subscriber>,
+    pub(super) probe: Arc<P>,
+    pub(super) interval: Duration,
+    pub(super) sampling: Sampling,
+    pub(super) escalation: Option<Escalation>,
+}
+
+/// State of a subscriber, judged by its own timeout.
+struct SubscriberState {
+    loss_run: u32,
+    reachability: Reachability,
+    baseline: Option<Baseline>,
+}
+
+struct Entry<P> {
+    target: Target<P>,
+    rtt: RttEstimator,
+    variation: DelayVariation,
+    seq: Wrapping<u16>,
+    next_at: u64,
+    /// Bumped when the entry is rescheduled, older wheel entries are stale.
+    version: u32,
+    in_flight: bool,
+    /// One for every subscriber, in the same order.
+    states: Vec<SubscriberState>,
+    losses: u32,
+    replies: u32,
+    escalated: bool,
+}
+
+impl<P> Entry<P> {
+    fn interval(&self) -> Duration {
+        match self.target.escalation {
+            Some(escalation) if self.escalated => escalation.fast_interval,
+            _ => self.target.interval,
+        }
+    }
+
+    /// Track the loss run of the target, returns true when the escalation
+    /// kicked in with this probe.
+    fn track(&mut self, replied: bool) -> bool {
+        if replied {
+            self.losses = 0;
+            self.replies += 1;
+        } else {
+            self.losses += 1;
+            self.replies = 0;
+        }
+
+        let Some(escalation) = self.target.escalation else {
+            return false;
+        };
+        if !self.escalated && self.losses >= escalation.escalate_after {
+            self.escalated = true;
+            return true;
+        }
+        if self.escalated && self.replies >= escalation.cool_down_after {
+            self.escalated = false;
+        }
+        false
+    }
+
+    /// The probe waits for the most patient subscriber.
+    fn timeout(&self) -> Duration {
+        self.target
+            .subscribers
+            .iter()
+            .map(|s| s.timeout(&self.rtt))
+            .max()
+            .unwrap_or_default()
+    }
+}
+
+struct Job<P> {
+    key: usize,
+    generation: u64,
+    seq: u16,
+    timeout: Duration,
+    probe: Arc<P>,
+}
+
+struct Done {
+    key: usize,
+    generation: u64,
+    seq: u16,
+    result: io::Result<Sample>,
+}
+
+type TargetsTx<P> = mpsc::Sender<Vec<Target<P>>>;
+type TargetsRx<P> = mpsc::Receiver<Vec<Target<P>>>;
+type JobTx<P> = mpsc::Sender<Job<P>>;
+type JobRx<P> = mpsc::Receiver<Job<P>>;
+type DoneTx = mpsc::UnboundedSender<Done>;
+type DoneRx = mpsc::UnboundedReceiver<Done>;
+
+pub(super) struct SchedulerHandle<P> {
+    tx: TargetsTx<P>,
+}
+
+impl<P: Probe> SchedulerHandle<P> {
+    /// Stop probing the current targets and start on `targets`.
+    pub(super) async fn replace(&self, targets: Vec<Target<P>>) {
+        self.tx
+            .send(targets)
+            .await
+            .expect("Send targets to scheduler fail");
+    }
+}
+
+/// Keeps every target of a detector in one timer wheel and hands due probes to
+/// a fixed pool of workers, so the task count does not grow with targets.
+pub(super) struct Scheduler<P, R> {
+    name: &'static str,
+    probe_type: ProbeType,
+    smoothing: Smoothing,
+    reachability: conf::Reachability,
+    start: Instant,
+    start_sys: SystemTime,
+    wheel: TimerWheel<(usize, u32)>,
+    entries: Vec<Entry<P>>,
+    generation: u64,
+    workers: Vec<JobTx<P>>,
+    next_worker: usize,
+    rng: SmallRng,
+    result_tx: ResultTx<R>,
+    /// Results dropped because the reporter fell behind.
+    dropped: u64,
+    event_tx: EventTx,
+}
+
+impl<P: Probe, R: FromOutcome + Send + 'static> Scheduler<P, R> {
+    pub(super) fn spawn(
+        name: &'static str,
+        probe_type: ProbeType,
+        budget: Budget,
+        conf: &conf::Detector,
+        result_tx: ResultTx<R>,
+        event_tx: EventTx,
+    ) -> SchedulerHandle<P> {
+        let (tx, rx) = mpsc::channel(1);
+        let (done_tx, done_rx) = mpsc::unbounded_channel();
+
+        let workers = thread::available_parallelism().map_or(DEFAULT_WORKERS, |n| n.get());
+        let workers = (0..workers)
+            .map(|_| {
+                let (job_tx, job_rx) = mpsc::channel(JOB_BUFFER);
+                task::spawn(Self::work(job_rx, done_tx.clone(), budget.clone()));
+                job_tx
+            })
+            .collect();
+
+        let scheduler = Self {
+            name,
+            probe_type,
+            smoothing: conf.smoothing,
+            reachability: conf.reachability.clone(),
+            start: Instant::now(),
+            start_sys: SystemTime::now(),
+            wheel: TimerWheel::new(),
+            entries: Vec::new(),
+            generation: 0,
+            workers,
+            next_worker: 0,
+            rng: SmallRng::from_entropy(),
+            result_tx,
+            dropped: 0,
+            event_tx,
+        };
+        task::spawn(scheduler.run(rx, done_rx));
+
+        SchedulerHandle { tx }
+    }
+
+    async fn work(mut job_rx: JobRx<P>, done_tx: DoneTx, budget: Budget) {
+        let mut in_flight = FuturesUnordered::new();
+        loop {
+            tokio::select! {
+                job = job_rx.recv() => {
+                    let Some(job) = job else { return };
+                    let budget = budget.clone();
+                    in_flight.push(async move {
+                        budget.acquire().await;
+                        let result = job.probe.probe(job.seq, job.timeout).await;
+                        Done {
+                            key: job.key,
+                            generation: job.generation,
+                            seq: job.seq,
+                            result,
+                        }
+                    });
+                }
+                Some(done) = in_flight.next(), if !in_flight.is_empty() => {
+                    if done_tx.send(done).is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    async fn run(mut self, mut targets_rx: TargetsRx<P>, mut done_rx: DoneRx) {
+        let mut due = Vec::new();
+        loop {
+            let deadline = self.wheel.next_deadline().map(|tick| self.instant_of(tick));
+            let sleep = async {
+                match deadline {
+                    Some(deadline) => time::sleep_until(deadline).await,
+                    None => future::pending().await,
+                }
+            };
+
+            tokio::select! {
+                biased;
+
+                done = done_rx.recv() => {
+                    let done = done.expect("Recv probe done fail");
+                    self.complete(done);
+                }
+                targets = targets_rx.recv() => {
+                    let targets = targets.expect("Recv targets fail");
+                    self.replace(targets);
+                }
+                _ = sleep => {
+                    self.wheel.poll(self.now(), &mut due);
+                    for (key, version) in due.drain(..) {
+                        if self.entries[key].version == version {
+                            self.fire(key);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn now(&self) -> u64 {
+        self.start.elapsed().as_millis() as u64 / TICK_MILLIS
+    }
+
+    fn instant_of(&self, tick: u64) -> Instant {
+        self.start + Duration::from_millis(tick * TICK_MILLIS)
+    }
+
+    fn ticks(d: Duration) -> u64 {
+        (d.as_millis() as u64 / TICK_MILLIS).max(1)
+    }
+
+    fn replace(&mut self, targets: Vec<Target<P>>) {
+        info!(
+            "Replace {} targets, old {} new {}",
+            self.name,
+            self.entries.len(),
+            targets.len()
+        );
+
+        // In flight probes of the old targets are dropped when they complete.
+        self.generation += 1;
+        self.wheel = TimerWheel::new();
+        self.start = Instant::now();
+        self.start_sys = SystemTime::now();
+
+        let total = targets.len();
+        self.entries = targets
+            .into_iter()
+            .enumerate()
+            .map(|(i, target)| Entry {
+                next_at: Self::ticks(self.offset(&target, i, total)),
+                states: target
+                    .subscribers
+                    .iter()
+                    .map(|subscriber| SubscriberState {
+                        loss_run: 0,
+                        reachability: Reachability::new(),
+                        baseline: subscriber.anomaly_sensitivity.map(Baseline::new),
+                    })
+                    .collect(),
+                target,
+                rtt: RttEstimator::default(),
+                variation: DelayVariation::default(),
+                seq: Wrapping(0),
+                version: 0,
+                in_flight: false,
+                losses: 0,
+                replies: 0,
+                escalated: false,
+            })
+            .collect();
+        for (key, entry) in self.entries.iter().enumerate() {
+            self.wheel.insert(entry.next_at, (key, entry.version));
+        }
+    }
+
+    fn offset(&self, target: &Target<P>, index: usize, total: usize) -> Duration {
+        match self.smoothing {
+            Smoothing::None => Duration::ZERO,
+            Smoothing::Uniform => target.interval.mul_f64(index as f64 / total as f64),
+            Smoothing::Hash => {
+                let hash = fnv1a(target.probe.target().as_bytes());
+                let interval = target.interval.as_micros().max(1) as u64;
+                Duration::from_micros(hash % interval)
+            }
+        }
+    }
+
+    fn gap(&mut self, key: usize) -> Duration {
+        let entry = &self.entries[key];
+        let interval = entry.interval();
+        match entry.target.sampling {
+            Sampling::Fixed => interval,
+            Sampling::Uniform { jitter } => {
+                let jitter = jitter.min(interval);
+                let low = interval - jitter;
+                low + jitter.mul_f64(2.0 * self.rng.gen::<f64>())
+            }
+            Sampling::Poisson => {
+                let gaps = -(1.0 - self.rng.gen::<f64>()).ln();
+                interval.mul_f64(gaps.min(POISSON_MAX_GAPS))
+            }
+        }
+    }
+
+    /// Move the next slot of `key` to one gap from now, dropping the old one.
+    fn reschedule(&mut self, key: usize) {
+        let next = self.now() + Self::ticks(self.gap(key));
+        let entry = &mut self.entries[key];
+        if next >= entry.next_at {
+            return;
+        }
+
+        entry.version += 1;
+        entry.next_at = next;
+        self.wheel.insert(next, (key, entry.version));
+    }
+
+    /// Fire the slot of `key` that was due at `next_at`. The schedule never
+    /// shifts, slots which could not be probed in time are reported as not sent
+    /// so the loss they hide is still visible.
+    fn fire(&mut self, key: usize) {
+        let now = self.now();
+        let mut slot = self.entries[key].next_at;
+        let mut next = slot + Self::ticks(self.gap(key));
+        while next <= now {
+            self.skip(key, slot);
+            slot = next;
+            next = slot + Self::ticks(self.gap(key));
+        }
+        self.entries[key].next_at = next;
+        self.wheel.insert(next, (key, self.entries[key].version));
+
+        if self.entries[key].in_flight {
+            self.skip(key, slot);
+            return;
+        }
+
+        let entry = &mut self.entries[key];
+        entry.seq += Wrapping(1);
+        let job = Job {
+            key,
+            generation: self.generation,
+            seq: entry.seq.0,
+            timeout: entry.timeout(),
+            probe: entry.target.probe.clone(),
+        };
+
+        let worker = &self.workers[self.next_worker];
+        self.next_worker = (self.next_worker + 1) % self.workers.len();
+        match worker.try_send(job) {
+            Ok(()) => self.entries[key].in_flight = true,
+            Err(TrySendError::Full(_)) => self.skip(key, slot),
+            Err(TrySendError::Closed(_)) => panic!("Send probe job fail"),
+        }
+    }
+
+    fn skip(&mut self, key: usize, slot: u64) {
+        let send_at = self.start_sys + Duration::from_millis(slot * TICK_MILLIS);
+        let entry = &self.entries[key];
+        let results: Vec<_> = entry
+            .target
+            .subscribers
+            .iter()
+            .map(|subscriber| Outcome {
+                id: subscriber.id,
+                send_at,
+                rtt: None,
+                sent: false,
+                timeout: subscriber.timeout(&entry.rtt),
+                interval: entry.interval(),
+                ipdv: None,
+                jitter: entry.variation.jitter(),
+                reordered: 0,
+                loss_run: 0,
+                report: subscriber.report,
+                ip: entry.target.probe.ip(),
+            })
+            .map(|outcome| R::from_outcome(&outcome))
+            .collect();
+        for result in results {
+            self.send_result(result);
+        }
+    }
+
+    /// Results are dropped rather than waited for, so a stalled reporter never
+    /// holds up the probes of every target.
+    fn send_result(&mut self, result: R) {
+        match self.result_tx.try_send(result) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                self.dropped += 1;
+                if self.dropped.is_power_of_two() {
+                    warn!(
+                        "{} result queue full, dropped {} results",
+                        self.name, self.dropped
+                    );
+                }
+            }
+            Err(TrySendError::Closed(_)) => panic!("Send {} result fail", self.name),
+        }
+    }
+
+    fn complete(&mut self, done: Done) {
+        if done.generation != self.generation {
+            return;
+        }
+
+        let entry = &mut self.entries[done.key];
+        entry.in_flight = false;
+
+        match done.result {
+            Ok(sample) => {
+                let ipdv = entry.variation.on_probe(done.seq, sample.rtt);
+                let interval = entry.interval();
+                let jitter = entry.variation.jitter();
+
+                // Judge every subscriber before the estimator learns from this probe.
+                let outcomes: Vec<_> = entry
+                    .target
+                    .subscribers
+                    .iter()
+                    .zip(entry.states.iter_mut())
+                    .map(|(subscriber, state)| {
+                        let timeout = subscriber.timeout(&entry.rtt);
+                        let rtt = sample.rtt.filter(|rtt| *rtt <= timeout);
+                        let loss_run = match rtt {
+                            Some(_) => mem::take(&mut state.loss_run),
+                            None => {
+                                state.loss_run += 1;
+                                state.loss_run
+                            }
+                        };
+                        Outcome {
+                            id: subscriber.id,
+                            send_at: sample.send_at,
+                            rtt,
+                            sent: true,
+                            timeout,
+                            interval,
+                            ipdv: ipdv.filter(|_| rtt.is_some()),
+                            jitter,
+                            reordered: sample.reordered,
+                            loss_run,
+                            report: subscriber.report,
+                            ip: entry.target.probe.ip(),
+                        }
+                    })
+                    .collect();
+
+                let threshold = Reachability::rtt_threshold(&self.reachability);
+                let mut events = Vec::new();
+                for (state, outcome) in entry.states.iter_mut().zip(&outcomes) {
+                    let flag = match (&mut state.baseline, outcome.rtt) {
+                        (Some(baseline), Some(rtt)) => baseline
+                            .on_reply(rtt)
+                            .map(|flag| (flag, baseline.sensitivity())),
+                        _ => None,
+                    };
+                    if let Some((flag, sensitivity)) = flag {
+                        events.push(Event::Anomaly(Anomaly {
+                            id: outcome.id,
+                            probe_type: self.probe_type,
+                            target: entry.target.probe.target().to_string(),
+                            kind: flag.kind,
+                            at: outcome.send_at,
+                            baseline: flag.baseline,
+                            deviation: flag.deviation,
+                            rtt: flag.rtt,
+                            sensitivity,
+                        }));
+                    }
+
+                    let transition = state.reachability.on_probe(
+                        outcome.rtt,
+                        outcome.send_at,
+                        &self.reachability,
+                    );
+                    let Some(transition) = transition else {
+                        continue;
+                    };
+                    events.push(Event::State(StateChange {
+                        id: outcome.id,
+                        probe_type: self.probe_type,
+                        target: entry.target.probe.target().to_string(),
+                        from: transition.from,
+                        to: transition.to,
+                        since: transition.since,
+                        at: outcome.send_at,
+                        losses: transition.losses,
+                        rtt: outcome.rtt,
+                        rtt_threshold: threshold,
+                        origin: None,
+                    }));
+                }
+
+                match sample.rtt {
+                    Some(rtt) => entry.rtt.on_reply(rtt),
+                    None => entry.rtt.on_timeout(),
+                }
+                if entry.track(sample.rtt.is_some()) {
+                    info!(
+                        "{} target:{} lost {} probes, probe faster",
+                        self.name,
+                        entry.target.probe.target(),
+                        entry.losses
+                    );
+                    self.reschedule(done.key);
+                }
+
+                for outcome in outcomes {
+                    self.send_result(R::from_outcome(&outcome));
+                }
+                for event in events {
+                    match &event {
+                        Event::State(c) => info!(
+                            "{} target:{} id:{} {:?} -> {:?}",
+                            self.name, c.target, c.id, c.from, c.to
+                        ),
+                        Event::Anomaly(a) => info!(
+                            "{} target:{} id:{} {:?}, baseline {:?} rtt {:?}",
+                            self.name, a.target, a.id, a.kind, a.baseline, a.rtt
+                        ),
+                        Event::PathChange(_) | Event::Window(_) | Event::Budget(_) => {}
+                    }
+                    // Nobody listening is fine, events are best effort.
+                    let _ = self.event_tx.send(event);
+                }
+            }
+            Err(e) => warn!(
+                "{} fail target:{}, err:{}",
+                self.name,
+                entry.target.probe.target(),
+                e
+            ),
+        }
+    }
+}
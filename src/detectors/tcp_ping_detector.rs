@@ -1,163 +1,138 @@
+use super::scheduler::{
+    FromOutcome, Outcome, Probe, Sample, Scheduler, SchedulerHandle, Subscriber, Target,
+};
+use super::{Detector, ResultTx};
+use crate::budget::Budget;
+use crate::conf;
+use crate::events::EventTx;
+use crate::grpc::collector_grpc::ProbeType;
 use crate::structures::{TcpPingCommand, TcpPingResult};
 use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
 use tokio::net::TcpStream;
-use tokio::sync::broadcast;
-use tokio::sync::broadcast::error::TryRecvError;
-use tokio::sync::mpsc;
-use tokio::task;
-use tokio::time;
+use tokio::time::{self, Duration};
+use tracing::info;
 
-use tokio::time::MissedTickBehavior;
-use tracing::{info, warn};
-
-const SMOOTH_MICROS: u64 = 1_000_000;
-
-type ExitedTx = mpsc::Sender<()>;
-type ExitedRx = mpsc::Receiver<()>;
-type ExitSignalTx = broadcast::Sender<()>;
-type ExitSignalRx = broadcast::Receiver<()>;
-type ResultTx = mpsc::Sender<TcpPingResult>;
-type CommandRx = mpsc::Receiver<Vec<TcpPingCommand>>;
+impl FromOutcome for TcpPingResult {
+    fn from_outcome(outcome: &Outcome) -> Self {
+        Self {
+            id: outcome.id,
+            is_timeout: outcome.sent && outcome.rtt.is_none(),
+            send_at: outcome.send_at,
+            rtt: outcome.rtt,
+            not_sent: !outcome.sent,
+            timeout: outcome.timeout,
+            interval: outcome.interval,
+            loss_run: outcome.loss_run,
+            report: outcome.report,
+            ip: outcome.ip,
+            origin: None,
+        }
+    }
+}
 
 struct TcpPinger {
-    comm: TcpPingCommand,
+    target: String,
+    ip: Option<IpAddr>,
 }
 
 impl TcpPinger {
-    fn from_command(comm: TcpPingCommand) -> Self {
-        Self { comm }
+    fn from_command(comm: &TcpPingCommand) -> Self {
+        Self {
+            target: comm.target.clone(),
+            ip: comm.target.parse::<SocketAddr>().ok().map(|addr| addr.ip()),
+        }
     }
+}
 
-    async fn ping(&self) -> io::Result<TcpPingResult> {
-        let conn = TcpStream::connect(self.comm.target.clone());
+impl Probe for TcpPinger {
+    fn target(&self) -> &str {
+        &self.target
+    }
+
+    fn ip(&self) -> Option<IpAddr> {
+        self.ip
+    }
+
+    async fn probe(&self, _seq: u16, timeout: Duration) -> io::Result<Sample> {
+        let conn = TcpStream::connect(self.target.as_str());
 
         let send_at_sys = std::time::SystemTime::now();
         let send_at = std::time::Instant::now();
 
-        let result = time::timeout(self.comm.timeout, conn).await;
+        let result = time::timeout(timeout, conn).await;
         match result {
-            Ok(Ok(_)) => {
-                let rtt = send_at.elapsed();
-                Ok(TcpPingResult {
-                    id: self.comm.id,
-                    is_timeout: false,
-                    send_at: send_at_sys,
-                    rtt: Some(rtt),
-                })
-            }
+            Ok(Ok(_)) => Ok(Sample {
+                send_at: send_at_sys,
+                rtt: Some(send_at.elapsed()),
+                reordered: 0,
+            }),
             Ok(Err(e)) => Err(e),
-            Err(_) => Ok(TcpPingResult {
-                id: self.comm.id,
-                is_timeout: true,
+            Err(_) => Ok(Sample {
                 send_at: send_at_sys,
                 rtt: None,
+                reordered: 0,
             }),
         }
     }
-
-    async fn loop_ping(&self, result_tx: ResultTx, mut rx: ExitSignalRx, tx: ExitedTx) {
-        let mut interval = time::interval(self.comm.interval);
-        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
-        loop {
-            interval.tick().await;
-
-            let result = self.ping().await;
-
-            match result {
-                Ok(r) => result_tx.send(r).await.expect("Send tcp ping result fail"),
-                Err(e) => warn!("Tcp ping fail target:{}, err:{}", self.comm.target, e),
-            }
-
-            match rx.try_recv() {
-                Ok(_) => {
-                    tx.send(()).await.expect("Exited tx send fail");
-                    return;
-                }
-                Err(TryRecvError::Closed | TryRecvError::Lagged(_)) => {
-                    panic!("Exit signal recv fail");
-                }
-                Err(broadcast::error::TryRecvError::Empty) => (),
-            }
-        }
-    }
 }
 
 pub struct TcpPingDetector {
-    exited_tx: ExitedTx,
-    exited_rx: ExitedRx,
-    exit_signal_tx: ExitSignalTx,
+    budget: Budget,
+    conf: conf::Detector,
+    event_tx: EventTx,
+    scheduler: Option<SchedulerHandle<TcpPinger>>,
 }
 
 impl TcpPingDetector {
-    pub fn new() -> Self {
-        let (exited_tx, exited_rx) = mpsc::channel(10);
-        let (exit_signal_tx, _) = broadcast::channel(1);
+    pub fn new(budget: Budget, conf: &conf::Detector, event_tx: EventTx) -> Self {
         Self {
-            exited_tx,
-            exited_rx,
-            exit_signal_tx,
-        }
-    }
-
-    async fn stop_all_tcp_ping_task(&mut self) {
-        let total = self.exit_signal_tx.receiver_count();
-
-        if total == 0 {
-            info!("No tcp ping task need to be stop");
-            return;
-        }
-
-        info!("Stop tcp ping task total:{}", total);
-        self.exit_signal_tx
-            .send(())
-            .expect("Broadcast stop tcp ping fail");
-
-        let mut completed_num = 0;
-
-        loop {
-            self.exited_rx
-                .recv()
-                .await
-                .expect("Recv tcp ping exited fail");
-            completed_num += 1;
-            if completed_num == total {
-                info!("All tcp ping tasks was stop.");
-                return;
-            }
+            budget,
+            conf: conf.clone(),
+            event_tx,
+            scheduler: None,
         }
     }
+}
 
-    pub async fn detect(mut self, mut command_rx: CommandRx, result_tx: ResultTx) {
-        loop {
-            let commands = command_rx.recv().await.expect("Command rx fail");
-            info!("Recv tcp ping commands");
-
-            self.stop_all_tcp_ping_task().await;
-
-            if commands.is_empty() {
-                info!("Commands is empty, noting to do");
-                continue;
-            }
-
-            let total = commands.len();
-
-            info!("Start tcp ping tasks, total {}", commands.len());
-
-            let smooth_task_time = time::Duration::from_micros(SMOOTH_MICROS / total as u64);
-            let mut smooth_task_ticker = time::interval(smooth_task_time);
-            for command in commands {
-                smooth_task_ticker.tick().await;
-
-                let result_tx = result_tx.clone();
-                let exit_signal_rx = self.exit_signal_tx.subscribe();
-                let exited_tx = self.exited_tx.clone();
-                task::spawn(async move {
-                    let pinger = TcpPinger::from_command(command);
-                    pinger.loop_ping(result_tx, exit_signal_rx, exited_tx).await;
-                });
-            }
-
-            info!("All ping tasks was started, total {}", total)
-        }
+impl Detector for TcpPingDetector {
+    type Command = TcpPingCommand;
+    type Result = TcpPingResult;
+
+    async fn apply(&mut self, commands: Vec<TcpPingCommand>, result_tx: &ResultTx<TcpPingResult>) {
+        let total = commands.len();
+        info!("Start tcp ping targets, total {}", total);
+
+        let targets = commands
+            .iter()
+            .map(|command| Target {
+                subscribers: vec![Subscriber {
+                    id: command.id,
+                    timeout: command.timeout,
+                    adaptive: command.adaptive_timeout,
+                    report: command.report,
+                    anomaly_sensitivity: None,
+                }],
+                probe: Arc::new(TcpPinger::from_command(command)),
+                interval: command.interval,
+                sampling: command.sampling,
+                escalation: command.escalation,
+            })
+            .collect();
+
+        let scheduler = self.scheduler.get_or_insert_with(|| {
+            Scheduler::spawn(
+                "tcp ping",
+                ProbeType::TcpPing,
+                self.budget.clone(),
+                &self.conf,
+                result_tx.clone(),
+                self.event_tx.clone(),
+            )
+        });
+        scheduler.replace(targets).await;
+
+        info!("All tcp ping targets was scheduled, total {}", total)
     }
 }
@@ -0,0 +1,194 @@
+use super::icmp::{self, TimestampReply};
+use super::{Detector, ResultTx};
+use crate::budget::Budget;
+use crate::structures::{TimestampCommand, TimestampFormat, TimestampResult};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io::Result;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::net::UdpSocket;
+use tokio::time::{self, Instant};
+use tracing::warn;
+
+const MILLIS_PER_DAY: u32 = 86_400_000;
+const RECV_BUFFER: usize = 2048;
+/// Farthest a little-endian clock may be from ours, in milliseconds.
+const MAX_SWAPPED_SKEW: u32 = 60 * 60 * 1000;
+
+/// Milliseconds since midnight UT, the clock of timestamp messages.
+fn millis_of_day(at: SystemTime) -> u32 {
+    let millis = at.duration_since(UNIX_EPOCH).unwrap().as_millis();
+    (millis % u128::from(MILLIS_PER_DAY)) as u32
+}
+
+/// `to - from` in milliseconds, across midnight when that is shorter.
+fn day_diff(to: u32, from: u32) -> i32 {
+    let diff = (to + MILLIS_PER_DAY - from) % MILLIS_PER_DAY;
+    if diff > MILLIS_PER_DAY / 2 {
+        diff as i32 - MILLIS_PER_DAY as i32
+    } else {
+        diff as i32
+    }
+}
+
+/// Read the receive and transmit timestamps of a target. A standard clock has
+/// the high-order bit clear and stays within a day, some stacks answer zero
+/// without a clock. Others write it in little-endian byte order, which only
+/// counts when it is close to `originate`, a non-standard value often reads
+/// as some time of day swapped. When both byte orders fit, the closer wins.
+fn read_clock(originate: u32, receive: u32, transmit: u32) -> (TimestampFormat, u32, u32) {
+    let readings = [
+        (TimestampFormat::Standard, receive, transmit),
+        (
+            TimestampFormat::Swapped,
+            receive.swap_bytes(),
+            transmit.swap_bytes(),
+        ),
+    ];
+    readings
+        .into_iter()
+        .filter(|&(_, r, t)| r < MILLIS_PER_DAY && t < MILLIS_PER_DAY && (r, t) != (0, 0))
+        .filter(|&(format, r, _)| {
+            format == TimestampFormat::Standard
+                || day_diff(r, originate).unsigned_abs() <= MAX_SWAPPED_SKEW
+        })
+        .min_by_key(|&(_, r, _)| day_diff(r, originate).unsigned_abs())
+        .unwrap_or((TimestampFormat::NonStandard, receive, transmit))
+}
+
+/// Sends ICMP timestamp requests to IPv4 targets. Receive and transmit times
+/// of the target split the round trip into a forward and a backward delay,
+/// each off by the clock offset of the target. Every command set is a single
+/// round, rounds never cancel each other.
+pub struct TimestampDetector {
+    budget: Budget,
+}
+
+impl TimestampDetector {
+    pub fn new(budget: Budget) -> Self {
+        Self { budget }
+    }
+
+    /// Replies are read from a raw socket along with every other ICMP message
+    /// of the host and told apart by a random id.
+    fn socket() -> Result<UdpSocket> {
+        let sock = Socket::new(Domain::IPV4, Type::RAW, Some(Protocol::ICMPV4))?;
+        sock.set_nonblocking(true)?;
+        UdpSocket::from_std(sock.into())
+    }
+
+    async fn detect(
+        command: TimestampCommand,
+        budget: Budget,
+        result_tx: ResultTx<TimestampResult>,
+    ) {
+        let sock = match Self::socket() {
+            Ok(sock) => sock,
+            Err(e) => {
+                warn!("Open timestamp socket fail, err:{}", e);
+                return;
+            }
+        };
+        let ident = rand::random();
+        for seq in 0..command.count {
+            budget.acquire().await;
+            match Self::request(&sock, &command, ident, seq as u16).await {
+                Ok(result) => result_tx
+                    .send(result)
+                    .await
+                    .expect("Send timestamp result fail"),
+                Err(e) => {
+                    warn!("Timestamp request to {} fail, err:{}", command.ip, e);
+                    return;
+                }
+            }
+        }
+    }
+
+    async fn request(
+        sock: &UdpSocket,
+        command: &TimestampCommand,
+        ident: u16,
+        seq: u16,
+    ) -> Result<TimestampResult> {
+        let send_at = SystemTime::now();
+        let originate = millis_of_day(send_at);
+        let sent = Instant::now();
+        let request = icmp::timestamp_request(ident, seq, originate);
+        let dst = SocketAddr::new(IpAddr::V4(command.ip), 0);
+        sock.send_to(&request, dst).await?;
+
+        let mut result = TimestampResult {
+            id: command.id,
+            ip: command.ip,
+            send_at,
+            rtt: None,
+            originate,
+            receive: 0,
+            transmit: 0,
+            returned: 0,
+            format: TimestampFormat::Standard,
+            forward: None,
+            backward: None,
+        };
+        let Some(reply) = Self::reply(sock, command, ident, seq, sent + command.timeout).await?
+        else {
+            return Ok(result);
+        };
+        result.rtt = Some(sent.elapsed());
+        result.returned = millis_of_day(SystemTime::now());
+
+        let (format, receive, transmit) = read_clock(originate, reply.receive, reply.transmit);
+        result.format = format;
+        result.receive = receive;
+        result.transmit = transmit;
+        if format != TimestampFormat::NonStandard {
+            result.forward = Some(day_diff(receive, originate));
+            result.backward = Some(day_diff(result.returned, transmit));
+        }
+        Ok(result)
+    }
+
+    /// The reply to request `seq`, `None` when it is not there by `deadline`.
+    async fn reply(
+        sock: &UdpSocket,
+        command: &TimestampCommand,
+        ident: u16,
+        seq: u16,
+        deadline: Instant,
+    ) -> Result<Option<TimestampReply>> {
+        let mut buf = vec![0; RECV_BUFFER];
+        loop {
+            let Ok(recv) = time::timeout_at(deadline, sock.recv_from(&mut buf)).await else {
+                return Ok(None);
+            };
+            let (len, from) = recv?;
+            if from.ip() != IpAddr::V4(command.ip) {
+                continue;
+            }
+            match icmp::parse_timestamp_reply(&buf[..len]) {
+                Some(reply) if reply.id == ident && reply.seq == seq => return Ok(Some(reply)),
+                _ => {}
+            }
+        }
+    }
+}
+
+impl Detector for TimestampDetector {
+    type Command = TimestampCommand;
+    type Result = TimestampResult;
+
+    async fn apply(
+        &mut self,
+        commands: Vec<TimestampCommand>,
+        result_tx: &ResultTx<TimestampResult>,
+    ) {
+        for command in commands {
+            tokio::spawn(Self::detect(
+                command,
+                self.budget.clone(),
+                result_tx.clone(),
+            ));
+        }
+    }
+}
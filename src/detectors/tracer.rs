@@ -0,0 +1,320 @@
+use super::icmp::{self, Kind, Message, Quoted};
+use crate::budget::Budget;
+use crate::structures::{MplsLabel, MtrCommand, TraceProtocol};
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+use std::collections::HashMap;
+use std::io::{ErrorKind, Result};
+use std::net::{self, IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::net::{TcpSocket, TcpStream, UdpSocket};
+use tokio::task::JoinSet;
+use tokio::time::{self, Duration, Instant};
+
+const PROBE_LEN: usize = 64;
+const RECV_BUFFER: usize = 2048;
+/// A SYN is given up below the initial RTO of 1s, before the kernel would
+/// retransmit it, so every retransmit is sent by the tracer within budget.
+const SYN_RETRY: Duration = Duration::from_millis(900);
+
+type Connects = JoinSet<(u8, Result<TcpStream>)>;
+
+/// Answer to the probe of one TTL, `from` is `None` when nobody answered.
+#[derive(Debug, Clone)]
+pub(super) struct Hop {
+    pub(super) ttl: u8,
+    /// Paris flow of the probe, 0 for classic ones.
+    pub(super) flow: u16,
+    pub(super) from: Option<IpAddr>,
+    pub(super) rtt: Option<Duration>,
+    pub(super) mpls: Vec<MplsLabel>,
+}
+
+/// Traces the path to one destination with probes of growing TTL. Hops answer
+/// with ICMP errors, which are read from a raw socket along with every other
+/// ICMP message of the host, so probes are told apart by what the errors
+/// quote: a random echo id, the UDP source port of the flow or the TCP source
+/// port of the probe.
+pub(super) struct Tracer {
+    sock: UdpSocket,
+    dst: IpAddr,
+    protocol: TraceProtocol,
+    port: u16,
+    fixed_port: bool,
+    ident: u16,
+    /// Socket of every UDP flow, its port is the source port of the flow.
+    udp: HashMap<u16, UdpSocket>,
+}
+
+fn unspecified(dst: IpAddr) -> SocketAddr {
+    match dst {
+        IpAddr::V4(_) => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+        IpAddr::V6(_) => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0),
+    }
+}
+
+fn set_ttl(sock: SockRef<'_>, dst: IpAddr, ttl: u8) -> Result<()> {
+    match dst {
+        IpAddr::V4(_) => sock.set_ttl(u32::from(ttl)),
+        IpAddr::V6(_) => sock.set_unicast_hops_v6(u32::from(ttl)),
+    }
+}
+
+impl Tracer {
+    pub(super) fn new(command: &MtrCommand) -> Result<Self> {
+        let dst = command.ip;
+        let (domain, protocol) = match dst {
+            IpAddr::V4(_) => (Domain::IPV4, Protocol::ICMPV4),
+            IpAddr::V6(_) => (Domain::IPV6, Protocol::ICMPV6),
+        };
+        let sock = Socket::new(domain, Type::RAW, Some(protocol))?;
+        sock.set_nonblocking(true)?;
+        let sock = UdpSocket::from_std(sock.into())?;
+        let port = match command.port {
+            0 => command.protocol.default_port(),
+            port => port,
+        };
+
+        Ok(Self {
+            sock,
+            dst,
+            protocol: command.protocol,
+            port,
+            fixed_port: command.fixed_port,
+            ident: rand::random(),
+            udp: HashMap::new(),
+        })
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> Result<Option<Message>> {
+        let (len, from) = self.sock.recv_from(buf).await?;
+        let msg = match from {
+            SocketAddr::V4(_) => icmp::parse_v4(&buf[..len]),
+            SocketAddr::V6(from) => icmp::parse_v6(&buf[..len], *from.ip()),
+        };
+        Ok(msg)
+    }
+
+    /// The socket UDP probes of `flow` are sent from. It is bound to the
+    /// address routed toward the destination, the checksum covers it.
+    fn udp(&mut self, flow: u16) -> Result<&UdpSocket> {
+        if !self.udp.contains_key(&flow) {
+            let route = net::UdpSocket::bind(unspecified(self.dst))?;
+            route.connect(SocketAddr::new(self.dst, self.port))?;
+            let sock = net::UdpSocket::bind(SocketAddr::new(route.local_addr()?.ip(), 0))?;
+            sock.set_nonblocking(true)?;
+            self.udp.insert(flow, UdpSocket::from_std(sock)?);
+        }
+        Ok(&self.udp[&flow])
+    }
+
+    /// Send a SYN with `ttl` from a new socket, whose source port stands for
+    /// the TTL from now on. Answers to an earlier SYN of the TTL are ignored.
+    fn syn(
+        &self,
+        ttl: u8,
+        tcp_ports: &mut HashMap<u16, u8>,
+        connects: &mut Connects,
+    ) -> Result<()> {
+        let sock = match self.dst {
+            IpAddr::V4(_) => TcpSocket::new_v4()?,
+            IpAddr::V6(_) => TcpSocket::new_v6()?,
+        };
+        sock.bind(unspecified(self.dst))?;
+        set_ttl(SockRef::from(&sock), self.dst, ttl)?;
+        tcp_ports.retain(|_, t| *t != ttl);
+        tcp_ports.insert(sock.local_addr()?.port(), ttl);
+        let dst = SocketAddr::new(self.dst, self.port);
+        connects.spawn(async move {
+            let result = match time::timeout(SYN_RETRY, sock.connect(dst)).await {
+                Ok(result) => result,
+                Err(_) => Err(ErrorKind::TimedOut.into()),
+            };
+            (ttl, result)
+        });
+        Ok(())
+    }
+
+    /// TTL of the probe of `round` an ICMP message answers.
+    fn probe_ttl(
+        &self,
+        probe: Quoted,
+        round: u8,
+        udp_port: Option<u16>,
+        tcp_ports: &HashMap<u16, u8>,
+    ) -> Option<u8> {
+        let seq = match probe {
+            Quoted::Echo { id, seq }
+                if self.protocol == TraceProtocol::Icmp && id == self.ident =>
+            {
+                seq
+            }
+            Quoted::Udp { src_port, checksum } if udp_port == Some(src_port) => checksum,
+            Quoted::Tcp { src_port } if self.protocol == TraceProtocol::Tcp => {
+                return tcp_ports.get(&src_port).copied();
+            }
+            _ => return None,
+        };
+        let [r, ttl] = seq.to_be_bytes();
+        (r == round).then_some(ttl)
+    }
+
+    /// Probe every TTL up to `hop_limit` at once and wait `timeout` after the
+    /// last one for answers. Hops behind the destination are cut off. With a
+    /// `flow` every probe keeps that flow identifier. Unanswered TCP SYNs are
+    /// sent again every `SYN_RETRY` until then.
+    pub(super) async fn round(
+        &mut self,
+        round: u8,
+        hop_limit: u8,
+        timeout: Duration,
+        flow: Option<u16>,
+        budget: &Budget,
+    ) -> Result<Vec<Hop>> {
+        let v6 = self.dst.is_ipv6();
+        let mut answers = Answers::new(hop_limit, flow.unwrap_or_default());
+        let mut tcp_ports = HashMap::new();
+        let mut connects = JoinSet::new();
+        for ttl in 1..=hop_limit {
+            budget.acquire().await;
+            let seq = u16::from_be_bytes([round, ttl]);
+            match self.protocol {
+                TraceProtocol::Icmp => {
+                    set_ttl(SockRef::from(&self.sock), self.dst, ttl)?;
+                    let probe = match flow {
+                        Some(flow) => icmp::flow_echo_request(v6, self.ident, seq, PROBE_LEN, flow),
+                        None => icmp::echo_request(v6, self.ident, seq, PROBE_LEN),
+                    };
+                    self.sock
+                        .send_to(&probe, SocketAddr::new(self.dst, 0))
+                        .await?;
+                }
+                TraceProtocol::Udp => {
+                    // A Paris flow keeps its ports, the checksum tells probes apart.
+                    let port = if self.fixed_port || flow.is_some() {
+                        self.port
+                    } else {
+                        self.port.wrapping_add(u16::from(ttl) - 1)
+                    };
+                    let dst = SocketAddr::new(self.dst, port);
+                    let sock = self.udp(flow.unwrap_or_default())?;
+                    set_ttl(SockRef::from(sock), dst.ip(), ttl)?;
+                    let payload = icmp::udp_payload(sock.local_addr()?, dst, PROBE_LEN, seq);
+                    sock.send_to(&payload, dst).await?;
+                }
+                TraceProtocol::Tcp => self.syn(ttl, &mut tcp_ports, &mut connects)?,
+            }
+            answers.sent.push(Instant::now());
+        }
+
+        let udp_port = match self.protocol {
+            TraceProtocol::Udp => Some(self.udp(flow.unwrap_or_default())?.local_addr()?.port()),
+            _ => None,
+        };
+        let deadline = Instant::now() + timeout;
+        let mut buf = vec![0; RECV_BUFFER];
+        while !answers.done() {
+            tokio::select! {
+                msg = self.recv(&mut buf) => {
+                    let Some(msg) = msg? else { continue };
+                    let Some(ttl) = self.probe_ttl(msg.probe, round, udp_port, &tcp_ports) else {
+                        continue;
+                    };
+                    let at_dst = msg.kind == Kind::EchoReply
+                        || (matches!(msg.kind, Kind::Unreachable { .. } | Kind::TooBig { .. })
+                            && msg.from == self.dst);
+                    answers.answer(ttl, msg.from, at_dst, msg.mpls);
+                }
+                Some(connect) = connects.join_next() => {
+                    let Ok((ttl, result)) = connect else { continue };
+                    // A SYN-ACK or RST is the destination, errors quoted by
+                    // hops fail the connect too but are read from the raw socket.
+                    match result {
+                        Ok(_) => answers.answer(ttl, self.dst, true, Vec::new()),
+                        Err(e) if e.kind() == ErrorKind::ConnectionRefused => {
+                            answers.answer(ttl, self.dst, true, Vec::new())
+                        }
+                        Err(e) if e.kind() == ErrorKind::TimedOut && answers.pending(ttl) => {
+                            budget.acquire().await;
+                            self.syn(ttl, &mut tcp_ports, &mut connects)?;
+                            answers.resent(ttl);
+                        }
+                        Err(_) => {}
+                    }
+                }
+                _ = time::sleep_until(deadline) => break,
+            }
+        }
+
+        Ok(answers.into_hops())
+    }
+}
+
+/// Hops of one round as their answers come in.
+struct Answers {
+    hops: Vec<Hop>,
+    sent: Vec<Instant>,
+    /// Lowest TTL the destination answered.
+    reached: Option<u8>,
+}
+
+impl Answers {
+    fn new(hop_limit: u8, flow: u16) -> Self {
+        Self {
+            hops: (1..=hop_limit)
+                .map(|ttl| Hop {
+                    ttl,
+                    flow,
+                    from: None,
+                    rtt: None,
+                    mpls: Vec::new(),
+                })
+                .collect(),
+            sent: Vec::with_capacity(usize::from(hop_limit)),
+            reached: None,
+        }
+    }
+
+    /// Only the first answer of a TTL counts.
+    fn answer(&mut self, ttl: u8, from: IpAddr, at_dst: bool, mpls: Vec<MplsLabel>) {
+        let index = usize::from(ttl.wrapping_sub(1));
+        let Some(hop) = self.hops.get_mut(index) else {
+            return;
+        };
+        if hop.from.is_some() {
+            return;
+        }
+        hop.from = Some(from);
+        hop.rtt = Some(self.sent[index].elapsed());
+        hop.mpls = mpls;
+
+        if at_dst {
+            self.reached = Some(self.reached.map_or(ttl, |r| r.min(ttl)));
+        }
+    }
+
+    /// The hop of `ttl` is unanswered and before the destination.
+    fn pending(&self, ttl: u8) -> bool {
+        let index = usize::from(ttl.wrapping_sub(1));
+        self.reached.is_none_or(|r| ttl < r)
+            && self.hops.get(index).is_some_and(|hop| hop.from.is_none())
+    }
+
+    /// The probe of `ttl` was sent again, its RTT counts from now.
+    fn resent(&mut self, ttl: u8) {
+        if let Some(sent) = self.sent.get_mut(usize::from(ttl.wrapping_sub(1))) {
+            *sent = Instant::now();
+        }
+    }
+
+    /// Every hop up to the destination answered.
+    fn done(&self) -> bool {
+        let last = self.reached.map_or(self.hops.len(), usize::from);
+        self.hops[..last].iter().all(|hop| hop.from.is_some())
+    }
+
+    fn into_hops(mut self) -> Vec<Hop> {
+        if let Some(reached) = self.reached {
+            self.hops.truncate(usize::from(reached));
+        }
+        self.hops
+    }
+}
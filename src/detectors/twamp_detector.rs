@@ -0,0 +1,340 @@
+use super::{Detector, ResultTx};
+use crate::budget::Budget;
+use crate::structures::{Delay, TwampCommand, TwampResult};
+use crate::twamp::{self, Reflected};
+use socket2::SockRef;
+use std::collections::HashSet;
+use std::io::{ErrorKind, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::SystemTime;
+use tokio::net::UdpSocket;
+use tokio::time::{self, Duration, Instant, MissedTickBehavior};
+use tracing::{info, warn};
+
+const RECV_BUFFER: usize = 65_536;
+
+/// A reflected packet and when it came back.
+struct Reply {
+    reflected: Reflected,
+    at: SystemTime,
+}
+
+/// `to - from` in micros, negative when `to` is earlier.
+fn micros(from: SystemTime, to: SystemTime) -> i64 {
+    match to.duration_since(from) {
+        Ok(d) => d.as_micros() as i64,
+        Err(e) => -(e.duration().as_micros() as i64),
+    }
+}
+
+/// Sequence numbers, in the order they arrived, lower than one which arrived
+/// before them.
+fn reordered(arrivals: impl Iterator<Item = u32>) -> u32 {
+    let mut newest = None;
+    let mut count = 0;
+    for seq in arrivals {
+        match newest {
+            Some(n) if seq < n => count += 1,
+            _ => newest = Some(seq),
+        }
+    }
+    count
+}
+
+/// Runs TWAMP-Light sessions against reflectors, RFC 5357 unauthenticated
+/// test packets without a control session. Every command is one session,
+/// sessions never cancel each other.
+pub struct TwampDetector {
+    budget: Budget,
+}
+
+impl TwampDetector {
+    pub fn new(budget: Budget) -> Self {
+        Self { budget }
+    }
+
+    /// Send every test packet of `command` and collect the answers until
+    /// `timeout` after the last one, or until all of them came back.
+    async fn exchange(command: &TwampCommand, budget: &Budget) -> Result<(u32, Vec<Reply>)> {
+        let bind = match command.ip {
+            IpAddr::V4(_) => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+            IpAddr::V6(_) => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0),
+        };
+        let sock = UdpSocket::bind(bind).await?;
+        sock.connect(SocketAddr::new(command.ip, command.port))
+            .await?;
+        match command.ip {
+            IpAddr::V4(_) => sock.set_ttl(twamp::SENDER_TTL)?,
+            IpAddr::V6(_) => SockRef::from(&sock).set_unicast_hops_v6(twamp::SENDER_TTL)?,
+        }
+
+        // Back to back packets still go through the timer, which needs a period.
+        let mut timer = time::interval(command.interval.max(Duration::from_micros(1)));
+        timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut sent = 0;
+        let mut replies = Vec::new();
+        let mut deadline = None;
+        let mut buf = vec![0; RECV_BUFFER];
+        loop {
+            if deadline.is_some() && replies.len() >= sent as usize {
+                break;
+            }
+            let expired = async {
+                match deadline {
+                    Some(deadline) => time::sleep_until(deadline).await,
+                    None => std::future::pending().await,
+                }
+            };
+
+            tokio::select! {
+                _ = timer.tick(), if sent < command.count => {
+                    budget.acquire().await;
+                    let packet = twamp::test_packet(sent, SystemTime::now(), command.size);
+                    match sock.send(&packet).await {
+                        Ok(_) => {}
+                        // An earlier packet hit a closed port, this one counts as lost.
+                        Err(e) if e.kind() == ErrorKind::ConnectionRefused => {}
+                        Err(e) => return Err(e),
+                    }
+                    sent += 1;
+                    if sent == command.count {
+                        deadline = Some(Instant::now() + command.timeout);
+                    }
+                }
+                recv = sock.recv(&mut buf) => {
+                    let len = match recv {
+                        Ok(len) => len,
+                        // A port unreachable for an earlier packet, it is lost.
+                        Err(e) if e.kind() == ErrorKind::ConnectionRefused => continue,
+                        Err(e) => return Err(e),
+                    };
+                    let at = SystemTime::now();
+                    if let Some(reflected) = twamp::parse_reflected(&buf[..len]) {
+                        replies.push(Reply { reflected, at });
+                    }
+                }
+                _ = expired => break,
+            }
+        }
+        Ok((sent, replies))
+    }
+
+    async fn session(command: &TwampCommand, budget: &Budget) -> Result<TwampResult> {
+        let start_at = SystemTime::now();
+        let (sent, replies) = Self::exchange(command, budget).await?;
+        Ok(Self::summarize(command, start_at, sent, replies))
+    }
+
+    fn summarize(
+        command: &TwampCommand,
+        start_at: SystemTime,
+        sent: u32,
+        replies: Vec<Reply>,
+    ) -> TwampResult {
+        // Answers to packets never sent in this session are stray, repeated
+        // ones duplicates.
+        let mut seen = HashSet::new();
+        let mut duplicates = 0;
+        let replies: Vec<_> = replies
+            .into_iter()
+            .filter(|r| r.reflected.sender_seq < sent)
+            .filter(|r| {
+                let first = seen.insert(r.reflected.sender_seq);
+                if !first {
+                    duplicates += 1;
+                }
+                first
+            })
+            .collect();
+
+        let mut forward = Vec::with_capacity(replies.len());
+        let mut backward = Vec::with_capacity(replies.len());
+        let mut rtt = Vec::with_capacity(replies.len());
+        for reply in &replies {
+            let r = &reply.reflected;
+            forward.push(micros(r.sender_at, r.receive_at));
+            backward.push(micros(r.transmit_at, reply.at));
+            rtt.push(micros(r.sender_at, reply.at) - micros(r.receive_at, r.transmit_at));
+        }
+
+        // A stateful reflector numbers what it sent from zero, what it never
+        // got is lost forward, what it sent but never came back backward. A
+        // stateless one copies the sender sequence number, which says nothing
+        // about lost packets. A stateful one which lost nothing before any
+        // answer looks the same and only gets the round trip loss as well.
+        let received = replies.len() as u32;
+        let stateless = !replies.is_empty()
+            && replies
+                .iter()
+                .all(|r| r.reflected.seq == r.reflected.sender_seq);
+        let (forward_lost, backward_lost) = if stateless {
+            (0, 0)
+        } else {
+            let reflected = replies
+                .iter()
+                .map(|r| r.reflected.seq.saturating_add(1))
+                .max()
+                .unwrap_or(0)
+                .clamp(received, sent);
+            (sent - reflected, reflected - received)
+        };
+
+        // The reflector numbers packets in the order they reached it, copied
+        // numbers leave only the round trip order.
+        let mut by_reflector: Vec<_> = replies.iter().map(|r| &r.reflected).collect();
+        by_reflector.sort_by_key(|r| r.seq);
+
+        TwampResult {
+            id: command.id,
+            ip: command.ip,
+            port: command.port,
+            start_at,
+            sent,
+            received,
+            forward_lost,
+            backward_lost,
+            forward: Delay::from_micros(&forward),
+            backward: Delay::from_micros(&backward),
+            rtt: Delay::from_micros(&rtt),
+            forward_reordered: reordered(by_reflector.iter().map(|r| r.sender_seq)),
+            backward_reordered: reordered(replies.iter().map(|r| r.reflected.seq)),
+            duplicates,
+            synchronized: twamp::clock_synchronized()
+                && !replies.is_empty()
+                && replies.iter().all(|r| r.reflected.synchronized),
+            stateless,
+        }
+    }
+
+    async fn detect(command: TwampCommand, budget: Budget, result_tx: ResultTx<TwampResult>) {
+        let result = match Self::session(&command, &budget).await {
+            Ok(result) => result,
+            Err(e) => {
+                warn!(
+                    "Twamp session to {}:{} fail, err:{}",
+                    command.ip, command.port, e
+                );
+                return;
+            }
+        };
+
+        info!(
+            "Twamp session to {}:{}, sent:{} received:{}",
+            command.ip, command.port, result.sent, result.received
+        );
+        result_tx
+            .send(result)
+            .await
+            .expect("Send twamp result fail");
+    }
+}
+
+impl Detector for TwampDetector {
+    type Command = TwampCommand;
+    type Result = TwampResult;
+
+    async fn apply(&mut self, commands: Vec<TwampCommand>, result_tx: &ResultTx<TwampResult>) {
+        for command in commands {
+            tokio::spawn(Self::detect(
+                command,
+                self.budget.clone(),
+                result_tx.clone(),
+            ));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    fn command() -> TwampCommand {
+        TwampCommand {
+            id: 1,
+            ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: twamp::DEFAULT_PORT,
+            count: 5,
+            interval: Duration::ZERO,
+            timeout: Duration::from_secs(1),
+            size: twamp::REFLECTED_LEN,
+        }
+    }
+
+    /// Answer `seq` of the reflector to sender packet `sender_seq`, sent every
+    /// 10ms and taking 1ms each way.
+    fn reply(sender_seq: u32, seq: u32) -> Reply {
+        let sender_at = UNIX_EPOCH + Duration::from_millis(10 * u64::from(sender_seq));
+        Reply {
+            reflected: Reflected {
+                seq,
+                receive_at: sender_at + Duration::from_millis(1),
+                transmit_at: sender_at + Duration::from_millis(1),
+                synchronized: false,
+                sender_seq,
+                sender_at,
+                sender_ttl: 255,
+            },
+            at: sender_at + Duration::from_millis(2),
+        }
+    }
+
+    fn summarize(replies: Vec<Reply>) -> TwampResult {
+        TwampDetector::summarize(&command(), UNIX_EPOCH, 5, replies)
+    }
+
+    #[test]
+    fn split_loss_of_stateful_reflectors() {
+        // Packet 1 is lost forward, the answer to packet 2 backward.
+        let result = summarize(vec![reply(0, 0), reply(3, 2), reply(4, 3)]);
+        assert!(!result.stateless);
+        assert_eq!(result.received, 3);
+        assert_eq!(result.forward_lost, 1);
+        assert_eq!(result.backward_lost, 1);
+        let rtt = result.rtt.unwrap();
+        assert_eq!((rtt.min, rtt.mean, rtt.max), (2000, 2000, 2000));
+        assert_eq!(result.forward.unwrap().mean, 1000);
+    }
+
+    #[test]
+    fn keep_loss_of_stateless_reflectors_round_trip() {
+        // The tail is lost, forward or backward cannot be told.
+        let result = summarize(vec![reply(0, 0), reply(1, 1), reply(2, 2)]);
+        assert!(result.stateless);
+        assert_eq!(result.sent - result.received, 2);
+        assert_eq!(result.forward_lost, 0);
+        assert_eq!(result.backward_lost, 0);
+    }
+
+    #[test]
+    fn count_reordering_by_direction() {
+        // Packet 1 overtook packet 0 on the way there, the answer to packet 3
+        // overtook the one to packet 2 on the way back.
+        let result = summarize(vec![
+            reply(1, 0),
+            reply(0, 1),
+            reply(3, 3),
+            reply(2, 2),
+            reply(4, 4),
+        ]);
+        assert!(!result.stateless);
+        assert_eq!(result.forward_reordered, 1);
+        assert_eq!(result.backward_reordered, 1);
+        assert_eq!((result.forward_lost, result.backward_lost), (0, 0));
+    }
+
+    #[test]
+    fn drop_stray_and_duplicate_answers() {
+        let result = summarize(vec![reply(0, 0), reply(0, 0), reply(7, 1)]);
+        assert_eq!(result.received, 1);
+        assert_eq!(result.duplicates, 1);
+    }
+
+    #[test]
+    fn nothing_came_back() {
+        let result = summarize(Vec::new());
+        assert!(!result.stateless);
+        assert_eq!((result.forward_lost, result.backward_lost), (5, 0));
+        assert!(result.rtt.is_none());
+    }
+}
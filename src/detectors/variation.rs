@@ -0,0 +1,86 @@
+use std::time::Duration;
+
+/// RFC 3550 jitter gain.
+const JITTER_GAIN: f64 = 1.0 / 16.0;
+
+/// Delay variation of one target, derived from consecutive RTTs.
+#[derive(Default)]
+pub(super) struct DelayVariation {
+    /// Last reply, `(seq, rtt)`.
+    last: Option<(u16, Duration)>,
+    jitter_micros: f64,
+}
+
+impl DelayVariation {
+    /// Feed the probe `seq`. Returns the IPDV (RFC 3393) against the previous
+    /// probe in micros, if both of them were answered.
+    pub(super) fn on_probe(&mut self, seq: u16, rtt: Option<Duration>) -> Option<i64> {
+        let rtt = rtt?;
+
+        let mut ipdv = None;
+        if let Some((last_seq, last_rtt)) = self.last {
+            let d = rtt.as_micros() as i64 - last_rtt.as_micros() as i64;
+            // Interarrival jitter of RFC 3550, with RTT standing in for transit time.
+            self.jitter_micros += (d.unsigned_abs() as f64 - self.jitter_micros) * JITTER_GAIN;
+            if last_seq.wrapping_add(1) == seq {
+                ipdv = Some(d);
+            }
+        }
+
+        self.last = Some((seq, rtt));
+        ipdv
+    }
+
+    pub(super) fn jitter(&self) -> Duration {
+        Duration::from_micros(self.jitter_micros as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(n: u64) -> Option<Duration> {
+        Some(Duration::from_millis(n))
+    }
+
+    #[test]
+    fn jitter_recursion() {
+        let mut variation = DelayVariation::default();
+        let rtts = [10, 14, 11, 11, 30, 12];
+        let mut expected = 0.0;
+        let mut last = None;
+        for (seq, rtt) in rtts.into_iter().enumerate() {
+            variation.on_probe(seq as u16, ms(rtt));
+            if let Some(last) = last {
+                // J(i) = J(i-1) + (|D(i-1,i)| - J(i-1))/16
+                let d = (rtt as f64 - last as f64).abs() * 1_000.0;
+                expected += (d - expected) / 16.0;
+            }
+            last = Some(rtt);
+        }
+        assert_eq!(variation.jitter(), Duration::from_micros(expected as u64));
+        assert!(variation.jitter() > Duration::from_millis(2));
+    }
+
+    #[test]
+    fn ipdv_of_consecutive_replies() {
+        let mut variation = DelayVariation::default();
+        assert_eq!(variation.on_probe(0, ms(10)), None);
+        assert_eq!(variation.on_probe(1, ms(14)), Some(4_000));
+        assert_eq!(variation.on_probe(2, ms(11)), Some(-3_000));
+        // A loss breaks the pair, the jitter still follows the next reply.
+        assert_eq!(variation.on_probe(3, None), None);
+        let jitter = variation.jitter();
+        assert_eq!(variation.on_probe(4, ms(27)), None);
+        assert!(variation.jitter() > jitter);
+        assert_eq!(variation.on_probe(5, ms(27)), Some(0));
+    }
+
+    #[test]
+    fn ipdv_across_wrap() {
+        let mut variation = DelayVariation::default();
+        variation.on_probe(u16::MAX, ms(5));
+        assert_eq!(variation.on_probe(0, ms(6)), Some(1_000));
+    }
+}
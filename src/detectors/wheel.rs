@@ -0,0 +1,236 @@
+use std::mem;
+
+const LEVEL_BITS: u32 = 6;
+const SLOTS: usize = 1 << LEVEL_BITS;
+const LEVELS: usize = 6;
+const MAX_TICK: u64 = (1 << (LEVEL_BITS * LEVELS as u32)) - 1;
+const TOP_SLOT_TICKS: u64 = 1 << (LEVEL_BITS * (LEVELS as u32 - 1));
+
+struct Level<T> {
+    occupied: u64,
+    slots: Vec<Vec<(u64, T)>>,
+}
+
+impl<T> Level<T> {
+    fn new() -> Self {
+        let slots = (0..SLOTS).map(|_| Vec::new()).collect();
+        Self { occupied: 0, slots }
+    }
+}
+
+/// Hierarchical timer wheel. Level `n` has 64 slots of `64^n` ticks each, six
+/// levels cover about 2 years of 1ms ticks and later entries are clamped to
+/// that. Entries cascade to lower levels as time advances, so insert is O(1)
+/// and polling only touches occupied slots.
+pub(super) struct TimerWheel<T> {
+    elapsed: u64,
+    levels: Vec<Level<T>>,
+    expired: Vec<T>,
+}
+
+impl<T> TimerWheel<T> {
+    pub(super) fn new() -> Self {
+        let levels = (0..LEVELS).map(|_| Level::new()).collect();
+        Self {
+            elapsed: 0,
+            levels,
+            expired: Vec::new(),
+        }
+    }
+
+    /// The level is picked by the most significant bit `when` differs from
+    /// `elapsed`, so an entry always lands in a slot ahead of the current one.
+    fn level_for(elapsed: u64, when: u64) -> usize {
+        let masked = ((elapsed ^ when) | (SLOTS as u64 - 1)).min(MAX_TICK);
+        let significant = 63 - masked.leading_zeros() as usize;
+        significant / LEVEL_BITS as usize
+    }
+
+    fn slot_for(when: u64, level: usize) -> usize {
+        ((when >> (level as u32 * LEVEL_BITS)) as usize) & (SLOTS - 1)
+    }
+
+    pub(super) fn insert(&mut self, when: u64, item: T) {
+        if when <= self.elapsed {
+            self.expired.push(item);
+            return;
+        }
+
+        // Later ticks would wrap into the current slot of the top level.
+        let latest = (self.elapsed & !(TOP_SLOT_TICKS - 1)) + MAX_TICK;
+        let when = when.min(latest);
+        let level = Self::level_for(self.elapsed, when);
+        let slot = Self::slot_for(when, level);
+        let level = &mut self.levels[level];
+        level.slots[slot].push((when, item));
+        level.occupied |= 1 << slot;
+    }
+
+    fn next_expiration(&self) -> Option<(usize, usize, u64)> {
+        for (n, level) in self.levels.iter().enumerate() {
+            if level.occupied == 0 {
+                continue;
+            }
+
+            let slot_range = 1_u64 << (n as u32 * LEVEL_BITS);
+            let level_range = slot_range << LEVEL_BITS;
+            let now_slot = Self::slot_for(self.elapsed, n);
+            let distance = level
+                .occupied
+                .rotate_right(now_slot as u32)
+                .trailing_zeros() as usize;
+            let slot = (now_slot + distance) % SLOTS;
+
+            let level_start = self.elapsed & !(level_range - 1);
+            let mut deadline = level_start + slot as u64 * slot_range;
+            if slot < now_slot {
+                deadline += level_range;
+            }
+            return Some((n, slot, deadline.max(self.elapsed)));
+        }
+
+        None
+    }
+
+    /// Tick of the earliest pending entry, possibly in the past.
+    pub(super) fn next_deadline(&self) -> Option<u64> {
+        if !self.expired.is_empty() {
+            return Some(self.elapsed);
+        }
+        self.next_expiration().map(|(_, _, deadline)| deadline)
+    }
+
+    /// Advance to `now` and move every entry due at or before it into `out`.
+    pub(super) fn poll(&mut self, now: u64, out: &mut Vec<T>) {
+        out.append(&mut self.expired);
+
+        while let Some((level, slot, deadline)) = self.next_expiration() {
+            if deadline > now {
+                break;
+            }
+
+            self.elapsed = deadline;
+            let level = &mut self.levels[level];
+            level.occupied &= !(1 << slot);
+            for (when, item) in mem::take(&mut level.slots[slot]) {
+                if when <= self.elapsed {
+                    out.push(item);
+                } else {
+                    self.insert(when, item);
+                }
+            }
+        }
+
+        self.elapsed = self.elapsed.max(now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::SmallRng;
+    use rand::{Rng, SeedableRng};
+
+    fn poll(wheel: &mut TimerWheel<u64>, now: u64) -> Vec<u64> {
+        let mut out = Vec::new();
+        wheel.poll(now, &mut out);
+        out.sort();
+        out
+    }
+
+    #[test]
+    fn expire_in_order() {
+        let mut wheel = TimerWheel::new();
+        wheel.insert(10, 10);
+        wheel.insert(5, 5);
+        wheel.insert(5, 50);
+        assert_eq!(wheel.next_deadline(), Some(5));
+
+        assert!(poll(&mut wheel, 4).is_empty());
+        assert_eq!(poll(&mut wheel, 5), [5, 50]);
+        assert_eq!(wheel.next_deadline(), Some(10));
+        assert_eq!(poll(&mut wheel, 20), [10]);
+        assert_eq!(wheel.next_deadline(), None);
+    }
+
+    #[test]
+    fn expire_past_entries_at_once() {
+        let mut wheel = TimerWheel::new();
+        assert!(poll(&mut wheel, 100).is_empty());
+        wheel.insert(100, 1);
+        wheel.insert(3, 2);
+        assert_eq!(wheel.next_deadline(), Some(100));
+        assert_eq!(poll(&mut wheel, 100), [1, 2]);
+    }
+
+    #[test]
+    fn cascade_across_levels() {
+        let mut wheel = TimerWheel::new();
+        let whens = [63, 64, 65, 4095, 4096, 4097, 262_143, 262_144, 16_777_300];
+        for when in whens {
+            wheel.insert(when, when);
+        }
+        // Entries of higher levels are moved down on the way, none too early.
+        for when in whens {
+            assert!(poll(&mut wheel, when - 1).is_empty(), "{}", when);
+            assert_eq!(wheel.next_deadline(), Some(when), "{}", when);
+            assert_eq!(poll(&mut wheel, when), [when]);
+        }
+    }
+
+    #[test]
+    fn reinsert_earlier() {
+        // The wheel cannot cancel, the scheduler inserts again with a newer
+        // version and ignores the stale entry when it comes out.
+        let mut wheel = TimerWheel::new();
+        wheel.insert(5_000, 0);
+        wheel.insert(10, 1);
+        assert_eq!(poll(&mut wheel, 10), [1]);
+        assert_eq!(poll(&mut wheel, 4_999), Vec::<u64>::new());
+        assert_eq!(poll(&mut wheel, 5_000), [0]);
+    }
+
+    #[test]
+    fn clamp_delays_beyond_the_top_level() {
+        let mut wheel = TimerWheel::new();
+        let start = 5 * TOP_SLOT_TICKS + 1_000;
+        poll(&mut wheel, start);
+        wheel.insert(u64::MAX, 1);
+        wheel.insert(start + MAX_TICK, 2);
+        // They expire at the end of the last top level slot ahead.
+        let latest = 5 * TOP_SLOT_TICKS + MAX_TICK;
+        assert!(wheel.next_deadline() <= Some(latest));
+        assert!(poll(&mut wheel, latest - 1).is_empty());
+        assert_eq!(poll(&mut wheel, latest), [1, 2]);
+    }
+
+    #[test]
+    fn never_early_never_late() {
+        let mut rng = SmallRng::seed_from_u64(7);
+        let mut wheel = TimerWheel::new();
+        let mut now = 0;
+        for _ in 0..2_000 {
+            let delay = match rng.gen_range(0..3) {
+                0 => rng.gen_range(1..64),
+                1 => rng.gen_range(1..10_000),
+                _ => rng.gen_range(1..1_000_000),
+            };
+            wheel.insert(now + delay, now + delay);
+            let before = now;
+            now += rng.gen_range(0..5_000);
+            for when in poll(&mut wheel, now) {
+                assert!(
+                    when > before && when <= now,
+                    "{} out of {}..={}",
+                    when,
+                    before,
+                    now
+                );
+            }
+        }
+        now += 2_000_000;
+        let rest = poll(&mut wheel, now);
+        assert!(rest.iter().all(|&when| when <= now));
+        assert_eq!(wheel.next_deadline(), None);
+    }
+}
@@ -0,0 +1,24 @@
+use crate::structures::{Aggregate, Anomaly, BudgetStats, PathChange, StateChange};
+use tokio::sync::broadcast;
+
+const EVENT_BUFFER: usize = 1024;
+
+pub type EventTx = broadcast::Sender<Event>;
+pub type EventRx = broadcast::Receiver<Event>;
+
+/// Something detectors noticed about a target, as opposed to a probe result.
+#[derive(Debug, Clone)]
+pub enum Event {
+    State(StateChange),
+    Anomaly(Anomaly),
+    PathChange(PathChange),
+    /// A closed aggregation window, published for local consumers only.
+    Window(Aggregate),
+    /// Periodic counters of a packet budget.
+    Budget(BudgetStats),
+}
+
+/// The agent wide event bus, every consumer subscribes to the returned sender.
+pub fn channel() -> EventTx {
+    broadcast::channel(EVENT_BUFFER).0
+}
@@ -1,2 +1,2 @@
-pub(crate) mod collector_grpc;
-pub(crate) mod controller_grpc;
+pub mod collector_grpc;
+pub mod controller_grpc;
@@ -0,0 +1,156 @@
+use std::collections::BTreeMap;
+
+/// Values below `2^SUB_BUCKET_BITS` are recorded exactly, larger ones in
+/// buckets of `2^-(SUB_BUCKET_BITS - 1)` relative width, below 1.6% error.
+const SUB_BUCKET_BITS: u32 = 7;
+const SUB_BUCKET_HALF: u64 = 1 << (SUB_BUCKET_BITS - 1);
+
+/// Log-linear histogram in the style of HDR histogram, sparse so an idle
+/// target costs only a few buckets.
+#[derive(Debug, Default, Clone)]
+pub struct Histogram {
+    buckets: BTreeMap<u32, u64>,
+    count: u64,
+    min: u64,
+    max: u64,
+}
+
+impl Histogram {
+    fn index(v: u64) -> u32 {
+        if v < SUB_BUCKET_HALF << 1 {
+            return v as u32;
+        }
+        let shift = 63 - v.leading_zeros() - (SUB_BUCKET_BITS - 1);
+        (shift << (SUB_BUCKET_BITS - 1)) + (v >> shift) as u32
+    }
+
+    /// Lowest value and width of the bucket at `index`.
+    fn bucket(index: u32) -> (u64, u64) {
+        let half = SUB_BUCKET_HALF as u32;
+        if index < half << 1 {
+            return (u64::from(index), 1);
+        }
+        let shift = index / half - 1;
+        let sub = u64::from(index - shift * half);
+        (sub << shift, 1 << shift)
+    }
+
+    pub fn record(&mut self, v: u64) {
+        *self.buckets.entry(Self::index(v)).or_default() += 1;
+        if self.count == 0 || v < self.min {
+            self.min = v;
+        }
+        self.max = self.max.max(v);
+        self.count += 1;
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn min(&self) -> u64 {
+        self.min
+    }
+
+    pub fn max(&self) -> u64 {
+        self.max
+    }
+
+    /// Value at quantile `q` in `[0, 1]`, 0 when nothing was recorded.
+    pub fn quantile(&self, q: f64) -> u64 {
+        if self.count == 0 {
+            return 0;
+        }
+
+        let rank = ((q * self.count as f64).ceil() as u64).clamp(1, self.count);
+        let mut seen = 0;
+        for (&index, &n) in &self.buckets {
+            seen += n;
+            if seen >= rank {
+                let (low, width) = Self::bucket(index);
+                return (low + width / 2).clamp(self.min, self.max);
+            }
+        }
+
+        self.max
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Values spread over every magnitude, including both edges.
+    fn values() -> impl Iterator<Item = u64> {
+        (0..64)
+            .flat_map(|bit| [1_u64 << bit, (1 << bit) + 1, (1 << bit) | ((1 << bit) - 1)])
+            .chain([0, 127, 128, 129, 1_000_003, u64::MAX - 1, u64::MAX])
+    }
+
+    #[test]
+    fn small_values_are_exact() {
+        for v in 0..128 {
+            assert_eq!(Histogram::bucket(Histogram::index(v)), (v, 1));
+        }
+    }
+
+    #[test]
+    fn bucket_holds_its_values() {
+        for v in values() {
+            let (low, width) = Histogram::bucket(Histogram::index(v));
+            assert!(low <= v && v - low < width, "{} in {}+{}", v, low, width);
+            if v >= 128 {
+                // The width is at most 1/64 of the values in the bucket.
+                assert!(width <= low / 64, "{} in {}+{}", v, low, width);
+            }
+        }
+    }
+
+    #[test]
+    fn index_is_monotonic() {
+        let mut values: Vec<_> = values().collect();
+        values.sort();
+        for pair in values.windows(2) {
+            let (a, b) = (Histogram::index(pair[0]), Histogram::index(pair[1]));
+            assert!(a <= b, "{} {}", pair[0], pair[1]);
+        }
+    }
+
+    #[test]
+    fn quantile_within_relative_error() {
+        let mut histogram = Histogram::default();
+        for v in 1..=100_000 {
+            histogram.record(v);
+        }
+        assert_eq!(histogram.count(), 100_000);
+        for q in [0.01, 0.1, 0.5, 0.9, 0.99, 0.999] {
+            let expected = q * 100_000.0;
+            let got = histogram.quantile(q) as f64;
+            assert!(
+                (got - expected).abs() <= expected / 64.0,
+                "q{} {} {}",
+                q,
+                got,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn quantile_edges() {
+        let mut histogram = Histogram::default();
+        assert_eq!(histogram.quantile(0.5), 0);
+
+        histogram.record(0);
+        assert_eq!((histogram.min(), histogram.max()), (0, 0));
+        assert_eq!(histogram.quantile(1.0), 0);
+
+        histogram.record(u64::MAX);
+        assert_eq!((histogram.min(), histogram.max()), (0, u64::MAX));
+        assert_eq!(histogram.quantile(0.0), 0);
+        assert_eq!(histogram.quantile(0.5), 0);
+        // The top bucket answers with its midpoint, without overflow.
+        let top = histogram.quantile(1.0);
+        assert!(u64::MAX - top <= u64::MAX / 64, "{}", top);
+    }
+}
@@ -1,9 +1,18 @@
+pub mod aggregator;
+pub mod alert;
+pub mod asn;
+pub mod budget;
 pub mod commander;
 pub mod conf;
 pub mod detectors;
+pub mod events;
 pub mod grpc;
+pub mod histogram;
+pub mod rdns;
+pub mod registry;
 pub mod reporter;
 pub mod structures;
+pub mod twamp;
 
 #[macro_export]
 macro_rules! backoff {
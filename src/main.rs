@@ -1,12 +1,21 @@
 use futures::future;
+use ping_agent::alert::Alerter;
+use ping_agent::asn::AsnDb;
+use ping_agent::budget::Budgets;
 use ping_agent::commander::SuperCommander;
 use ping_agent::conf;
-use ping_agent::detectors::FpingDetector;
-use ping_agent::detectors::{PingDetector, TcpPingDetector};
+use ping_agent::detectors::{
+    FpingDetector, MtrDetector, PingDetector, PmtuDetector, TcpPingDetector, TimestampDetector,
+    TwampDetector,
+};
+use ping_agent::events;
+use ping_agent::rdns::Resolver;
+use ping_agent::registry::Registry;
 use ping_agent::reporter::Reporter;
+use ping_agent::twamp::Reflector;
 use std::process;
-use tokio::sync::mpsc;
 use tokio::task;
+use tokio::time::Duration;
 use tracing::error;
 
 const LVL: tracing::Level = tracing::Level::INFO;
@@ -29,7 +38,20 @@ async fn main() {
         }
     };
 
-    let reporter = Reporter::new(&conf.collector.url, conf.agent.id);
+    let event_tx = events::channel();
+
+    let asn = AsnDb::new(&conf.asn).await.unwrap_or_else(|e| {
+        error!("Load asn table: {:#}", e);
+        process::exit(exitcode::CONFIG);
+    });
+
+    let reporter = Reporter::new(
+        &conf.collector.url,
+        conf.agent.id,
+        &conf.aggregation,
+        event_tx.clone(),
+        asn.clone(),
+    );
     let reporter = match reporter {
         Ok(reporter) => reporter,
         Err(e) => {
@@ -38,44 +60,66 @@ async fn main() {
         }
     };
 
-    let mut handlers = vec![];
+    let alerter = match Alerter::new(&conf.alert) {
+        Ok(alerter) => alerter,
+        Err(e) => {
+            error!("Parse alert webhook url: {}", e);
+            process::exit(exitcode::CONFIG);
+        }
+    };
+
+    // Subscribe before any detector starts publishing.
+    let mut handlers = vec![task::spawn(
+        reporter.clone().report_events(event_tx.subscribe()),
+    )];
+    if let Some(alerter) = alerter {
+        handlers.push(task::spawn(alerter.run(event_tx.subscribe())));
+    }
+    if let Some(asn) = asn {
+        let interval = Duration::from_secs(conf.asn.reload_secs.max(1));
+        handlers.push(task::spawn(asn.watch(interval)));
+    }
 
-    // icmp ping pipe
-    let (ping_command_tx, ping_command_rx) = mpsc::channel(16);
-    let (ping_result_tx, ping_result_rx) = mpsc::channel(1024);
-    let c = super_commander.build_commander();
-    let ping_detector = PingDetector::new();
-    handlers.push(task::spawn(c.forward_ping_command(ping_command_tx)));
-    handlers.push(task::spawn(
-        ping_detector.detect(ping_command_rx, ping_result_tx),
-    ));
-    let r = reporter.clone();
-    handlers.push(task::spawn(r.report_ping_result(ping_result_rx)));
+    let rdns = Resolver::new(&conf.rdns).await.unwrap_or_else(|e| {
+        error!("Init reverse lookups: {:#}", e);
+        process::exit(exitcode::CONFIG);
+    });
+
+    let reflector = Reflector::new(&conf.twamp).unwrap_or_else(|e| {
+        error!("Init twamp reflector: {:#}", e);
+        process::exit(exitcode::CONFIG);
+    });
+    if let Some(reflector) = reflector {
+        handlers.push(task::spawn(reflector.run()));
+    }
 
-    // tcp ping pipe
-    let (tcp_ping_command_tx, tcp_ping_command_rx) = mpsc::channel(16);
-    let (tcp_ping_result_tx, tcp_ping_result_rx) = mpsc::channel(1024);
-    let tcp_ping_detector = TcpPingDetector::new();
-    let c = super_commander.build_commander();
-    handlers.push(task::spawn(c.forward_tcp_ping_command(tcp_ping_command_tx)));
-    handlers.push(task::spawn(
-        tcp_ping_detector.detect(tcp_ping_command_rx, tcp_ping_result_tx),
-    ));
-    let r = reporter.clone();
-    handlers.push(task::spawn(r.report_tcp_ping_result(tcp_ping_result_rx)));
+    let mut budgets = Budgets::new(&conf.budget);
 
-    // fping pipe
-    let (fping_command_tx, fping_command_rx) = mpsc::channel(16);
-    let (fping_result_tx, fping_result_rx) = mpsc::channel(1024);
-    let c = super_commander.build_commander();
-    handlers.push(task::spawn(c.forward_fping_command(fping_command_tx)));
-    handlers.push(task::spawn(FpingDetector::detect(
-        fping_command_rx,
-        fping_result_tx,
-    )));
-    let r = reporter.clone();
-    handlers.push(task::spawn(r.report_fping_result(fping_result_rx)));
+    let mut registry = Registry::new();
+    registry
+        .register(PingDetector::new(
+            budgets.protocol("ping"),
+            &conf.detector,
+            event_tx.clone(),
+        ))
+        .register(TcpPingDetector::new(
+            budgets.protocol("tcp_ping"),
+            &conf.detector,
+            event_tx.clone(),
+        ))
+        .register(FpingDetector::new(budgets.protocol("fping")))
+        .register(MtrDetector::new(
+            budgets.protocol("mtr"),
+            &conf.traceroute,
+            &event_tx,
+            rdns,
+        ))
+        .register(PmtuDetector::new(budgets.protocol("pmtu")))
+        .register(TimestampDetector::new(budgets.protocol("timestamp")))
+        .register(TwampDetector::new(budgets.protocol("twamp")));
 
+    handlers.extend(registry.spawn(&super_commander, &reporter));
+    handlers.push(task::spawn(budgets.publish_stats(event_tx.clone())));
     handlers.push(task::spawn(super_commander.register()));
 
     future::join_all(handlers).await;
@@ -0,0 +1,504 @@
+use crate::conf;
+use anyhow::{anyhow, bail, Context, Result};
+use bytes::{BufMut, BytesMut};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use tokio::fs;
+use tokio::net::UdpSocket;
+use tokio::time::{self, Duration, Instant};
+use tracing::{debug, info};
+
+const DNS_PORT: u16 = 53;
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+const HEADER_LEN: usize = 12;
+const MAX_MESSAGE: usize = 1232;
+const FLAG_QR: u16 = 0x8000;
+const FLAG_RD: u16 = 0x0100;
+const RCODE_NOERROR: u16 = 0;
+const RCODE_NXDOMAIN: u16 = 3;
+const TYPE_PTR: u16 = 12;
+const TYPE_SOA: u16 = 6;
+const CLASS_IN: u16 = 1;
+/// Compression pointers a name may follow before it is taken as a loop.
+const MAX_POINTERS: usize = 16;
+
+/// PTR name of `ip`, `None` when it has none.
+type Answer = Option<String>;
+
+/// Reverse lookups of trace hops against one DNS server. Answers and missing
+/// names are cached for their TTL, failed lookups are not cached. Cheap to
+/// clone, clones share the cache.
+#[derive(Clone)]
+pub struct Resolver {
+    server: SocketAddr,
+    timeout: Duration,
+    negative_ttl: Duration,
+    max_entries: usize,
+    cache: Arc<Mutex<HashMap<IpAddr, (Answer, Instant)>>>,
+}
+
+impl Resolver {
+    /// `None` when lookups are disabled. Without a configured server the first
+    /// nameserver of resolv.conf is used.
+    pub async fn new(conf: &conf::Rdns) -> Result<Option<Self>> {
+        if !conf.enable {
+            return Ok(None);
+        }
+
+        let server = match conf.server.as_str() {
+            "" => Self::system_server().await?,
+            server => server
+                .parse::<SocketAddr>()
+                .or_else(|_| server.parse().map(|ip| SocketAddr::new(ip, DNS_PORT)))
+                .with_context(|| format!("parse dns server {}", server))?,
+        };
+        info!("Reverse lookups against {}", server);
+
+        Ok(Some(Self {
+            server,
+            timeout: Duration::from_millis(conf.timeout_ms),
+            negative_ttl: Duration::from_secs(conf.negative_ttl_secs),
+            max_entries: conf.max_entries,
+            cache: Arc::new(Mutex::new(HashMap::new())),
+        }))
+    }
+
+    async fn system_server() -> Result<SocketAddr> {
+        let resolv = fs::read_to_string(RESOLV_CONF)
+            .await
+            .with_context(|| format!("read {}", RESOLV_CONF))?;
+        resolv
+            .lines()
+            .filter_map(|line| line.trim().strip_prefix("nameserver"))
+            .find_map(|ip| ip.trim().parse().ok())
+            .map(|ip| SocketAddr::new(ip, DNS_PORT))
+            .ok_or_else(|| anyhow!("no nameserver in {}", RESOLV_CONF))
+    }
+
+    /// Host name of `ip`, `None` when it has none or the lookup failed.
+    pub async fn lookup(&self, ip: IpAddr) -> Option<String> {
+        let now = Instant::now();
+        if let Some((answer, expires)) = self.cache.lock().unwrap().get(&ip) {
+            if *expires > now {
+                return answer.clone();
+            }
+        }
+
+        let (answer, ttl) = match time::timeout(self.timeout, self.query(ip)).await {
+            Ok(Ok(answer)) => answer,
+            Ok(Err(e)) => {
+                debug!("Reverse lookup of {} fail, err:{:#}", ip, e);
+                return None;
+            }
+            Err(_) => {
+                debug!("Reverse lookup of {} timeout", ip);
+                return None;
+            }
+        };
+
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= self.max_entries {
+            cache.retain(|_, (_, expires)| *expires > now);
+        }
+        if cache.len() < self.max_entries {
+            cache.insert(ip, (answer.clone(), now + ttl));
+        }
+        answer
+    }
+
+    async fn query(&self, ip: IpAddr) -> Result<(Answer, Duration)> {
+        let bind: SocketAddr = match self.server {
+            SocketAddr::V4(_) => "0.0.0.0:0".parse().unwrap(),
+            SocketAddr::V6(_) => "[::]:0".parse().unwrap(),
+        };
+        let sock = UdpSocket::bind(bind).await?;
+        sock.connect(self.server).await?;
+        let id = rand::random();
+        sock.send(&request(id, &ptr_name(ip))).await?;
+
+        let mut buf = vec![0; MAX_MESSAGE];
+        loop {
+            let len = sock.recv(&mut buf).await?;
+            // Stray or spoofed answers are skipped, the timeout bounds the wait.
+            if let Some(answer) = parse(&buf[..len], id, self.negative_ttl)? {
+                return Ok(answer);
+            }
+        }
+    }
+}
+
+/// `4.3.2.1.in-addr.arpa` or the nibbles of an IPv6 address under `ip6.arpa`.
+fn ptr_name(ip: IpAddr) -> String {
+    let mut name = String::new();
+    match ip {
+        IpAddr::V4(ip) => {
+            for octet in ip.octets().iter().rev() {
+                let _ = write!(name, "{}.", octet);
+            }
+            name.push_str("in-addr.arpa");
+        }
+        IpAddr::V6(ip) => {
+            for octet in ip.octets().iter().rev() {
+                let _ = write!(name, "{:x}.{:x}.", octet & 0x0f, octet >> 4);
+            }
+            name.push_str("ip6.arpa");
+        }
+    }
+    name
+}
+
+fn request(id: u16, name: &str) -> BytesMut {
+    let mut buf = BytesMut::with_capacity(HEADER_LEN + name.len() + 6);
+    buf.put_u16(id);
+    buf.put_u16(FLAG_RD);
+    buf.put_u16(1);
+    buf.put_u16(0);
+    buf.put_u16(0);
+    buf.put_u16(0);
+    for label in name.split('.') {
+        buf.put_u8(label.len() as u8);
+        buf.put_slice(label.as_bytes());
+    }
+    buf.put_u8(0);
+    buf.put_u16(TYPE_PTR);
+    buf.put_u16(CLASS_IN);
+    buf
+}
+
+fn u16_at(buf: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(buf.get(at..at + 2)?.try_into().ok()?))
+}
+
+fn u32_at(buf: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(buf.get(at..at + 4)?.try_into().ok()?))
+}
+
+/// Read the possibly compressed name at `at`, returns it and where the name
+/// ends in place.
+fn read_name(msg: &[u8], mut at: usize) -> Option<(String, usize)> {
+    let mut name = String::new();
+    let mut end = None;
+    let mut pointers = 0;
+    loop {
+        let len = *msg.get(at)?;
+        match len {
+            0 => return Some((name, end.unwrap_or(at + 1))),
+            len if len & 0xc0 == 0xc0 => {
+                pointers += 1;
+                if pointers > MAX_POINTERS {
+                    return None;
+                }
+                end.get_or_insert(at + 2);
+                at = usize::from(u16_at(msg, at)? & 0x3fff);
+            }
+            len => {
+                let label = msg.get(at + 1..at + 1 + usize::from(len))?;
+                if !name.is_empty() {
+                    name.push('.');
+                }
+                name.push_str(&String::from_utf8_lossy(label));
+                at += 1 + usize::from(len);
+            }
+        }
+    }
+}
+
+/// The answer of a response to query `id`, `None` if it answers another query.
+/// A missing name is cached for the SOA minimum of the zone, RFC 2308, or
+/// `negative_ttl` without one.
+fn parse(msg: &[u8], id: u16, negative_ttl: Duration) -> Result<Option<(Answer, Duration)>> {
+    let truncated = || anyhow!("truncated dns response");
+    let flags = u16_at(msg, 2).ok_or_else(truncated)?;
+    if u16_at(msg, 0) != Some(id) || flags & FLAG_QR == 0 {
+        return Ok(None);
+    }
+    let rcode = flags & 0x000f;
+    if rcode != RCODE_NOERROR && rcode != RCODE_NXDOMAIN {
+        bail!("dns rcode {}", rcode);
+    }
+
+    let count = |at| u16_at(msg, at).ok_or_else(truncated);
+    let (questions, answers, authorities) = (count(4)?, count(6)?, count(8)?);
+    let mut at = HEADER_LEN;
+    for _ in 0..questions {
+        at = read_name(msg, at).ok_or_else(truncated)?.1 + 4;
+    }
+
+    let mut negative = None;
+    for i in 0..u32::from(answers) + u32::from(authorities) {
+        let (_, end) = read_name(msg, at).ok_or_else(truncated)?;
+        let kind = u16_at(msg, end).ok_or_else(truncated)?;
+        let ttl = u32_at(msg, end + 4).ok_or_else(truncated)?;
+        let rdlen = usize::from(u16_at(msg, end + 8).ok_or_else(truncated)?);
+        let rdata = end + 10;
+        at = rdata + rdlen;
+
+        if i < u32::from(answers) && kind == TYPE_PTR {
+            let (name, _) = read_name(msg, rdata).ok_or_else(truncated)?;
+            let ttl = Duration::from_secs(u64::from(ttl));
+            return Ok(Some((Some(name), ttl)));
+        }
+        if i >= u32::from(answers) && kind == TYPE_SOA {
+            let (_, mname) = read_name(msg, rdata).ok_or_else(truncated)?;
+            let (_, rname) = read_name(msg, mname).ok_or_else(truncated)?;
+            let minimum = u32_at(msg, rname + 16).ok_or_else(truncated)?;
+            negative = Some(Duration::from_secs(u64::from(ttl.min(minimum))));
+        }
+    }
+    Ok(Some((None, negative.unwrap_or(negative_ttl))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::SmallRng;
+    use rand::{Rng, SeedableRng};
+    use std::net::Ipv4Addr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const SOA_TTL: u32 = 3600;
+    const SOA_MINIMUM: u32 = 60;
+
+    fn put_name(buf: &mut BytesMut, name: &str) {
+        for label in name.split('.') {
+            buf.put_u8(label.len() as u8);
+            buf.put_slice(label.as_bytes());
+        }
+        buf.put_u8(0);
+    }
+
+    fn header(id: u16, rcode: u16, question: &str, answers: u16, authorities: u16) -> BytesMut {
+        let mut buf = BytesMut::new();
+        buf.put_u16(id);
+        buf.put_u16(FLAG_QR | FLAG_RD | rcode);
+        buf.put_u16(1);
+        buf.put_u16(answers);
+        buf.put_u16(authorities);
+        buf.put_u16(0);
+        put_name(&mut buf, question);
+        buf.put_u16(TYPE_PTR);
+        buf.put_u16(CLASS_IN);
+        buf
+    }
+
+    /// A PTR answer whose owner points back at the question.
+    fn ptr_response(id: u16, question: &str, name: &str, ttl: u32) -> Vec<u8> {
+        let mut buf = header(id, RCODE_NOERROR, question, 1, 0);
+        buf.put_u16(0xc000 | HEADER_LEN as u16);
+        buf.put_u16(TYPE_PTR);
+        buf.put_u16(CLASS_IN);
+        buf.put_u32(ttl);
+        buf.put_u16(name.len() as u16 + 2);
+        put_name(&mut buf, name);
+        buf.to_vec()
+    }
+
+    fn nxdomain(id: u16, question: &str) -> Vec<u8> {
+        let mut buf = header(id, RCODE_NXDOMAIN, question, 0, 1);
+        put_name(&mut buf, "in-addr.arpa");
+        buf.put_u16(TYPE_SOA);
+        buf.put_u16(CLASS_IN);
+        buf.put_u32(SOA_TTL);
+        let mut soa = BytesMut::new();
+        put_name(&mut soa, "ns.example");
+        put_name(&mut soa, "hostmaster.example");
+        for value in [1, 7200, 900, 604_800, SOA_MINIMUM] {
+            soa.put_u32(value);
+        }
+        buf.put_u16(soa.len() as u16);
+        buf.put_slice(&soa);
+        buf.to_vec()
+    }
+
+    /// Local DNS server answering every query with `answer(id, question)`,
+    /// counting the queries it got.
+    async fn server<F>(answer: F) -> (SocketAddr, Arc<AtomicUsize>)
+    where
+        F: Fn(u16, &str) -> Vec<u8> + Send + 'static,
+    {
+        let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = sock.local_addr().unwrap();
+        let queries = Arc::new(AtomicUsize::new(0));
+        let counter = queries.clone();
+        tokio::spawn(async move {
+            let mut buf = vec![0; MAX_MESSAGE];
+            loop {
+                let (len, from) = sock.recv_from(&mut buf).await.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+                let id = u16_at(&buf[..len], 0).unwrap();
+                let (question, _) = read_name(&buf[..len], HEADER_LEN).unwrap();
+                sock.send_to(&answer(id, &question), from).await.unwrap();
+            }
+        });
+        (addr, queries)
+    }
+
+    async fn resolver(server: SocketAddr, max_entries: usize) -> Resolver {
+        let conf = conf::Rdns {
+            enable: true,
+            server: server.to_string(),
+            timeout_ms: 1000,
+            negative_ttl_secs: 300,
+            max_entries,
+        };
+        Resolver::new(&conf).await.unwrap().unwrap()
+    }
+
+    fn expires_in(resolver: &Resolver, ip: IpAddr) -> Option<Duration> {
+        let cache = resolver.cache.lock().unwrap();
+        let (_, expires) = cache.get(&ip)?;
+        Some(expires.saturating_duration_since(Instant::now()))
+    }
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(192, 0, 2, last))
+    }
+
+    #[test]
+    fn ptr_names() {
+        assert_eq!(ptr_name(ip(1)), "1.2.0.192.in-addr.arpa");
+        let v6 = ptr_name("2001:db8::1".parse().unwrap());
+        assert!(v6.starts_with("1.0.0.0.0.0.0.0."));
+        assert!(v6.ends_with(".8.b.d.0.1.0.0.2.ip6.arpa"));
+    }
+
+    #[tokio::test]
+    async fn resolve_and_cache_name() {
+        let (addr, queries) = server(|id, q| ptr_response(id, q, "r1.example.net", 600)).await;
+        let resolver = resolver(addr, 16).await;
+
+        assert_eq!(
+            resolver.lookup(ip(1)).await.as_deref(),
+            Some("r1.example.net")
+        );
+        assert_eq!(
+            resolver.lookup(ip(1)).await.as_deref(),
+            Some("r1.example.net")
+        );
+        assert_eq!(queries.load(Ordering::SeqCst), 1);
+        let ttl = expires_in(&resolver, ip(1)).unwrap();
+        assert!(ttl > Duration::from_secs(590) && ttl <= Duration::from_secs(600));
+    }
+
+    #[tokio::test]
+    async fn cache_missing_name_for_soa_minimum() {
+        let (addr, queries) = server(nxdomain).await;
+        let resolver = resolver(addr, 16).await;
+
+        assert_eq!(resolver.lookup(ip(2)).await, None);
+        assert_eq!(resolver.lookup(ip(2)).await, None);
+        assert_eq!(queries.load(Ordering::SeqCst), 1);
+        let ttl = expires_in(&resolver, ip(2)).unwrap();
+        let minimum = Duration::from_secs(u64::from(SOA_MINIMUM));
+        assert!(ttl > minimum - Duration::from_secs(10) && ttl <= minimum);
+    }
+
+    #[test]
+    fn negative_ttl_without_soa() {
+        let msg = header(9, RCODE_NXDOMAIN, "2.2.0.192.in-addr.arpa", 0, 0);
+        let negative_ttl = Duration::from_secs(42);
+        let parsed = parse(&msg, 9, negative_ttl).unwrap();
+        assert_eq!(parsed, Some((None, negative_ttl)));
+    }
+
+    #[tokio::test]
+    async fn evict_expired_entries_when_full() {
+        // The first two names expire at once, the third is kept.
+        let (addr, queries) = server(|id, q| {
+            let ttl = if q.starts_with("3.") { 600 } else { 0 };
+            ptr_response(id, q, "r.example.net", ttl)
+        })
+        .await;
+        let resolver = resolver(addr, 2).await;
+
+        resolver.lookup(ip(1)).await;
+        resolver.lookup(ip(2)).await;
+        assert_eq!(resolver.cache.lock().unwrap().len(), 2);
+        resolver.lookup(ip(3)).await;
+        resolver.lookup(ip(3)).await;
+        assert_eq!(queries.load(Ordering::SeqCst), 3);
+        let cache = resolver.cache.lock().unwrap();
+        assert_eq!(cache.len(), 1);
+        assert!(cache.contains_key(&ip(3)));
+    }
+
+    #[tokio::test]
+    async fn skip_caching_when_full_of_live_entries() {
+        let (addr, queries) = server(|id, q| ptr_response(id, q, "r.example.net", 600)).await;
+        let resolver = resolver(addr, 2).await;
+
+        for last in [1, 2, 3, 3] {
+            assert!(resolver.lookup(ip(last)).await.is_some());
+        }
+        assert_eq!(queries.load(Ordering::SeqCst), 4);
+        assert!(!resolver.cache.lock().unwrap().contains_key(&ip(3)));
+    }
+
+    #[tokio::test]
+    async fn malformed_answer_is_not_cached() {
+        let (addr, queries) = server(|id, q| {
+            let mut msg = ptr_response(id, q, "r.example.net", 600);
+            msg.truncate(msg.len() - 4);
+            msg
+        })
+        .await;
+        let resolver = resolver(addr, 16).await;
+
+        assert_eq!(resolver.lookup(ip(4)).await, None);
+        assert_eq!(resolver.lookup(ip(4)).await, None);
+        assert_eq!(queries.load(Ordering::SeqCst), 2);
+        assert!(resolver.cache.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn truncated_responses_fail() {
+        let question = "5.2.0.192.in-addr.arpa";
+        for msg in [
+            ptr_response(5, question, "r.example.net", 60),
+            nxdomain(5, question),
+        ] {
+            assert!(parse(&msg, 5, Duration::ZERO).unwrap().is_some());
+            for len in 0..msg.len() {
+                assert!(
+                    parse(&msg[..len], 5, Duration::ZERO).is_err(),
+                    "len {}",
+                    len
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn reject_other_queries_and_errors() {
+        let msg = ptr_response(5, "5.2.0.192.in-addr.arpa", "r.example.net", 60);
+        assert_eq!(parse(&msg, 6, Duration::ZERO).unwrap(), None);
+        let servfail = header(5, 2, "5.2.0.192.in-addr.arpa", 0, 0);
+        assert!(parse(&servfail, 5, Duration::ZERO).is_err());
+    }
+
+    #[test]
+    fn compression_loop_fails() {
+        let mut msg = header(5, RCODE_NOERROR, "x", 1, 0);
+        // The answer owner points at itself.
+        let at = msg.len() as u16;
+        msg.put_u16(0xc000 | at);
+        assert!(parse(&msg, 5, Duration::ZERO).is_err());
+    }
+
+    #[test]
+    fn garbage_never_panics() {
+        let mut rng = SmallRng::seed_from_u64(1);
+        for _ in 0..10_000 {
+            let len = rng.gen_range(0..64);
+            let mut msg: Vec<u8> = (0..len).map(|_| rng.gen()).collect();
+            if msg.len() >= 4 {
+                msg[0..2].copy_from_slice(&5u16.to_be_bytes());
+                msg[2] |= 0x80;
+                msg[3] &= 0xf0;
+            }
+            let _ = parse(&msg, 5, Duration::ZERO);
+        }
+    }
+}
@@ -0,0 +1,47 @@
+use crate::commander::{Commander, SuperCommander};
+use crate::detectors::Detector;
+use crate::reporter::Reporter;
+use tokio::sync::mpsc;
+use tokio::task::{self, JoinHandle};
+
+const COMMAND_BUFFER: usize = 16;
+const RESULT_BUFFER: usize = 1024;
+
+type Pipeline = Box<dyn FnOnce(Commander, Reporter) -> Vec<JoinHandle<()>>>;
+
+/// Detectors the agent runs. Each one becomes a command -> detector -> reporter
+/// pipeline when the registry is spawned.
+#[derive(Default)]
+pub struct Registry {
+    pipelines: Vec<Pipeline>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register<D: Detector>(&mut self, detector: D) -> &mut Self {
+        self.pipelines.push(Box::new(move |commander, reporter| {
+            let (command_tx, command_rx) = mpsc::channel(COMMAND_BUFFER);
+            let (result_tx, result_rx) = mpsc::channel(RESULT_BUFFER);
+            vec![
+                task::spawn(commander.forward_command::<D::Command>(command_tx)),
+                task::spawn(detector.run(command_rx, result_tx)),
+                task::spawn(reporter.report_result::<D::Result>(result_rx)),
+            ]
+        }));
+        self
+    }
+
+    pub fn spawn(
+        self,
+        super_commander: &SuperCommander,
+        reporter: &Reporter,
+    ) -> Vec<JoinHandle<()>> {
+        self.pipelines
+            .into_iter()
+            .flat_map(|pipeline| pipeline(super_commander.build_commander(), reporter.clone()))
+            .collect()
+    }
+}
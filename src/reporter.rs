@@ -1,64 +1,355 @@
 use super::backoff;
+use crate::aggregator::{AggregateTx, Aggregator, Sample};
+use crate::asn::AsnDb;
+use crate::conf;
+use crate::events::{Event, EventRx, EventTx};
 use crate::grpc::collector_grpc::collector_client::CollectorClient;
-use crate::grpc::collector_grpc::{FPingReportReq, PingReportReq, TcpPingReportReq};
-use crate::structures::{FPingResult, PingResult, TcpPingResult};
+use crate::grpc::collector_grpc::{
+    AggregateReportReq, EventReportReq, FPingReportReq, MtrReportReq, PingReportReq, PmtuReportReq,
+    ProbeType, TcpPingReportReq, TimestampReportReq, TwampReportReq,
+};
+use crate::structures::{
+    Aggregate, FPingResult, PingResult, PingStats, PmtuResult, TcpPingResult, TimestampResult,
+    Trace, TwampResult,
+};
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
+use tokio::sync::broadcast::error::RecvError;
 use tokio::sync::mpsc;
 use tokio::task;
 use tokio::time;
 use tokio::time::MissedTickBehavior;
 use tonic::codegen::http::uri::InvalidUri;
 use tonic::transport::{Channel, Uri};
+use tonic::Status;
 use tracing::warn;
 
 const RETRY_INTERVAL_MIN: u64 = 5;
 const RETRY_INTERVAL_MAX: u64 = 15;
 const BATCH_SIZE: usize = 1024;
 const BATCH_INTERVAL: Duration = Duration::from_secs(1);
+const AGGREGATE_BUFFER: usize = 1024;
+const EVENT_BUFFER: usize = 1024;
 
-type PingResultRx = mpsc::Receiver<PingResult>;
-type TcpPingResultRx = mpsc::Receiver<TcpPingResult>;
-type FpingResultRx = mpsc::Receiver<Vec<FPingResult>>;
+pub type Client = CollectorClient<Channel>;
+
+type ResultRx<R> = mpsc::Receiver<R>;
 type FlushSignalTx = mpsc::Sender<()>;
 
-#[derive(Clone)]
-pub struct Reporter {
-    channel: Channel,
-    agent_id: u32,
-}
+/// A result type the agent can report to the collector.
+pub trait Report: Sized + Send + 'static {
+    type Req: Clone + Send + 'static;
+    /// Human readable name used in logs.
+    const NAME: &'static str;
 
-impl Reporter {
-    pub fn new(server_add: &str, agent_id: u32) -> Result<Self, InvalidUri> {
-        let uri = Uri::from_str(server_add)?;
-        let channel = Channel::builder(uri).connect_lazy();
-        Ok(Self { channel, agent_id })
+    fn build_request(agent_id: u32, results: Vec<Self>) -> Self::Req;
+
+    fn send(client: &mut Client, req: Self::Req)
+        -> impl Future<Output = Result<(), Status>> + Send;
+
+    /// Results of this type are folded into window aggregates, the reporter
+    /// only keeps an aggregator for these.
+    const AGGREGATED: bool = false;
+
+    /// Window aggregates are built from this, `None` if the result is not
+    /// aggregated.
+    fn sample(&self) -> Option<Sample> {
+        None
     }
 
-    fn build_ping_request(&self, results: Vec<PingResult>) -> PingReportReq {
+    /// Annotate addresses with their origin AS before the result is batched.
+    fn enrich(&mut self, _asn: &AsnDb) {}
+}
+
+impl Report for PingResult {
+    type Req = PingReportReq;
+    const NAME: &'static str = "ping";
+    const AGGREGATED: bool = true;
+
+    fn build_request(agent_id: u32, results: Vec<Self>) -> Self::Req {
+        let stats = PingStats::from_results(&results);
         let r = results.into_iter().map(|x| x.into()).collect();
         PingReportReq {
-            agent_id: self.agent_id,
+            agent_id,
             results: r,
+            stats: stats.into_iter().map(|x| x.into()).collect(),
         }
     }
 
-    fn build_tcp_ping_request(&self, results: Vec<TcpPingResult>) -> TcpPingReportReq {
+    async fn send(client: &mut Client, req: Self::Req) -> Result<(), Status> {
+        client.ping_report(req).await.map(|_| ())
+    }
+
+    fn sample(&self) -> Option<Sample> {
+        Some(Sample {
+            id: self.id,
+            probe_type: ProbeType::Ping,
+            send_at: self.send_at,
+            rtt: self.rtt,
+            loss_run: (!self.not_sent).then_some(self.loss_run),
+            ipdv: self.ipdv,
+            jitter: self.jitter,
+            reordered: self.reordered,
+            report: self.report,
+            ip: self.ip,
+        })
+    }
+
+    fn enrich(&mut self, asn: &AsnDb) {
+        self.origin = self.ip.and_then(|ip| asn.lookup(ip));
+    }
+}
+
+impl Report for TcpPingResult {
+    type Req = TcpPingReportReq;
+    const NAME: &'static str = "tcp ping";
+    const AGGREGATED: bool = true;
+
+    fn build_request(agent_id: u32, results: Vec<Self>) -> Self::Req {
         let r = results.into_iter().map(|x| x.into()).collect();
         TcpPingReportReq {
-            agent_id: self.agent_id,
+            agent_id,
             results: r,
         }
     }
 
-    fn build_fping_request(&self, results: Vec<FPingResult>) -> FPingReportReq {
-        let r = results.into_iter().map(|x| x.into()).collect();
+    async fn send(client: &mut Client, req: Self::Req) -> Result<(), Status> {
+        client.tcp_ping_report(req).await.map(|_| ())
+    }
+
+    fn sample(&self) -> Option<Sample> {
+        Some(Sample {
+            id: self.id,
+            probe_type: ProbeType::TcpPing,
+            send_at: self.send_at,
+            rtt: self.rtt,
+            loss_run: (!self.not_sent).then_some(self.loss_run),
+            ipdv: None,
+            jitter: Duration::ZERO,
+            reordered: 0,
+            report: self.report,
+            ip: self.ip,
+        })
+    }
+
+    fn enrich(&mut self, asn: &AsnDb) {
+        self.origin = self.ip.and_then(|ip| asn.lookup(ip));
+    }
+}
+
+/// Every fping round is reported as a whole, batching only merges rounds.
+impl Report for Vec<FPingResult> {
+    type Req = FPingReportReq;
+    const NAME: &'static str = "fping";
+
+    fn build_request(agent_id: u32, results: Vec<Self>) -> Self::Req {
+        let r = results.into_iter().flatten().map(|x| x.into()).collect();
         FPingReportReq {
             results: r,
-            agent_id: self.agent_id,
+            agent_id,
         }
     }
 
+    async fn send(client: &mut Client, req: Self::Req) -> Result<(), Status> {
+        client.fping_report(req).await.map(|_| ())
+    }
+}
+
+impl Report for Trace {
+    type Req = MtrReportReq;
+    const NAME: &'static str = "mtr";
+
+    fn build_request(agent_id: u32, results: Vec<Self>) -> Self::Req {
+        let r = results.into_iter().map(|x| x.into()).collect();
+        MtrReportReq {
+            results: Vec::new(),
+            agent_id,
+            traces: r,
+        }
+    }
+
+    async fn send(client: &mut Client, req: Self::Req) -> Result<(), Status> {
+        client.mtr_report(req).await.map(|_| ())
+    }
+
+    fn enrich(&mut self, asn: &AsnDb) {
+        self.origin = asn.lookup(self.ip);
+        for r in &mut self.results {
+            r.origin = r.ip.parse().ok().and_then(|ip| asn.lookup(ip));
+        }
+    }
+}
+
+impl Report for PmtuResult {
+    type Req = PmtuReportReq;
+    const NAME: &'static str = "pmtu";
+
+    fn build_request(agent_id: u32, results: Vec<Self>) -> Self::Req {
+        let r = results.into_iter().map(|x| x.into()).collect();
+        PmtuReportReq {
+            results: r,
+            agent_id,
+        }
+    }
+
+    async fn send(client: &mut Client, req: Self::Req) -> Result<(), Status> {
+        client.pmtu_report(req).await.map(|_| ())
+    }
+}
+
+impl Report for TimestampResult {
+    type Req = TimestampReportReq;
+    const NAME: &'static str = "timestamp";
+
+    fn build_request(agent_id: u32, results: Vec<Self>) -> Self::Req {
+        let r = results.into_iter().map(|x| x.into()).collect();
+        TimestampReportReq {
+            results: r,
+            agent_id,
+        }
+    }
+
+    async fn send(client: &mut Client, req: Self::Req) -> Result<(), Status> {
+        client.timestamp_report(req).await.map(|_| ())
+    }
+}
+
+impl Report for TwampResult {
+    type Req = TwampReportReq;
+    const NAME: &'static str = "twamp";
+
+    fn build_request(agent_id: u32, results: Vec<Self>) -> Self::Req {
+        let r = results.into_iter().map(|x| x.into()).collect();
+        TwampReportReq {
+            results: r,
+            agent_id,
+        }
+    }
+
+    async fn send(client: &mut Client, req: Self::Req) -> Result<(), Status> {
+        client.twamp_report(req).await.map(|_| ())
+    }
+}
+
+impl Report for Aggregate {
+    type Req = AggregateReportReq;
+    const NAME: &'static str = "aggregate";
+
+    fn build_request(agent_id: u32, results: Vec<Self>) -> Self::Req {
+        let r = results.into_iter().map(|x| x.into()).collect();
+        AggregateReportReq {
+            aggregates: r,
+            agent_id,
+        }
+    }
+
+    async fn send(client: &mut Client, req: Self::Req) -> Result<(), Status> {
+        client.aggregate_report(req).await.map(|_| ())
+    }
+
+    fn enrich(&mut self, asn: &AsnDb) {
+        self.origin = self.ip.and_then(|ip| asn.lookup(ip));
+    }
+}
+
+impl Report for Event {
+    type Req = EventReportReq;
+    const NAME: &'static str = "event";
+
+    fn build_request(agent_id: u32, results: Vec<Self>) -> Self::Req {
+        let mut state_changes = Vec::new();
+        let mut anomalies = Vec::new();
+        let mut path_changes = Vec::new();
+        let mut budget_stats = Vec::new();
+        for event in results {
+            match event {
+                Event::State(change) => state_changes.push(change.into()),
+                Event::Anomaly(anomaly) => anomalies.push(anomaly.into()),
+                Event::PathChange(change) => path_changes.push(change.into()),
+                Event::Budget(stats) => budget_stats.push(stats.into()),
+                Event::Window(_) => {}
+            }
+        }
+        EventReportReq {
+            state_changes,
+            agent_id,
+            anomalies,
+            path_changes,
+            budget_stats,
+        }
+    }
+
+    async fn send(client: &mut Client, req: Self::Req) -> Result<(), Status> {
+        client.event_report(req).await.map(|_| ())
+    }
+
+    /// Ping targets are addresses, tcp ping ones `ip:port` unless they are
+    /// host names, which are left alone.
+    fn enrich(&mut self, asn: &AsnDb) {
+        let Event::State(change) = self else { return };
+        let ip = change
+            .target
+            .parse::<IpAddr>()
+            .or_else(|_| change.target.parse::<SocketAddr>().map(|addr| addr.ip()));
+        change.origin = ip.ok().and_then(|ip| asn.lookup(ip));
+    }
+}
+
+#[derive(Clone)]
+pub struct Reporter {
+    channel: Channel,
+    agent_id: u32,
+    aggregation: conf::Aggregation,
+    aggregate_tx: AggregateTx,
+    event_tx: EventTx,
+    asn: Option<AsnDb>,
+}
+
+impl Reporter {
+    pub fn new(
+        server_add: &str,
+        agent_id: u32,
+        aggregation: &conf::Aggregation,
+        event_tx: EventTx,
+        asn: Option<AsnDb>,
+    ) -> Result<Self, InvalidUri> {
+        let uri = Uri::from_str(server_add)?;
+        let channel = Channel::builder(uri).connect_lazy();
+        let (aggregate_tx, aggregate_rx) = mpsc::channel(AGGREGATE_BUFFER);
+        let reporter = Self {
+            channel,
+            agent_id,
+            aggregation: aggregation.clone(),
+            aggregate_tx,
+            event_tx,
+            asn,
+        };
+
+        // Closed windows of every detector are batched by one reporter.
+        task::spawn(reporter.clone().report_result::<Aggregate>(aggregate_rx));
+        Ok(reporter)
+    }
+
+    /// Report everything published on the event bus.
+    pub async fn report_events(self, mut events: EventRx) {
+        let (tx, rx) = mpsc::channel(EVENT_BUFFER);
+        task::spawn(async move {
+            loop {
+                match events.recv().await {
+                    // Windows are reported by their own RPC.
+                    Ok(Event::Window(_)) => {}
+                    Ok(event) => tx.send(event).await.expect("Send event fail"),
+                    Err(RecvError::Lagged(n)) => warn!("Event reporter lagged, drop {} events", n),
+                    Err(RecvError::Closed) => return,
+                }
+            }
+        });
+
+        self.report_result::<Event>(rx).await
+    }
+
     fn start_timer(period: Duration, tx: FlushSignalTx) {
         let mut timer = time::interval(period);
         timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
@@ -71,11 +362,18 @@ impl Reporter {
         });
     }
 
-    pub async fn report_ping_result(self, mut rx: PingResultRx) {
-        let mut client = CollectorClient::new(self.channel.clone());
-        let (failed_tx, mut failed_rx) = mpsc::channel::<PingReportReq>(1);
+    pub async fn report_result<R: Report>(self, mut rx: ResultRx<R>) {
+        let mut client = Client::new(self.channel.clone());
+        let (failed_tx, mut failed_rx) = mpsc::channel::<R::Req>(1);
         let (flush_buff_tx, mut flush_buff_rx) = mpsc::channel(1);
         let mut buff = Vec::with_capacity(BATCH_SIZE);
+        let mut aggregator = R::AGGREGATED.then(|| {
+            Aggregator::new(
+                &self.aggregation,
+                self.aggregate_tx.clone(),
+                self.event_tx.clone(),
+            )
+        });
 
         Self::start_timer(BATCH_INTERVAL, flush_buff_tx.clone());
 
@@ -84,77 +382,45 @@ impl Reporter {
                 biased;
 
                 req = failed_rx.recv() => {
-                    let req = req.expect("Recv failed ping req fail");
-                    let result = client.ping_report(req.clone()).await;
+                    let req = req.expect("Recv failed req fail");
+                    let result = R::send(&mut client, req.clone()).await;
                     if let Err(e) = result {
-                        warn!("Send ping result fail, err:{}", e.message());
-                        failed_tx.send(req).await.expect("Secv failed ping req fail");
+                        warn!("Send {} result fail, err:{}", R::NAME, e.message());
+                        failed_tx.send(req).await.expect("Send failed req fail");
                         backoff!(RETRY_INTERVAL_MIN, RETRY_INTERVAL_MAX);
                     }
                 }
                 s = flush_buff_rx.recv() => {
                     s.expect("Recv flush buff signal fail");
+                    if let Some(aggregator) = &mut aggregator {
+                        aggregator.flush(SystemTime::now()).await;
+                    }
                     if buff.is_empty() {
                         continue
                     }
 
-                    let req = self.build_ping_request(buff);
-                    let result = client.ping_report(req.clone()).await;
+                    let req = R::build_request(self.agent_id, buff);
+                    let result = R::send(&mut client, req.clone()).await;
                     if let Err(e) = result {
-                        warn!("Send ping result fail, err:{}", e.message());
-                        failed_tx.send(req).await.expect("Secv failed req fail");
+                        warn!("Send {} result fail, err:{}", R::NAME, e.message());
+                        failed_tx.send(req).await.expect("Send failed req fail");
                     }
 
                     buff = Vec::with_capacity(BATCH_SIZE);
                 }
                 r = rx.recv() => {
-                    let r = r.expect("Recv ping result fail");
-                    buff.push(r);
-                    if buff.len() == BATCH_SIZE {
-                        flush_buff_tx.send(()).await.expect("Send flush buff signal fail")
-                    }
-                }
-            }
-        }
-    }
-
-    pub async fn report_tcp_ping_result(self, mut rx: TcpPingResultRx) {
-        let mut client = CollectorClient::new(self.channel.clone());
-        let (flush_buff_tx, mut flush_buff_rx) = mpsc::channel(1);
-        let (failed_tx, mut failed_rx) = mpsc::channel::<TcpPingReportReq>(1);
-
-        Self::start_timer(BATCH_INTERVAL, flush_buff_tx.clone());
-        let mut buff = Vec::with_capacity(BATCH_SIZE);
-
-        loop {
-            tokio::select! {
-                biased;
-
-                req = failed_rx.recv() => {
-                    let req = req.expect("Recv failed tcp ping req fail");
-                    let result = client.tcp_ping_report(req.clone()).await;
-                    if let Err(e) = result {
-                        warn!("Send ping result fail, err:{}", e.message());
-                        failed_tx.send(req).await.expect("Secv failed tcp ping req fail");
-                        backoff!(RETRY_INTERVAL_MIN, RETRY_INTERVAL_MAX);
-                    }
-                }
-                s = flush_buff_rx.recv() => {
-                    s.expect("Recv flush buff signal fail");
-                    if buff.is_empty() {
+                    let mut r = r.expect("Recv result fail");
+                    let raw = match (&mut aggregator, r.sample()) {
+                        (Some(aggregator), Some(sample)) => aggregator.add(sample, SystemTime::now()),
+                        _ => true,
+                    };
+                    if !raw {
                         continue
                     }
-                    let req = self.build_tcp_ping_request(buff);
-                    let result = client.tcp_ping_report(req.clone()).await;
-                    if let Err(e) = result {
-                        warn!("Send tcp ping result fail, err:{}", e.message());
-                        failed_tx.send(req).await.expect("Secv failed req fail");
-                    }
 
-                    buff = Vec::with_capacity(BATCH_SIZE);
-                }
-                r = rx.recv() => {
-                    let r = r.expect("Recv tcp ping result fail");
+                    if let Some(asn) = &self.asn {
+                        r.enrich(asn);
+                    }
                     buff.push(r);
                     if buff.len() == BATCH_SIZE {
                         flush_buff_tx.send(()).await.expect("Send flush buff signal fail")
@@ -163,33 +429,4 @@ impl Reporter {
             }
         }
     }
-
-    pub async fn report_fping_result(self, mut rx: FpingResultRx) {
-        let mut client = CollectorClient::new(self.channel.clone());
-        let (failed_tx, mut failed_rx) = mpsc::channel::<FPingReportReq>(1);
-        loop {
-            tokio::select! {
-                biased;
-
-                req = failed_rx.recv() => {
-                    let req = req.unwrap();
-                    let result = client.fping_report(req.clone()).await;
-                    if let Err(e) = result {
-                        warn!("Send fping result fail, err:{}", e.message());
-                        failed_tx.send(req).await.unwrap();
-                        backoff!(RETRY_INTERVAL_MIN, RETRY_INTERVAL_MAX);
-                    }
-                }
-                r = rx.recv() => {
-                    let r = r.unwrap();
-                    let req = self.build_fping_request(r);
-                    let result = client.fping_report(req.clone()).await;
-                    if let Err(e) = result {
-                        warn!("Send fping result fail, err:{}", e.message());
-                        failed_tx.send(req).await.unwrap();
-                    }
-                }
-            }
-        }
-    }
 }
@@ -1,14 +1,102 @@
+use crate::asn::Origin;
 use crate::grpc::collector_grpc::{
-    GrpcFPingResult, GrpcMtrResult, GrpcPingResult, GrpcTcpPingResult,
+    self, GrpcAggregate, GrpcAnomaly, GrpcBudgetStats, GrpcDelay, GrpcFPingResult, GrpcHopDiff,
+    GrpcMplsLabel, GrpcMtrResult, GrpcPath, GrpcPathChange, GrpcPingResult, GrpcPingStats,
+    GrpcPmtuResult, GrpcStateChange, GrpcTcpPingResult, GrpcTimestampResult, GrpcTrace,
+    GrpcTwampResult, ProbeType,
 };
 use crate::grpc::controller_grpc::{
-    GrpcFpingCommand, GrpcPingCommand, GrpcTcpPingCommand, MtrCommandResp,
+    self, GrpcFpingCommand, GrpcPingCommand, GrpcPmtuCommand, GrpcTcpPingCommand,
+    GrpcTimestampCommand, GrpcTwampCommand, MtrCommandResp, SamplingMode,
 };
+use crate::twamp;
+use serde::Deserialize;
+use std::collections::BTreeMap;
 use std::convert::TryFrom;
-use std::net::{AddrParseError, IpAddr};
+use std::net::{AddrParseError, IpAddr, Ipv4Addr};
 use std::option::Option::Some;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+const DEFAULT_PING_SIZE: usize = 64;
+const ICMP_HEADER_LEN: usize = 8;
+const MAX_PING_SIZE: usize = 65_507;
+/// Smallest ping size, the echo header and two bytes of payload.
+const MIN_PING_SIZE: usize = 10;
+const IPV4_HEADER_LEN: u16 = 20;
+const IPV6_HEADER_LEN: u16 = 40;
+const DEFAULT_PMTU_MIN_V4: u16 = 576;
+const DEFAULT_PMTU_MIN_V6: u16 = 1280;
+const DEFAULT_PMTU_MAX: u16 = 1500;
+
+/// How the gaps between probes of a target are drawn.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Sampling {
+    /// Every gap is exactly the interval.
+    Fixed,
+    /// Gaps are uniformly distributed in `interval ± jitter`.
+    Uniform { jitter: Duration },
+    /// Gaps are exponentially distributed with the interval as mean, see RFC 2330.
+    Poisson,
+}
+
+impl Sampling {
+    fn new(mode: SamplingMode, jitter_ms: u32) -> Self {
+        match mode {
+            SamplingMode::Fixed => Self::Fixed,
+            SamplingMode::Uniform => Self::Uniform {
+                jitter: Duration::from_millis(u64::from(jitter_ms)),
+            },
+            SamplingMode::Poisson => Self::Poisson,
+        }
+    }
+}
+
+/// How results of a command reach the collector.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportMode {
+    /// Every result is reported.
+    #[default]
+    Raw,
+    /// Only window aggregates are reported.
+    Aggregate,
+}
+
+impl ReportMode {
+    /// `None` leaves the choice to the agent config.
+    fn from_grpc(mode: controller_grpc::ReportMode) -> Option<Self> {
+        match mode {
+            controller_grpc::ReportMode::AgentDefault => None,
+            controller_grpc::ReportMode::Raw => Some(Self::Raw),
+            controller_grpc::ReportMode::Aggregate => Some(Self::Aggregate),
+        }
+    }
+}
+
+/// Probe faster while a target is losing packets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Escalation {
+    pub fast_interval: Duration,
+    /// Consecutive timeouts before switching to `fast_interval`.
+    pub escalate_after: u32,
+    /// Consecutive replies before switching back to the base interval.
+    pub cool_down_after: u32,
+}
+
+impl Escalation {
+    fn new(fast_interval_ms: u32, escalate_after: u32, cool_down_after: u32) -> Option<Self> {
+        if fast_interval_ms == 0 || escalate_after == 0 {
+            return None;
+        }
+
+        Some(Self {
+            fast_interval: Duration::from_millis(u64::from(fast_interval_ms)),
+            escalate_after,
+            cool_down_after: cool_down_after.max(1),
+        })
+    }
+}
+
 #[derive(Debug)]
 pub struct PingCommand {
     pub id: u64,
@@ -16,6 +104,15 @@ pub struct PingCommand {
     pub interval: Duration,
     pub timeout: Duration,
     pub dscp: u32,
+    pub sampling: Sampling,
+    /// ICMP packet length including the header.
+    pub size: usize,
+    /// Time out by the smoothed RTT of the target, `timeout` is the upper bound.
+    pub adaptive_timeout: bool,
+    pub escalation: Option<Escalation>,
+    pub report: Option<ReportMode>,
+    /// Deviations from the RTT baseline flagged as anomaly.
+    pub anomaly_sensitivity: Option<f64>,
 }
 
 impl TryFrom<GrpcPingCommand> for PingCommand {
@@ -23,12 +120,24 @@ impl TryFrom<GrpcPingCommand> for PingCommand {
 
     fn try_from(c: GrpcPingCommand) -> Result<Self, Self::Error> {
         let ip = c.ip.parse::<IpAddr>()?;
+        let sampling = Sampling::new(c.sampling(), c.jitter_ms);
+        let size = match c.size as usize {
+            0 => DEFAULT_PING_SIZE,
+            size => size.clamp(MIN_PING_SIZE, MAX_PING_SIZE),
+        };
         Ok(Self {
             id: c.id,
             ip,
             interval: Duration::from_millis(u64::from(c.interval_ms)),
             timeout: Duration::from_millis(u64::from(c.timeout_ms)),
             dscp: c.dscp,
+            sampling,
+            size,
+            adaptive_timeout: c.adaptive_timeout,
+            escalation: Escalation::new(c.fast_interval_ms, c.escalate_after, c.cool_down_after),
+            report: ReportMode::from_grpc(c.report()),
+            anomaly_sensitivity: (c.anomaly_sensitivity > 0.0)
+                .then_some(f64::from(c.anomaly_sensitivity)),
         })
     }
 }
@@ -39,6 +148,28 @@ pub struct PingResult {
     pub is_timeout: bool,
     pub send_at: SystemTime,
     pub rtt: Option<Duration>,
+    /// The probe was due but never sent, it must be counted as lost.
+    pub not_sent: bool,
+    /// Timeout the probe was judged by.
+    pub timeout: Duration,
+    /// Interval the target was probed at when this probe was sent.
+    pub interval: Duration,
+    /// IPDV against the previous probe in micros, RFC 3393.
+    pub ipdv: Option<i64>,
+    /// Interarrival jitter of the target, RFC 3550.
+    pub jitter: Duration,
+    /// Replies of earlier probes that arrived out of order, RFC 4737. Only one
+    /// probe of a target is in flight, so these are replies which came after
+    /// their timeout, read while waiting for this probe.
+    pub reordered: u32,
+    /// Consecutive losses up to this probe, for a reply the length of the
+    /// loss run it ended.
+    pub loss_run: u32,
+    pub report: Option<ReportMode>,
+    /// Destination, `None` for host names.
+    pub ip: Option<IpAddr>,
+    /// Filled in by the reporter when an ASN table is loaded.
+    pub origin: Option<Origin>,
 }
 
 impl From<PingResult> for GrpcPingResult {
@@ -52,6 +183,82 @@ impl From<PingResult> for GrpcPingResult {
             is_timeout: v.is_timeout,
             rtt_micros,
             send_at: v.send_at.duration_since(UNIX_EPOCH).unwrap().as_secs() as i64,
+            not_sent: v.not_sent,
+            timeout_micros: v.timeout.as_micros() as u32,
+            interval_ms: v.interval.as_millis() as u32,
+            asn: v.origin.map(|o| o.asn).unwrap_or_default(),
+            prefix: v.origin.map(|o| o.to_string()).unwrap_or_default(),
+        }
+    }
+}
+
+/// Delay variation and reordering of one target over a batch of results or
+/// an aggregation window.
+#[derive(Debug, Clone)]
+pub struct PingStats {
+    pub id: u64,
+    pub jitter: Duration,
+    pub ipdv_min: i64,
+    pub ipdv_max: i64,
+    pub ipdv_abs_sum: u64,
+    pub ipdv_samples: u32,
+    pub reordered: u32,
+}
+
+impl PingStats {
+    pub fn new(id: u64) -> Self {
+        Self {
+            id,
+            jitter: Duration::ZERO,
+            ipdv_min: i64::MAX,
+            ipdv_max: i64::MIN,
+            ipdv_abs_sum: 0,
+            ipdv_samples: 0,
+            reordered: 0,
+        }
+    }
+
+    /// Fold in one probe, the jitter is that of the newest.
+    pub fn add(&mut self, jitter: Duration, ipdv: Option<i64>, reordered: u32) {
+        self.jitter = jitter;
+        self.reordered += reordered;
+        if let Some(ipdv) = ipdv {
+            self.ipdv_min = self.ipdv_min.min(ipdv);
+            self.ipdv_max = self.ipdv_max.max(ipdv);
+            self.ipdv_abs_sum += ipdv.unsigned_abs();
+            self.ipdv_samples += 1;
+        }
+    }
+
+    /// Stats of every target in `results`, ordered by command id.
+    pub fn from_results(results: &[PingResult]) -> Vec<Self> {
+        let mut stats: BTreeMap<u64, Self> = BTreeMap::new();
+        for r in results {
+            stats
+                .entry(r.id)
+                .or_insert_with(|| Self::new(r.id))
+                .add(r.jitter, r.ipdv, r.reordered);
+        }
+
+        stats.into_values().collect()
+    }
+}
+
+impl From<PingStats> for GrpcPingStats {
+    fn from(v: PingStats) -> Self {
+        let (min, max, mean) = match v.ipdv_samples {
+            0 => (0, 0, 0),
+            n => (v.ipdv_min, v.ipdv_max, v.ipdv_abs_sum / u64::from(n)),
+        };
+
+        GrpcPingStats {
+            id: v.id,
+            jitter_micros: v.jitter.as_micros() as u32,
+            ipdv_min_micros: min as i32,
+            ipdv_max_micros: max as i32,
+            ipdv_mean_abs_micros: mean as u32,
+            ipdv_samples: v.ipdv_samples,
+            reordered: v.reordered,
         }
     }
 }
@@ -62,15 +269,26 @@ pub struct TcpPingCommand {
     pub target: String,
     pub interval: Duration,
     pub timeout: Duration,
+    pub sampling: Sampling,
+    /// Time out by the smoothed RTT of the target, `timeout` is the upper bound.
+    pub adaptive_timeout: bool,
+    pub escalation: Option<Escalation>,
+    pub report: Option<ReportMode>,
 }
 
 impl From<GrpcTcpPingCommand> for TcpPingCommand {
     fn from(c: GrpcTcpPingCommand) -> Self {
+        let sampling = Sampling::new(c.sampling(), c.jitter_ms);
+        let report = ReportMode::from_grpc(c.report());
         Self {
             id: c.id,
             target: c.target,
             interval: Duration::from_millis(u64::from(c.interval_ms)),
             timeout: Duration::from_millis(u64::from(c.timeout_ms)),
+            sampling,
+            adaptive_timeout: c.adaptive_timeout,
+            escalation: Escalation::new(c.fast_interval_ms, c.escalate_after, c.cool_down_after),
+            report,
         }
     }
 }
@@ -81,6 +299,20 @@ pub struct TcpPingResult {
     pub is_timeout: bool,
     pub send_at: SystemTime,
     pub rtt: Option<Duration>,
+    /// The probe was due but never sent, it must be counted as lost.
+    pub not_sent: bool,
+    /// Timeout the probe was judged by.
+    pub timeout: Duration,
+    /// Interval the target was probed at when this probe was sent.
+    pub interval: Duration,
+    /// Consecutive losses up to this probe, for a reply the length of the
+    /// loss run it ended.
+    pub loss_run: u32,
+    pub report: Option<ReportMode>,
+    /// Destination, `None` for host names.
+    pub ip: Option<IpAddr>,
+    /// Filled in by the reporter when an ASN table is loaded.
+    pub origin: Option<Origin>,
 }
 
 impl From<TcpPingResult> for GrpcTcpPingResult {
@@ -94,6 +326,202 @@ impl From<TcpPingResult> for GrpcTcpPingResult {
             is_timeout: v.is_timeout,
             rtt_micros,
             send_at: v.send_at.duration_since(UNIX_EPOCH).unwrap().as_secs() as i64,
+            not_sent: v.not_sent,
+            timeout_micros: v.timeout.as_micros() as u32,
+            interval_ms: v.interval.as_millis() as u32,
+            asn: v.origin.map(|o| o.asn).unwrap_or_default(),
+            prefix: v.origin.map(|o| o.to_string()).unwrap_or_default(),
+        }
+    }
+}
+
+/// Two state Gilbert-Elliott loss model fitted to the probes of a window.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Gilbert {
+    /// Probability to go from good to bad.
+    pub p: f64,
+    /// Probability to go from bad to good.
+    pub r: f64,
+    /// Loss probability in the good state, `1 - k`.
+    pub good_loss: f64,
+    /// Loss probability in the bad state, `1 - h`.
+    pub bad_loss: f64,
+    /// Stationary loss probability.
+    pub loss: f64,
+}
+
+impl Gilbert {
+    /// Independent losses of probability `loss`, a bad state which is left
+    /// as likely as the good one is entered.
+    pub fn independent(loss: f64) -> Self {
+        Self {
+            p: loss,
+            r: 1.0 - loss,
+            good_loss: 0.0,
+            bad_loss: 1.0,
+            loss,
+        }
+    }
+}
+
+/// Results of one command over one aggregation window.
+#[derive(Debug, Clone)]
+pub struct Aggregate {
+    pub id: u64,
+    pub probe_type: ProbeType,
+    pub window_start: SystemTime,
+    pub window: Duration,
+    pub count: u32,
+    /// Timed out and not sent probes.
+    pub lost: u32,
+    pub rtt_min: Duration,
+    pub rtt_max: Duration,
+    pub rtt_p50: Duration,
+    pub rtt_p90: Duration,
+    pub rtt_p99: Duration,
+    pub rtt_p999: Duration,
+    /// Runs of consecutive lost probes in the window, including one carried
+    /// over from the previous window. The mean only counts lost probes in the
+    /// window, the max whole runs.
+    pub bursts: u32,
+    pub burst_mean: f64,
+    pub burst_max: u32,
+    pub gilbert: Gilbert,
+    /// Delay variation and reordering, only ping results carry them.
+    pub variation: PingStats,
+    /// Destination, `None` for host names.
+    pub ip: Option<IpAddr>,
+    /// Filled in by the reporter when an ASN table is loaded.
+    pub origin: Option<Origin>,
+}
+
+impl From<Aggregate> for GrpcAggregate {
+    fn from(v: Aggregate) -> Self {
+        GrpcAggregate {
+            id: v.id,
+            r#type: v.probe_type.into(),
+            window_start: v.window_start.duration_since(UNIX_EPOCH).unwrap().as_secs() as i64,
+            window_secs: v.window.as_secs() as u32,
+            count: v.count,
+            lost: v.lost,
+            rtt_min_micros: v.rtt_min.as_micros() as u32,
+            rtt_max_micros: v.rtt_max.as_micros() as u32,
+            rtt_p50_micros: v.rtt_p50.as_micros() as u32,
+            rtt_p90_micros: v.rtt_p90.as_micros() as u32,
+            rtt_p99_micros: v.rtt_p99.as_micros() as u32,
+            rtt_p999_micros: v.rtt_p999.as_micros() as u32,
+            bursts: v.bursts,
+            burst_mean_len: v.burst_mean as f32,
+            burst_max_len: v.burst_max,
+            gilbert_p: v.gilbert.p as f32,
+            gilbert_r: v.gilbert.r as f32,
+            gilbert_loss: v.gilbert.loss as f32,
+            gilbert_good_loss: v.gilbert.good_loss as f32,
+            gilbert_bad_loss: v.gilbert.bad_loss as f32,
+            variation: Some(v.variation.into()),
+            asn: v.origin.map(|o| o.asn).unwrap_or_default(),
+            prefix: v.origin.map(|o| o.to_string()).unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TargetState {
+    Up,
+    Degraded,
+    Down,
+}
+
+impl From<TargetState> for collector_grpc::TargetState {
+    fn from(v: TargetState) -> Self {
+        match v {
+            TargetState::Up => Self::Up,
+            TargetState::Degraded => Self::Degraded,
+            TargetState::Down => Self::Down,
+        }
+    }
+}
+
+/// Reachability transition of a command target, with the probe that caused it.
+#[derive(Debug, Clone)]
+pub struct StateChange {
+    pub id: u64,
+    pub probe_type: ProbeType,
+    pub target: String,
+    pub from: TargetState,
+    pub to: TargetState,
+    /// When `from` was entered.
+    pub since: SystemTime,
+    pub at: SystemTime,
+    pub losses: u32,
+    pub rtt: Option<Duration>,
+    pub rtt_threshold: Option<Duration>,
+    /// Filled in by the reporter when an ASN table is loaded.
+    pub origin: Option<Origin>,
+}
+
+impl From<StateChange> for GrpcStateChange {
+    fn from(v: StateChange) -> Self {
+        let millis = |t: SystemTime| t.duration_since(UNIX_EPOCH).unwrap().as_millis() as i64;
+        GrpcStateChange {
+            id: v.id,
+            r#type: v.probe_type.into(),
+            target: v.target,
+            from: collector_grpc::TargetState::from(v.from).into(),
+            to: collector_grpc::TargetState::from(v.to).into(),
+            since_millis: millis(v.since),
+            at_millis: millis(v.at),
+            consecutive_losses: v.losses,
+            rtt_micros: v.rtt.unwrap_or_default().as_micros() as u32,
+            rtt_threshold_micros: v.rtt_threshold.unwrap_or_default().as_micros() as u32,
+            asn: v.origin.map(|o| o.asn).unwrap_or_default(),
+            prefix: v.origin.map(|o| o.to_string()).unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnomalyKind {
+    Step,
+    LevelShift,
+}
+
+impl From<AnomalyKind> for collector_grpc::AnomalyKind {
+    fn from(v: AnomalyKind) -> Self {
+        match v {
+            AnomalyKind::Step => Self::Step,
+            AnomalyKind::LevelShift => Self::LevelShift,
+        }
+    }
+}
+
+/// RTT of a command target left its baseline.
+#[derive(Debug, Clone)]
+pub struct Anomaly {
+    pub id: u64,
+    pub probe_type: ProbeType,
+    pub target: String,
+    pub kind: AnomalyKind,
+    pub at: SystemTime,
+    pub baseline: Duration,
+    pub deviation: Duration,
+    /// Smoothed RTT the anomaly was flagged on.
+    pub rtt: Duration,
+    pub sensitivity: f64,
+}
+
+impl From<Anomaly> for GrpcAnomaly {
+    fn from(v: Anomaly) -> Self {
+        GrpcAnomaly {
+            id: v.id,
+            r#type: v.probe_type.into(),
+            target: v.target,
+            kind: collector_grpc::AnomalyKind::from(v.kind).into(),
+            at_millis: v.at.duration_since(UNIX_EPOCH).unwrap().as_millis() as i64,
+            baseline_micros: v.baseline.as_micros() as u32,
+            deviation_micros: v.deviation.as_micros() as u32,
+            rtt_micros: v.rtt.as_micros() as u32,
+            sensitivity: v.sensitivity as f32,
         }
     }
 }
@@ -153,6 +581,61 @@ impl From<&PingResult> for FPingResult {
     }
 }
 
+/// How probes of a trace are told apart by load balancers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TraceMode {
+    /// Every probe is a flow of its own, hops of equal-cost paths mix.
+    #[default]
+    Classic,
+    /// Probes of one flow keep their flow identifier, see Paris traceroute.
+    Paris,
+}
+
+impl From<controller_grpc::TraceMode> for TraceMode {
+    fn from(v: controller_grpc::TraceMode) -> Self {
+        match v {
+            controller_grpc::TraceMode::Classic => Self::Classic,
+            controller_grpc::TraceMode::Paris => Self::Paris,
+        }
+    }
+}
+
+/// What a trace probes with. Answers of the hops are ICMP errors whatever
+/// the protocol, the destination answers in kind.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TraceProtocol {
+    /// Echo requests.
+    #[default]
+    Icmp,
+    /// Datagrams to a port nobody listens on, answered by port unreachable.
+    Udp,
+    /// SYNs to a port, answered by SYN-ACK or RST. Every probe has a source
+    /// port of its own, so it is never a Paris trace.
+    Tcp,
+}
+
+impl TraceProtocol {
+    pub fn default_port(self) -> u16 {
+        match self {
+            Self::Icmp => 0,
+            Self::Udp => 33434,
+            Self::Tcp => 80,
+        }
+    }
+}
+
+impl From<controller_grpc::TraceProtocol> for TraceProtocol {
+    fn from(v: controller_grpc::TraceProtocol) -> Self {
+        match v {
+            controller_grpc::TraceProtocol::Icmp => Self::Icmp,
+            controller_grpc::TraceProtocol::Udp => Self::Udp,
+            controller_grpc::TraceProtocol::Tcp => Self::Tcp,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct MtrCommand {
     pub version: String,
@@ -160,6 +643,14 @@ pub struct MtrCommand {
     pub times: u32,
     pub hop_limit: u32,
     pub timeout: Duration,
+    pub mode: TraceMode,
+    /// Flows traced in Paris mode, every one runs `times` rounds.
+    pub flows: u32,
+    pub protocol: TraceProtocol,
+    /// Destination port of UDP and TCP probes.
+    pub port: u16,
+    /// Keep the UDP port of classic traces instead of incrementing it.
+    pub fixed_port: bool,
 }
 
 impl TryFrom<MtrCommandResp> for MtrCommand {
@@ -167,22 +658,70 @@ impl TryFrom<MtrCommandResp> for MtrCommand {
 
     fn try_from(value: MtrCommandResp) -> Result<Self, Self::Error> {
         let ip = value.ip.parse::<IpAddr>()?;
+        let protocol = TraceProtocol::from(value.protocol());
+        // Zero and ports out of range fall back to the protocol default.
+        let port = match u16::try_from(value.port) {
+            Ok(0) | Err(_) => protocol.default_port(),
+            Ok(port) => port,
+        };
         Ok(Self {
+            mode: value.mode().into(),
+            protocol,
+            port,
+            fixed_port: value.fixed_port,
             version: value.version,
             ip,
             times: value.times,
             hop_limit: value.hop_limit,
             timeout: Duration::from_millis(u64::from(value.timeout_ms)),
+            flows: value.flows.max(1),
         })
     }
 }
 
+/// One entry of the MPLS label stack a hop reported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MplsLabel {
+    pub label: u32,
+    pub tc: u8,
+    /// Bottom of the stack.
+    pub s: bool,
+    pub ttl: u8,
+}
+
+impl MplsLabel {
+    /// Decode a label stack entry, RFC 3032.
+    pub fn from_entry(entry: u32) -> Self {
+        Self {
+            label: entry >> 12,
+            tc: ((entry >> 9) & 0x7) as u8,
+            s: (entry >> 8) & 0x1 == 1,
+            ttl: entry as u8,
+        }
+    }
+}
+
+impl From<MplsLabel> for GrpcMplsLabel {
+    fn from(v: MplsLabel) -> Self {
+        GrpcMplsLabel {
+            label: v.label,
+            tc: u32::from(v.tc),
+            s: v.s,
+            ttl: u32::from(v.ttl),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct MtrResult {
     pub hop: u32,
     pub ip: String,
     pub is_timeout: bool,
     pub rtt: Option<Duration>,
+    pub flow: u32,
+    pub mpls: Vec<MplsLabel>,
+    pub origin: Option<Origin>,
+    pub hostname: Option<String>,
 }
 
 impl From<MtrResult> for GrpcMtrResult {
@@ -197,6 +736,445 @@ impl From<MtrResult> for GrpcMtrResult {
             ip: v.ip,
             is_timeout: v.is_timeout,
             rtt_micros,
+            flow: v.flow,
+            mpls: v.mpls.into_iter().map(|x| x.into()).collect(),
+            asn: v.origin.map(|o| o.asn).unwrap_or_default(),
+            prefix: v.origin.map(|o| o.to_string()).unwrap_or_default(),
+            hostname: v.hostname.unwrap_or_default(),
+        }
+    }
+}
+
+/// Hops indexed by TTL - 1 as reported, unanswered ones are empty.
+fn hop_ips(hops: Vec<Option<IpAddr>>) -> Vec<String> {
+    hops.into_iter()
+        .map(|ip| ip.map(|ip| ip.to_string()).unwrap_or_default())
+        .collect()
+}
+
+/// One distinct path of a Paris trace and the flows which took it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FlowPath {
+    pub flows: Vec<u32>,
+    pub hops: Vec<Option<IpAddr>>,
+}
+
+impl From<FlowPath> for GrpcPath {
+    fn from(v: FlowPath) -> Self {
+        GrpcPath {
+            flows: v.flows,
+            hops: hop_ips(v.hops),
+        }
+    }
+}
+
+/// Every probe of one trace.
+#[derive(Debug)]
+pub struct Trace {
+    /// The command and its type whose degradation triggered the trace, `None`
+    /// when the controller commanded it.
+    pub trigger: Option<(ProbeType, u64)>,
+    pub version: String,
+    pub ip: IpAddr,
+    pub start_at: SystemTime,
+    pub results: Vec<MtrResult>,
+    /// Equal-cost paths found by a Paris trace, empty for a classic one.
+    pub paths: Vec<FlowPath>,
+    /// Of the destination, filled in by the reporter like those of the hops.
+    pub origin: Option<Origin>,
+}
+
+impl From<Trace> for GrpcTrace {
+    fn from(v: Trace) -> Self {
+        let (probe_type, command_id) = v.trigger.unwrap_or((ProbeType::Ping, 0));
+        GrpcTrace {
+            command_id,
+            r#type: probe_type.into(),
+            version: v.version,
+            ip: v.ip.to_string(),
+            start_at: v.start_at.duration_since(UNIX_EPOCH).unwrap().as_secs() as i64,
+            results: v.results.into_iter().map(|x| x.into()).collect(),
+            paths: v.paths.into_iter().map(|x| x.into()).collect(),
+            asn: v.origin.map(|o| o.asn).unwrap_or_default(),
+            prefix: v.origin.map(|o| o.to_string()).unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HopChange {
+    Added,
+    Removed,
+    Changed,
+}
+
+impl From<HopChange> for collector_grpc::HopChange {
+    fn from(v: HopChange) -> Self {
+        match v {
+            HopChange::Added => Self::Added,
+            HopChange::Removed => Self::Removed,
+            HopChange::Changed => Self::Changed,
+        }
+    }
+}
+
+/// One hop that differs between two paths, `hop` is the TTL.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HopDiff {
+    pub hop: u32,
+    pub change: HopChange,
+    pub from: Option<IpAddr>,
+    pub to: Option<IpAddr>,
+}
+
+impl From<HopDiff> for GrpcHopDiff {
+    fn from(v: HopDiff) -> Self {
+        let ip = |ip: Option<IpAddr>| ip.map(|ip| ip.to_string()).unwrap_or_default();
+        GrpcHopDiff {
+            hop: v.hop,
+            change: collector_grpc::HopChange::from(v.change).into(),
+            from: ip(v.from),
+            to: ip(v.to),
+        }
+    }
+}
+
+/// Probe packets a protocol budget handed out over the last `interval`, and
+/// how many of them had to wait for tokens.
+#[derive(Debug, Clone)]
+pub struct BudgetStats {
+    pub protocol: &'static str,
+    pub at: SystemTime,
+    pub interval: Duration,
+    pub acquired: u64,
+    pub delayed: u64,
+    pub delayed_total: Duration,
+}
+
+impl From<BudgetStats> for GrpcBudgetStats {
+    fn from(v: BudgetStats) -> Self {
+        GrpcBudgetStats {
+            protocol: v.protocol.to_string(),
+            at_millis: v.at.duration_since(UNIX_EPOCH).unwrap().as_millis() as i64,
+            interval_secs: v.interval.as_secs() as u32,
+            acquired: v.acquired,
+            delayed: v.delayed,
+            delayed_micros: v.delayed_total.as_micros() as u64,
+        }
+    }
+}
+
+/// The path toward a watched destination changed. Hops are indexed by
+/// TTL - 1, `None` did not answer.
+#[derive(Debug, Clone)]
+pub struct PathChange {
+    pub target: String,
+    pub ip: IpAddr,
+    pub at: SystemTime,
+    pub old: Vec<Option<IpAddr>>,
+    pub new: Vec<Option<IpAddr>>,
+    pub diff: Vec<HopDiff>,
+}
+
+impl From<PathChange> for GrpcPathChange {
+    fn from(v: PathChange) -> Self {
+        GrpcPathChange {
+            target: v.target,
+            ip: v.ip.to_string(),
+            at_millis: v.at.duration_since(UNIX_EPOCH).unwrap().as_millis() as i64,
+            old_hops: hop_ips(v.old),
+            new_hops: hop_ips(v.new),
+            diff: v.diff.into_iter().map(|x| x.into()).collect(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct PmtuCommand {
+    pub id: u64,
+    pub ip: IpAddr,
+    /// Bounds of the search, in bytes of IP packet.
+    pub min_size: u16,
+    pub max_size: u16,
+    pub timeout: Duration,
+}
+
+impl TryFrom<GrpcPmtuCommand> for PmtuCommand {
+    type Error = AddrParseError;
+
+    fn try_from(value: GrpcPmtuCommand) -> Result<Self, Self::Error> {
+        let ip = value.ip.parse::<IpAddr>()?;
+        let (header, default_min) = match ip {
+            IpAddr::V4(_) => (IPV4_HEADER_LEN, DEFAULT_PMTU_MIN_V4),
+            IpAddr::V6(_) => (IPV6_HEADER_LEN, DEFAULT_PMTU_MIN_V6),
+        };
+        let size = |size: u32, default: u16| match size {
+            0 => default,
+            size => size.clamp(
+                u32::from(header) + ICMP_HEADER_LEN as u32,
+                u32::from(u16::MAX),
+            ) as u16,
+        };
+        let max_size = size(value.max_size, DEFAULT_PMTU_MAX);
+
+        Ok(Self {
+            id: value.id,
+            ip,
+            min_size: size(value.min_size, default_min).min(max_size),
+            max_size,
+            timeout: Duration::from_millis(u64::from(value.timeout_ms)),
+        })
+    }
+}
+
+/// Why the smallest size which did not get through failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PmtuDrop {
+    /// A router refused it and reported the MTU of its next hop.
+    TooBig { from: IpAddr, mtu: u32 },
+    /// Nothing answered.
+    Blackhole,
+    /// The interface toward the target has a smaller MTU.
+    Local,
+}
+
+#[derive(Debug)]
+pub struct PmtuResult {
+    pub id: u64,
+    pub ip: IpAddr,
+    pub start_at: SystemTime,
+    /// Largest size which got through, 0 when not even the minimum did.
+    pub mtu: u16,
+    /// Smallest size which did not, `None` when the maximum got through.
+    pub failed: Option<(u16, PmtuDrop)>,
+    pub probes: u32,
+}
+
+impl From<PmtuResult> for GrpcPmtuResult {
+    fn from(v: PmtuResult) -> Self {
+        let (failed_size, drop) = v.failed.unwrap_or((0, PmtuDrop::Blackhole));
+        let (kind, dropped_at, reported_mtu) = match drop {
+            PmtuDrop::TooBig { from, mtu } => {
+                (collector_grpc::PmtuDrop::TooBig, from.to_string(), mtu)
+            }
+            PmtuDrop::Blackhole => (collector_grpc::PmtuDrop::Blackhole, String::new(), 0),
+            PmtuDrop::Local => (collector_grpc::PmtuDrop::Local, String::new(), 0),
+        };
+
+        GrpcPmtuResult {
+            id: v.id,
+            ip: v.ip.to_string(),
+            start_at: v.start_at.duration_since(UNIX_EPOCH).unwrap().as_secs() as i64,
+            mtu: u32::from(v.mtu),
+            failed_size: u32::from(failed_size),
+            drop: kind.into(),
+            dropped_at,
+            reported_mtu,
+            probes: v.probes,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct TimestampCommand {
+    pub id: u64,
+    pub ip: Ipv4Addr,
+    pub timeout: Duration,
+    /// Requests sent one after another.
+    pub count: u32,
+}
+
+impl TryFrom<GrpcTimestampCommand> for TimestampCommand {
+    type Error = AddrParseError;
+
+    fn try_from(value: GrpcTimestampCommand) -> Result<Self, Self::Error> {
+        let ip = value.ip.parse::<Ipv4Addr>()?;
+
+        Ok(Self {
+            id: value.id,
+            ip,
+            timeout: Duration::from_millis(u64::from(value.timeout_ms)),
+            count: value.count.max(1),
+        })
+    }
+}
+
+/// How a target wrote its receive and transmit timestamps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimestampFormat {
+    /// Milliseconds since midnight UT, RFC 792.
+    Standard,
+    /// The same in little-endian byte order.
+    Swapped,
+    /// Only meaningful to the target.
+    NonStandard,
+}
+
+impl From<TimestampFormat> for collector_grpc::TimestampFormat {
+    fn from(v: TimestampFormat) -> Self {
+        match v {
+            TimestampFormat::Standard => collector_grpc::TimestampFormat::Standard,
+            TimestampFormat::Swapped => collector_grpc::TimestampFormat::Swapped,
+            TimestampFormat::NonStandard => collector_grpc::TimestampFormat::NonStandard,
+        }
+    }
+}
+
+/// One timestamp request, clocks are in milliseconds since midnight UT.
+#[derive(Debug)]
+pub struct TimestampResult {
+    pub id: u64,
+    pub ip: Ipv4Addr,
+    pub send_at: SystemTime,
+    /// `None` when the request timed out, the timestamps are zero then.
+    pub rtt: Option<Duration>,
+    pub originate: u32,
+    /// Receive and transmit are read in `format`.
+    pub receive: u32,
+    pub transmit: u32,
+    /// The agent clock when the reply arrived.
+    pub returned: u32,
+    pub format: TimestampFormat,
+    /// Receive - originate and returned - transmit, `None` for non-standard
+    /// targets.
+    pub forward: Option<i32>,
+    pub backward: Option<i32>,
+}
+
+impl From<TimestampResult> for GrpcTimestampResult {
+    fn from(v: TimestampResult) -> Self {
+        let mut rtt_micros = 0;
+        if let Some(rtt) = v.rtt {
+            rtt_micros = rtt.as_micros() as u32;
+        }
+        let format: collector_grpc::TimestampFormat = v.format.into();
+
+        GrpcTimestampResult {
+            id: v.id,
+            ip: v.ip.to_string(),
+            is_timeout: v.rtt.is_none(),
+            rtt_micros,
+            send_at: v.send_at.duration_since(UNIX_EPOCH).unwrap().as_secs() as i64,
+            originate: v.originate,
+            receive: v.receive,
+            transmit: v.transmit,
+            returned: v.returned,
+            format: format.into(),
+            forward_millis: v.forward.unwrap_or_default(),
+            backward_millis: v.backward.unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct TwampCommand {
+    pub id: u64,
+    pub ip: IpAddr,
+    pub port: u16,
+    pub count: u32,
+    pub interval: Duration,
+    pub timeout: Duration,
+    /// UDP payload of test packets.
+    pub size: usize,
+}
+
+impl TryFrom<GrpcTwampCommand> for TwampCommand {
+    type Error = AddrParseError;
+
+    fn try_from(value: GrpcTwampCommand) -> Result<Self, Self::Error> {
+        let ip = value.ip.parse::<IpAddr>()?;
+
+        Ok(Self {
+            id: value.id,
+            ip,
+            // Zero and ports out of range fall back to the well-known one.
+            port: match u16::try_from(value.port) {
+                Ok(0) | Err(_) => twamp::DEFAULT_PORT,
+                Ok(port) => port,
+            },
+            count: value.count.max(1),
+            interval: Duration::from_millis(u64::from(value.interval_ms)),
+            timeout: Duration::from_millis(u64::from(value.timeout_ms)),
+            size: (value.size as usize).clamp(twamp::REFLECTED_LEN, MAX_PING_SIZE),
+        })
+    }
+}
+
+/// Min, mean and max of one delay over a session, in micros.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Delay {
+    pub min: i64,
+    pub mean: i64,
+    pub max: i64,
+}
+
+impl Delay {
+    /// `None` without samples.
+    pub fn from_micros(samples: &[i64]) -> Option<Self> {
+        Some(Self {
+            min: *samples.iter().min()?,
+            mean: samples.iter().sum::<i64>() / samples.len() as i64,
+            max: *samples.iter().max()?,
+        })
+    }
+}
+
+impl From<Delay> for GrpcDelay {
+    fn from(v: Delay) -> Self {
+        let micros = |m: i64| m.clamp(i64::from(i32::MIN), i64::from(i32::MAX)) as i32;
+        GrpcDelay {
+            min_micros: micros(v.min),
+            mean_micros: micros(v.mean),
+            max_micros: micros(v.max),
+        }
+    }
+}
+
+/// One TWAMP-Light session.
+#[derive(Debug)]
+pub struct TwampResult {
+    pub id: u64,
+    pub ip: IpAddr,
+    pub port: u16,
+    pub start_at: SystemTime,
+    pub sent: u32,
+    pub received: u32,
+    pub forward_lost: u32,
+    pub backward_lost: u32,
+    /// One-way delays, skewed by the clock offset of the reflector unless
+    /// `synchronized`.
+    pub forward: Option<Delay>,
+    pub backward: Option<Delay>,
+    /// Round trip without the time spent in the reflector.
+    pub rtt: Option<Delay>,
+    pub forward_reordered: u32,
+    pub backward_reordered: u32,
+    pub duplicates: u32,
+    pub synchronized: bool,
+    /// The reflector copied the sender sequence numbers, loss could not be
+    /// split by direction.
+    pub stateless: bool,
+}
+
+impl From<TwampResult> for GrpcTwampResult {
+    fn from(v: TwampResult) -> Self {
+        GrpcTwampResult {
+            id: v.id,
+            ip: v.ip.to_string(),
+            port: u32::from(v.port),
+            start_at: v.start_at.duration_since(UNIX_EPOCH).unwrap().as_secs() as i64,
+            sent: v.sent,
+            received: v.received,
+            forward_lost: v.forward_lost,
+            backward_lost: v.backward_lost,
+            forward: v.forward.map(|x| x.into()),
+            backward: v.backward.map(|x| x.into()),
+            rtt: v.rtt.map(|x| x.into()),
+            forward_reordered: v.forward_reordered,
+            backward_reordered: v.backward_reordered,
+            duplicates: v.duplicates,
+            synchronized: v.synchronized,
+            stateless: v.stateless,
         }
     }
 }
@@ -0,0 +1,403 @@
+use crate::conf;
+use anyhow::{Context, Result};
+use bytes::{BufMut, Bytes, BytesMut};
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use std::collections::HashMap;
+use std::io::{self, ErrorKind};
+use std::mem;
+use std::net::SocketAddr;
+use std::os::fd::AsRawFd;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::Interest;
+use tokio::net::UdpSocket;
+use tokio::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// Well-known TWAMP port, also used by most TWAMP-Light reflectors.
+pub const DEFAULT_PORT: u16 = 862;
+/// Sender packets must be TTL 255, RFC 5357.
+pub const SENDER_TTL: u32 = 255;
+/// Unauthenticated sender packet without padding.
+pub const SENDER_LEN: usize = 14;
+/// Unauthenticated reflector packet without padding. Senders pad up to it so
+/// both directions carry the same size, RFC 6038.
+pub const REFLECTED_LEN: usize = 41;
+const MAX_PACKET: usize = 65_507;
+/// Seconds from 1900, the NTP epoch, to 1970.
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+/// S bit of the error estimate, the clock is synchronized to UTC.
+const SYNCHRONIZED: u16 = 0x8000;
+/// Error estimate of about a millisecond in NTP format, scale 22 and
+/// multiplier 1, the S bit is added when the kernel clock is synchronized.
+const ERROR_ESTIMATE: u16 = 0x1601;
+/// A sender quiet for this long starts a new session with sequence zero.
+const SESSION_IDLE: Duration = Duration::from_secs(60);
+/// Senders tracked at once, the one quiet the longest makes room for a new
+/// one, so spoofed sources cannot grow the table without bound.
+const MAX_SESSIONS: usize = 4096;
+
+/// 64 bit NTP timestamp of `at`, seconds since 1900 and their fraction.
+pub fn ntp_timestamp(at: SystemTime) -> u64 {
+    let since = at.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since.as_secs() + NTP_UNIX_OFFSET;
+    let frac = (u64::from(since.subsec_nanos()) << 32) / 1_000_000_000;
+    (secs << 32) | frac
+}
+
+pub fn from_ntp(timestamp: u64) -> SystemTime {
+    let secs = (timestamp >> 32).saturating_sub(NTP_UNIX_OFFSET);
+    // Rounded, so a time survives the round trip through NTP format.
+    let nanos = ((timestamp & 0xffff_ffff) * 1_000_000_000 + (1 << 31)) >> 32;
+    UNIX_EPOCH + Duration::new(secs, nanos as u32)
+}
+
+/// Whether the kernel clock is kept synchronized, by NTP or PTP.
+pub fn clock_synchronized() -> bool {
+    // SAFETY: a zeroed timex with no modes only reads the clock state.
+    let state = unsafe {
+        let mut timex: libc::timex = mem::zeroed();
+        libc::adjtimex(&mut timex)
+    };
+    state >= 0 && state != libc::TIME_ERROR
+}
+
+fn error_estimate() -> u16 {
+    if clock_synchronized() {
+        ERROR_ESTIMATE | SYNCHRONIZED
+    } else {
+        ERROR_ESTIMATE
+    }
+}
+
+fn u16_at(buf: &[u8], at: usize) -> u16 {
+    u16::from_be_bytes(buf[at..at + 2].try_into().unwrap())
+}
+
+fn u32_at(buf: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes(buf[at..at + 4].try_into().unwrap())
+}
+
+fn u64_at(buf: &[u8], at: usize) -> u64 {
+    u64::from_be_bytes(buf[at..at + 8].try_into().unwrap())
+}
+
+/// Unauthenticated test packet of `len` bytes, RFC 5357 4.1.2.
+pub fn test_packet(seq: u32, at: SystemTime, len: usize) -> Bytes {
+    let mut buf = BytesMut::with_capacity(len);
+    buf.put_u32(seq);
+    buf.put_u64(ntp_timestamp(at));
+    buf.put_u16(error_estimate());
+    buf.resize(len.max(SENDER_LEN), 0);
+    buf.freeze()
+}
+
+/// An unauthenticated reflector packet, RFC 5357 4.2.1.
+#[derive(Debug)]
+pub struct Reflected {
+    /// Counts the packets the reflector sent in the session from zero.
+    pub seq: u32,
+    pub receive_at: SystemTime,
+    pub transmit_at: SystemTime,
+    pub synchronized: bool,
+    pub sender_seq: u32,
+    pub sender_at: SystemTime,
+    /// TTL the test packet arrived at the reflector with.
+    pub sender_ttl: u8,
+}
+
+pub fn parse_reflected(buf: &[u8]) -> Option<Reflected> {
+    if buf.len() < REFLECTED_LEN {
+        return None;
+    }
+
+    Some(Reflected {
+        seq: u32_at(buf, 0),
+        transmit_at: from_ntp(u64_at(buf, 4)),
+        synchronized: u16_at(buf, 12) & SYNCHRONIZED != 0,
+        receive_at: from_ntp(u64_at(buf, 16)),
+        sender_seq: u32_at(buf, 24),
+        sender_at: from_ntp(u64_at(buf, 28)),
+        sender_ttl: buf[40],
+    })
+}
+
+/// Answer to test packet `packet`, which is at least a reflector packet long,
+/// as long as it.
+fn reflect(packet: &[u8], seq: u32, receive_at: SystemTime, ttl: u8) -> Bytes {
+    let mut buf = BytesMut::with_capacity(packet.len());
+    buf.put_u32(seq);
+    buf.put_u64(ntp_timestamp(SystemTime::now()));
+    buf.put_u16(error_estimate());
+    buf.put_u16(0);
+    buf.put_u64(ntp_timestamp(receive_at));
+    buf.put_slice(&packet[..SENDER_LEN]);
+    buf.put_u16(0);
+    buf.put_u8(ttl);
+    buf.resize(packet.len(), 0);
+    buf.freeze()
+}
+
+fn set_option(sock: &Socket, level: libc::c_int, name: libc::c_int) -> io::Result<()> {
+    let value: libc::c_int = 1;
+    // SAFETY: the option value is a c_int living across the call.
+    let ret = unsafe {
+        libc::setsockopt(
+            sock.as_raw_fd(),
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Receive a datagram along with the TTL or hop limit it arrived with, 0 when
+/// the kernel did not pass it.
+fn recv_with_ttl(sock: &UdpSocket, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, u8)> {
+    // SAFETY: all zeroes is a valid sockaddr_storage and msghdr.
+    let mut addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    let mut control = [0u64; 8];
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr().cast(),
+        iov_len: buf.len(),
+    };
+    msg.msg_name = (&mut addr as *mut libc::sockaddr_storage).cast();
+    msg.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr().cast();
+    msg.msg_controllen = mem::size_of_val(&control) as _;
+
+    // SAFETY: every pointer of msg points at a live buffer of the given length.
+    let len = unsafe { libc::recvmsg(sock.as_raw_fd(), &mut msg, 0) };
+    if len < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut ttl = 0;
+    // SAFETY: the kernel filled msg_controllen bytes of control messages.
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            let (level, kind) = ((*cmsg).cmsg_level, (*cmsg).cmsg_type);
+            if (level == libc::IPPROTO_IP && kind == libc::IP_TTL)
+                || (level == libc::IPPROTO_IPV6 && kind == libc::IPV6_HOPLIMIT)
+            {
+                ttl = libc::CMSG_DATA(cmsg).cast::<libc::c_int>().read_unaligned() as u8;
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+
+    // SAFETY: the kernel wrote an address of msg_namelen bytes.
+    let from = unsafe { SockAddr::new(addr, msg.msg_namelen) };
+    let from = from
+        .as_socket()
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "not an ip address"))?;
+    Ok((len as usize, from, ttl))
+}
+
+/// TWAMP-Light session reflector, RFC 5357 Appendix I. It answers every
+/// unauthenticated test packet it gets, other agents and routers send them
+/// without a control session. A session is a sender address, the reflector
+/// numbers its answers from zero so senders tell forward from backward loss.
+/// Only test packets padded to the answer size are answered, RFC 6038, so the
+/// reflector never sends more than it got.
+pub struct Reflector {
+    sock: UdpSocket,
+    sessions: HashMap<SocketAddr, (u32, Instant)>,
+}
+
+impl Reflector {
+    /// `None` when reflecting is disabled.
+    pub fn new(conf: &conf::Twamp) -> Result<Option<Self>> {
+        if !conf.reflector {
+            return Ok(None);
+        }
+
+        let addr: SocketAddr = conf
+            .listen
+            .parse()
+            .with_context(|| format!("parse twamp listen addr {}", conf.listen))?;
+        let sock = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+        // IPv4 senders reach a dual-stack socket as mapped addresses, whose
+        // TTL comes from the IPv4 option.
+        if addr.is_ipv6() {
+            sock.set_only_v6(false)?;
+            set_option(&sock, libc::IPPROTO_IPV6, libc::IPV6_RECVHOPLIMIT)?;
+        }
+        set_option(&sock, libc::IPPROTO_IP, libc::IP_RECVTTL)?;
+        sock.set_nonblocking(true)?;
+        sock.bind(&addr.into())
+            .with_context(|| format!("bind twamp reflector {}", addr))?;
+        info!("Reflect twamp light test packets on {}", addr);
+
+        Ok(Some(Self {
+            sock: UdpSocket::from_std(sock.into())?,
+            sessions: HashMap::new(),
+        }))
+    }
+
+    /// Sequence number of the next answer to `sender`.
+    fn next_seq(&mut self, sender: SocketAddr) -> u32 {
+        let now = Instant::now();
+        if !self.sessions.contains_key(&sender) {
+            self.sessions
+                .retain(|_, (_, last)| now.duration_since(*last) < SESSION_IDLE);
+            if self.sessions.len() >= MAX_SESSIONS {
+                let oldest = self
+                    .sessions
+                    .iter()
+                    .min_by_key(|(_, (_, last))| *last)
+                    .map(|(addr, _)| *addr);
+                if let Some(oldest) = oldest {
+                    self.sessions.remove(&oldest);
+                }
+            }
+        }
+        let (next, last) = self.sessions.entry(sender).or_insert((0, now));
+        if now.duration_since(*last) >= SESSION_IDLE {
+            *next = 0;
+        }
+        *last = now;
+        let seq = *next;
+        *next = next.wrapping_add(1);
+        seq
+    }
+
+    pub async fn run(mut self) {
+        let mut buf = vec![0; MAX_PACKET];
+        loop {
+            let recv = self
+                .sock
+                .async_io(Interest::READABLE, || recv_with_ttl(&self.sock, &mut buf))
+                .await;
+            let receive_at = SystemTime::now();
+            let (len, from, ttl) = match recv {
+                Ok(recv) => recv,
+                Err(e) => {
+                    warn!("Recv twamp test packet fail, err:{}", e);
+                    continue;
+                }
+            };
+            if len < REFLECTED_LEN {
+                continue;
+            }
+
+            let seq = self.next_seq(from);
+            let reply = reflect(&buf[..len], seq, receive_at, ttl);
+            if let Err(e) = self.sock.send_to(&reply, from).await {
+                warn!("Reflect twamp test packet to {} fail, err:{}", from, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ntp_round_trip() {
+        let at = UNIX_EPOCH + Duration::new(1_700_000_000, 250_000_000);
+        let ntp = ntp_timestamp(at);
+        assert_eq!(ntp >> 32, 1_700_000_000 + NTP_UNIX_OFFSET);
+        assert_eq!(ntp & 0xffff_ffff, 1 << 30);
+        assert_eq!(from_ntp(ntp), at);
+    }
+
+    #[test]
+    fn sender_packet_layout() {
+        let at = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let packet = test_packet(0x0102_0304, at, REFLECTED_LEN);
+        assert_eq!(packet.len(), REFLECTED_LEN);
+        assert_eq!(u32_at(&packet, 0), 0x0102_0304);
+        assert_eq!(u64_at(&packet, 4), ntp_timestamp(at));
+        assert_eq!(u16_at(&packet, 12) & !SYNCHRONIZED, ERROR_ESTIMATE);
+        assert!(packet[SENDER_LEN..].iter().all(|&b| b == 0));
+        assert_eq!(test_packet(0, at, 0).len(), SENDER_LEN);
+    }
+
+    #[test]
+    fn reflected_packet_layout() {
+        let sender_at = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let receive_at = sender_at + Duration::from_millis(3);
+        let packet = test_packet(9, sender_at, REFLECTED_LEN);
+        let reply = reflect(&packet, 4, receive_at, 62);
+
+        assert_eq!(reply.len(), REFLECTED_LEN);
+        assert_eq!(u32_at(&reply, 0), 4);
+        assert!(from_ntp(u64_at(&reply, 4)) >= receive_at);
+        assert_eq!(u16_at(&reply, 12) & !SYNCHRONIZED, ERROR_ESTIMATE);
+        assert_eq!(u16_at(&reply, 14), 0);
+        assert_eq!(u64_at(&reply, 16), ntp_timestamp(receive_at));
+        assert_eq!(&reply[24..38], &packet[..SENDER_LEN]);
+        assert_eq!(u16_at(&reply, 38), 0);
+        assert_eq!(reply[40], 62);
+
+        let reflected = parse_reflected(&reply).unwrap();
+        assert_eq!(reflected.seq, 4);
+        assert_eq!(reflected.sender_seq, 9);
+        assert_eq!(reflected.sender_at, sender_at);
+        assert_eq!(reflected.receive_at, receive_at);
+        assert_eq!(reflected.sender_ttl, 62);
+        assert!(parse_reflected(&reply[..REFLECTED_LEN - 1]).is_none());
+    }
+
+    async fn reflector() -> Reflector {
+        let conf = conf::Twamp {
+            reflector: true,
+            listen: "127.0.0.1:0".to_string(),
+        };
+        Reflector::new(&conf).unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn evict_the_quietest_session_when_full() {
+        let mut reflector = reflector().await;
+        let now = Instant::now();
+        for port in 0..MAX_SESSIONS as u16 {
+            let sender = SocketAddr::from(([192, 0, 2, 1], port));
+            let last = now - Duration::from_millis(u64::from(port));
+            reflector.sessions.insert(sender, (5, last));
+        }
+        let oldest = SocketAddr::from(([192, 0, 2, 1], MAX_SESSIONS as u16 - 1));
+        let newest = SocketAddr::from(([192, 0, 2, 1], 0));
+
+        let sender = SocketAddr::from(([198, 51, 100, 1], 862));
+        assert_eq!(reflector.next_seq(sender), 0);
+        assert_eq!(reflector.next_seq(sender), 1);
+        assert_eq!(reflector.sessions.len(), MAX_SESSIONS);
+        assert!(!reflector.sessions.contains_key(&oldest));
+        assert_eq!(reflector.next_seq(newest), 5);
+    }
+
+    #[tokio::test]
+    async fn answer_only_padded_packets() {
+        let reflector = reflector().await;
+        let addr = reflector.sock.local_addr().unwrap();
+        tokio::spawn(reflector.run());
+        let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        sock.connect(addr).await.unwrap();
+        let mut buf = [0; 128];
+
+        let short = test_packet(0, SystemTime::now(), SENDER_LEN);
+        sock.send(&short).await.unwrap();
+        let padded = test_packet(1, SystemTime::now(), 64);
+        sock.send(&padded).await.unwrap();
+
+        // Only the padded packet comes back, as long as it was sent.
+        let len = sock.recv(&mut buf).await.unwrap();
+        assert_eq!(len, 64);
+        let reflected = parse_reflected(&buf[..len]).unwrap();
+        assert_eq!(reflected.sender_seq, 1);
+        assert_eq!(reflected.seq, 0);
+        let more = tokio::time::timeout(Duration::from_millis(200), sock.recv(&mut buf)).await;
+        assert!(more.is_err());
+    }
+}